@@ -0,0 +1,12 @@
+CREATE TABLE exchange_rate_overrides (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  date INTEGER NOT NULL,
+  from_currency_code TEXT NOT NULL,
+  to_currency_code TEXT NOT NULL,
+  exchange_rate BIGINT NOT NULL,
+  UNIQUE(date, from_currency_code, to_currency_code)
+);
+
+ALTER TABLE difference_transactions ADD COLUMN exchange_rate_date INTEGER;
+
+ALTER TABLE difference_transactions ADD COLUMN needs_recompute INTEGER NOT NULL DEFAULT 0;
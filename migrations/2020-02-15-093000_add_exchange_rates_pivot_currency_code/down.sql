@@ -0,0 +1,21 @@
+ALTER TABLE exchange_rates RENAME TO new_exchange_rates_20200215;
+
+CREATE TABLE exchange_rates (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  date INTEGER NOT NULL,
+  from_currency_code TEXT NOT NULL,
+  to_currency_code TEXT NOT NULL,
+  exchange_rate BIGINT NOT NULL,
+  UNIQUE(date, from_currency_code, to_currency_code)
+);
+
+INSERT INTO exchange_rates
+SELECT
+  id,
+  date,
+  from_currency_code,
+  to_currency_code,
+  exchange_rate
+FROM new_exchange_rates_20200215;
+
+DROP TABLE new_exchange_rates_20200215;
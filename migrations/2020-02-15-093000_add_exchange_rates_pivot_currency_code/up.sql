@@ -0,0 +1 @@
+ALTER TABLE exchange_rates ADD COLUMN pivot_currency_code TEXT;
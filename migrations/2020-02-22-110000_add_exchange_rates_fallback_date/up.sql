@@ -0,0 +1 @@
+ALTER TABLE exchange_rates ADD COLUMN fallback_date INTEGER;
@@ -0,0 +1,47 @@
+ALTER TABLE exchange_rates RENAME TO old_exchange_rates_20200301;
+
+CREATE TABLE exchange_rates (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  date INTEGER NOT NULL,
+  from_currency_code TEXT NOT NULL,
+  to_currency_code TEXT NOT NULL,
+  exchange_rate TEXT NOT NULL,
+  pivot_currency_code TEXT,
+  fallback_date INTEGER,
+  UNIQUE(date, from_currency_code, to_currency_code)
+);
+
+INSERT INTO exchange_rates
+SELECT
+  id,
+  date,
+  from_currency_code,
+  to_currency_code,
+  printf('%d.%06d', exchange_rate / 1000000, exchange_rate % 1000000),
+  pivot_currency_code,
+  fallback_date
+FROM old_exchange_rates_20200301;
+
+DROP TABLE old_exchange_rates_20200301;
+
+ALTER TABLE exchange_rate_overrides RENAME TO old_exchange_rate_overrides_20200301;
+
+CREATE TABLE exchange_rate_overrides (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  date INTEGER NOT NULL,
+  from_currency_code TEXT NOT NULL,
+  to_currency_code TEXT NOT NULL,
+  exchange_rate TEXT NOT NULL,
+  UNIQUE(date, from_currency_code, to_currency_code)
+);
+
+INSERT INTO exchange_rate_overrides
+SELECT
+  id,
+  date,
+  from_currency_code,
+  to_currency_code,
+  printf('%d.%06d', exchange_rate / 1000000, exchange_rate % 1000000)
+FROM old_exchange_rate_overrides_20200301;
+
+DROP TABLE old_exchange_rate_overrides_20200301;
@@ -0,0 +1,35 @@
+ALTER TABLE difference_transactions RENAME TO new_difference_transactions_20200308;
+
+CREATE TABLE difference_transactions (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  budget_id INT NOT NULL,
+  foreign_ynab_transaction_id TEXT NOT NULL,
+  difference_ynab_transaction_id TEXT NOT NULL,
+  difference_amount_milliunits BIGINT NOT NULL,
+  difference_currency_code TEXT NOT NULL,
+  difference_account_class TEXT NOT NULL,
+  transfer_currency_code TEXT,
+  transfer_account_class TEXT,
+  exchange_rate_date INTEGER,
+  needs_recompute INTEGER NOT NULL DEFAULT 0,
+  UNIQUE(budget_id, foreign_ynab_transaction_id),
+  UNIQUE(budget_id, difference_ynab_transaction_id),
+  FOREIGN KEY(budget_id) REFERENCES budgets(id)
+);
+
+INSERT INTO difference_transactions
+SELECT
+  id,
+  budget_id,
+  foreign_ynab_transaction_id,
+  difference_ynab_transaction_id,
+  difference_amount_milliunits,
+  difference_currency_code,
+  difference_account_class,
+  transfer_currency_code,
+  transfer_account_class,
+  exchange_rate_date,
+  needs_recompute
+FROM new_difference_transactions_20200308;
+
+DROP TABLE new_difference_transactions_20200308;
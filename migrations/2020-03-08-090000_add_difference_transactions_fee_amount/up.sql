@@ -0,0 +1 @@
+ALTER TABLE difference_transactions ADD COLUMN fee_amount_milliunits BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+-- The original case of currency codes is not kept, so they stay uppercase.
@@ -0,0 +1,44 @@
+-- Currency tags were matched case-insensitively and their codes stored as
+-- written.  Codes are now always uppercase, so convert the stored ones,
+-- keeping an existing uppercase row when there is a row for both cases.
+DELETE FROM exchange_rates
+WHERE id NOT IN (
+  SELECT COALESCE(
+    MIN(CASE
+      WHEN from_currency_code = UPPER(from_currency_code)
+        AND to_currency_code = UPPER(to_currency_code)
+      THEN id
+    END),
+    MIN(id))
+  FROM exchange_rates
+  GROUP BY date, UPPER(from_currency_code), UPPER(to_currency_code)
+);
+
+UPDATE exchange_rates
+SET
+  from_currency_code = UPPER(from_currency_code),
+  to_currency_code = UPPER(to_currency_code),
+  pivot_currency_code = UPPER(pivot_currency_code);
+
+DELETE FROM exchange_rate_overrides
+WHERE id NOT IN (
+  SELECT COALESCE(
+    MIN(CASE
+      WHEN from_currency_code = UPPER(from_currency_code)
+        AND to_currency_code = UPPER(to_currency_code)
+      THEN id
+    END),
+    MIN(id))
+  FROM exchange_rate_overrides
+  GROUP BY date, UPPER(from_currency_code), UPPER(to_currency_code)
+);
+
+UPDATE exchange_rate_overrides
+SET
+  from_currency_code = UPPER(from_currency_code),
+  to_currency_code = UPPER(to_currency_code);
+
+UPDATE difference_transactions
+SET
+  difference_currency_code = UPPER(difference_currency_code),
+  transfer_currency_code = UPPER(transfer_currency_code);
@@ -0,0 +1,7 @@
+CREATE TABLE skipped_foreign_transactions (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  budget_id INT NOT NULL,
+  foreign_ynab_transaction_id TEXT NOT NULL,
+  UNIQUE(budget_id, foreign_ynab_transaction_id),
+  FOREIGN KEY(budget_id) REFERENCES budgets(id)
+);
@@ -0,0 +1,21 @@
+CREATE TABLE old_budgets_20200329 (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  ynab_budget_id TEXT NOT NULL,
+  start_date INTEGER NOT NULL,
+  ynab_server_knowledge BIGINT NULL,
+  last_run_date INTEGER NULL,
+  UNIQUE(ynab_budget_id)
+);
+
+INSERT INTO old_budgets_20200329
+SELECT
+  id,
+  ynab_budget_id,
+  start_date,
+  ynab_server_knowledge,
+  last_run_date
+FROM budgets;
+
+DROP TABLE budgets;
+
+ALTER TABLE old_budgets_20200329 RENAME TO budgets;
@@ -0,0 +1,4 @@
+-- The budget's currency, so that a changed exchange rate only flags the
+-- difference transactions of budgets in the rate's to currency.  Set on each
+-- run; NULL until the first run after this migration.
+ALTER TABLE budgets ADD COLUMN currency_code TEXT;
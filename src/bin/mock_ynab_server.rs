@@ -0,0 +1 @@
+error_chain::quick_main!(fca4ynab::run_mock_ynab_server);
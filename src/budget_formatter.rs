@@ -4,18 +4,48 @@ use rust_decimal::{Decimal, RoundingStrategy};
 use std::cell::RefCell;
 use std::str;
 
+use crate::currencies::*;
 use crate::types::*;
 
 #[derive(Debug)]
 pub struct BudgetFormatter<'a> {
     settings: &'a ynab_api::models::BudgetSettings,
+    foreign_amount_style: ForeignAmountStyle,
+    currency_registry: &'a CurrencyRegistry,
     date_format: RefCell<Option<String>>,
 }
 
+/// How to format amounts in foreign currencies.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ForeignAmountStyle {
+    /// The currency code and amount, using the budget's separators and
+    /// decimal digits (e.g. `EUR 1,234.56` in a U.S. Dollar budget).
+    Local,
+    /// The currency's own symbol, separators and decimal digits (e.g.
+    /// `€1.234,56` or `¥1,235`), if the currency has a native format.
+    Native,
+}
+
+struct AmountFormat<'b> {
+    currency_symbol: &'b str,
+    currency_symbol_spacer: &'b str,
+    display_symbol: bool,
+    symbol_first: bool,
+    decimal_digits: u32,
+    decimal_separator: &'b str,
+    group_separator: &'b str,
+}
+
 impl<'a> BudgetFormatter<'a> {
-    pub fn new(settings: &ynab_api::models::BudgetSettings) -> BudgetFormatter {
+    pub fn new(
+        settings: &'a ynab_api::models::BudgetSettings,
+        foreign_amount_style: ForeignAmountStyle,
+        currency_registry: &'a CurrencyRegistry,
+    ) -> BudgetFormatter<'a> {
         BudgetFormatter {
             settings,
+            foreign_amount_style,
+            currency_registry,
             date_format: RefCell::new(None),
         }
     }
@@ -25,24 +55,47 @@ impl<'a> BudgetFormatter<'a> {
         currency: CurrencyCode,
         amount: Milliunits,
     ) -> String {
-        self.format_milliunits_custom(&currency.to_str(), " ", true, false, amount)
+        if self.foreign_amount_style == ForeignAmountStyle::Native {
+            if let (Some(native_format), Some(decimal_digits)) = (
+                self.currency_registry.native_format(currency),
+                self.currency_registry.minor_units(currency),
+            ) {
+                return self.format_currency_custom(
+                    &AmountFormat {
+                        currency_symbol: native_format.symbol,
+                        currency_symbol_spacer: native_format.symbol_spacer,
+                        display_symbol: true,
+                        symbol_first: native_format.symbol_first,
+                        decimal_digits,
+                        decimal_separator: native_format.decimal_separator,
+                        group_separator: native_format.group_separator,
+                    },
+                    true,
+                    false,
+                    amount.to_decimal(),
+                );
+            }
+        }
+        self.format_currency_custom(
+            &self.budget_amount_format(&currency.to_str(), " ", true),
+            false,
+            false,
+            amount.to_decimal(),
+        )
     }
 
     pub fn format_milliunits(&self, amount: Milliunits) -> String {
-        self.format_milliunits_custom(
-            &self.settings.currency_format.currency_symbol,
-            "",
-            false,
+        self.format_currency_custom(
+            &self.budget_amount_format(&self.settings.currency_format.currency_symbol, "", false),
             true,
-            amount,
+            false,
+            amount.to_decimal(),
         )
     }
 
     pub fn format_exchange_rate(&self, exchange_rate: ExchangeRate) -> String {
         self.format_currency_custom(
-            &self.settings.currency_format.currency_symbol,
-            "",
-            false,
+            &self.budget_amount_format(&self.settings.currency_format.currency_symbol, "", false),
             true,
             true,
             exchange_rate.to_decimal(),
@@ -62,77 +115,81 @@ impl<'a> BudgetFormatter<'a> {
         date.format(fmt).to_string()
     }
 
-    fn format_milliunits_custom(
-        &self,
-        currency_symbol: &str,
-        currency_symbol_spacer: &str,
+    fn budget_amount_format<'b>(
+        &'b self,
+        currency_symbol: &'b str,
+        currency_symbol_spacer: &'b str,
         force_display_symbol: bool,
-        minus_before_symbol_first: bool,
-        amount: Milliunits,
-    ) -> String {
-        self.format_currency_custom(
+    ) -> AmountFormat<'b> {
+        let currency_format = &self.settings.currency_format;
+        AmountFormat {
             currency_symbol,
             currency_symbol_spacer,
-            force_display_symbol,
-            minus_before_symbol_first,
-            false,
-            amount.to_decimal(),
-        )
+            display_symbol: currency_format.display_symbol || force_display_symbol,
+            symbol_first: currency_format.symbol_first,
+            decimal_digits: currency_format.decimal_digits as u32,
+            decimal_separator: &currency_format.decimal_separator,
+            group_separator: &currency_format.group_separator,
+        }
     }
 
     fn format_currency_custom(
         &self,
-        currency_symbol: &str,
-        currency_symbol_spacer: &str,
-        force_display_symbol: bool,
+        amount_format: &AmountFormat,
         minus_before_symbol_first: bool,
         all_decimal_digits: bool,
         amount: Decimal,
     ) -> String {
-        let currency_format = &self.settings.currency_format;
         let abs_amount: Decimal = amount.abs();
         let raw_formatted = if all_decimal_digits {
             format!("{}", abs_amount)
         } else {
             format!(
                 "{:.*}",
-                currency_format.decimal_digits as usize,
+                amount_format.decimal_digits as usize,
                 abs_amount.round_dp_with_strategy(
-                    currency_format.decimal_digits as u32,
+                    amount_format.decimal_digits,
                     RoundingStrategy::RoundHalfUp
                 )
             )
         };
         let split_around_decimal: Vec<&str> = raw_formatted.split('.').collect();
-        let group_separated_before_decimal = self.add_group_separators(
+        let group_separated_before_decimal = add_group_separators(
             split_around_decimal
                 .get(0)
-                .expect("split_around_decimal should have two elements"),
+                .expect("split_around_decimal should have at least one element"),
+            amount_format.group_separator,
         );
         let group_separated = format!(
-            "{}{}{}{}",
+            "{}{}{}",
             if !minus_before_symbol_first && amount < Decimal::zero() {
                 "-"
             } else {
                 ""
             },
             group_separated_before_decimal,
-            currency_format.decimal_separator,
-            split_around_decimal
-                .get(1)
-                .expect("split_around_decimal should have two elements")
+            // Exchange rates are formatted with all their decimal digits, and
+            // whole number rates have none.
+            match split_around_decimal.get(1) {
+                Some(after_decimal) =>
+                    format!("{}{}", amount_format.decimal_separator, after_decimal),
+                None => String::new(),
+            }
         );
-        let group_separated_with_symbol = if currency_format.display_symbol || force_display_symbol
-        {
-            if currency_format.symbol_first {
+        let group_separated_with_symbol = if amount_format.display_symbol {
+            if amount_format.symbol_first {
                 format!(
                     "{}{}{}",
-                    currency_symbol, currency_symbol_spacer, group_separated
+                    amount_format.currency_symbol,
+                    amount_format.currency_symbol_spacer,
+                    group_separated
                 )
             } else {
                 format!(
                     "{}{}{}",
-                    group_separated, currency_symbol_spacer, currency_symbol
+                    group_separated,
+                    amount_format.currency_symbol_spacer,
+                    amount_format.currency_symbol
                 )
             }
         } else {
@@ -144,20 +201,20 @@ impl<'a> BudgetFormatter<'a> {
             group_separated_with_symbol
         }
     }
+}
 
-    fn add_group_separators(&self, before_decimal: &str) -> String {
-        before_decimal
-            .chars()
-            .rev()
-            .collect::<Vec<char>>()
-            .chunks(3)
-            .map(|chunk| chunk.iter().collect())
-            .collect::<Vec<String>>()
-            .join(&self.settings.currency_format.group_separator)
-            .chars()
-            .rev()
-            .collect()
-    }
+fn add_group_separators(before_decimal: &str, group_separator: &str) -> String {
+    before_decimal
+        .chars()
+        .rev()
+        .collect::<Vec<char>>()
+        .chunks(3)
+        .map(|chunk| chunk.iter().collect())
+        .collect::<Vec<String>>()
+        .join(group_separator)
+        .chars()
+        .rev()
+        .collect()
 }
 
 #[cfg(test)]
@@ -214,32 +271,36 @@ mod tests {
                     display_symbol: false,
                 }
             };
+        pub static ref CURRENCY_REGISTRY: CurrencyRegistry = CurrencyRegistry::new(&[]);
+    }
+
+    fn local_formatter(settings: &ynab_api::models::BudgetSettings) -> BudgetFormatter {
+        BudgetFormatter::new(settings, ForeignAmountStyle::Local, &CURRENCY_REGISTRY)
     }
 
     #[test]
     fn test_format_milliunits() {
         assert_eq!(
-            BudgetFormatter::new(&US_SETTINGS)
-                .format_milliunits(Milliunits::from_scaled_i64(-12_345)),
+            local_formatter(&US_SETTINGS).format_milliunits(Milliunits::from_scaled_i64(-12_345)),
             "-$12.35"
         );
         assert_eq!(
-            BudgetFormatter::new(&OTHER_SETTINGS)
+            local_formatter(&OTHER_SETTINGS)
                 .format_milliunits(Milliunits::from_scaled_i64(-12_345)),
             "-12,345X"
         );
         assert_eq!(
-            BudgetFormatter::new(&NO_SYMBOL_SETTINGS)
+            local_formatter(&NO_SYMBOL_SETTINGS)
                 .format_milliunits(Milliunits::from_scaled_i64(-123_456)),
             "-123,456"
         );
         assert_eq!(
-            BudgetFormatter::new(&US_SETTINGS)
+            local_formatter(&US_SETTINGS)
                 .format_milliunits(Milliunits::from_scaled_i64(123_456_789_012_345)),
             "$123,456,789,012.35"
         );
         assert_eq!(
-            BudgetFormatter::new(&US_SETTINGS).format_milliunits(Milliunits::from_scaled_i64(123)),
+            local_formatter(&US_SETTINGS).format_milliunits(Milliunits::from_scaled_i64(123)),
             "$0.12"
         );
     }
@@ -247,21 +308,21 @@ mod tests {
     #[test]
     fn test_format_milliunits_with_code() {
         assert_eq!(
-            BudgetFormatter::new(&US_SETTINGS).format_milliunits_with_code(
+            local_formatter(&US_SETTINGS).format_milliunits_with_code(
                 CurrencyCode::from_str("USD").unwrap(),
                 Milliunits::from_scaled_i64(-12_345_678)
             ),
             "USD -12,345.68"
         );
         assert_eq!(
-            BudgetFormatter::new(&OTHER_SETTINGS).format_milliunits_with_code(
+            local_formatter(&OTHER_SETTINGS).format_milliunits_with_code(
                 CurrencyCode::from_str("ABC").unwrap(),
                 Milliunits::from_scaled_i64(-12_345_678)
             ),
             "-12.345,678 ABC"
         );
         assert_eq!(
-            BudgetFormatter::new(&NO_SYMBOL_SETTINGS).format_milliunits_with_code(
+            local_formatter(&NO_SYMBOL_SETTINGS).format_milliunits_with_code(
                 CurrencyCode::from_str("XXY").unwrap(),
                 Milliunits::from_scaled_i64(-123_456_789)
             ),
@@ -269,14 +330,62 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_format_milliunits_with_code_native() {
+        let formatter =
+            BudgetFormatter::new(&US_SETTINGS, ForeignAmountStyle::Native, &CURRENCY_REGISTRY);
+        assert_eq!(
+            formatter.format_milliunits_with_code(
+                CurrencyCode::from_str("EUR").unwrap(),
+                Milliunits::from_scaled_i64(-1_234_560)
+            ),
+            "-€1.234,56"
+        );
+        assert_eq!(
+            formatter.format_milliunits_with_code(
+                CurrencyCode::from_str("JPY").unwrap(),
+                Milliunits::from_scaled_i64(1_234_500)
+            ),
+            "¥1,235"
+        );
+        assert_eq!(
+            formatter.format_milliunits_with_code(
+                CurrencyCode::from_str("SEK").unwrap(),
+                Milliunits::from_scaled_i64(1_234_560)
+            ),
+            "1 234,56 kr"
+        );
+        assert_eq!(
+            formatter.format_milliunits_with_code(
+                CurrencyCode::from_str("XOF").unwrap(),
+                Milliunits::from_scaled_i64(1_234_000)
+            ),
+            "XOF 1,234.00"
+        );
+    }
+
+    #[test]
+    fn test_format_exchange_rate() {
+        assert_eq!(
+            local_formatter(&US_SETTINGS)
+                .format_exchange_rate(ExchangeRate::from_str("0.000041234568").unwrap()),
+            "$0.000041234568"
+        );
+        assert_eq!(
+            local_formatter(&OTHER_SETTINGS)
+                .format_exchange_rate(ExchangeRate::from_str("1234").unwrap()),
+            "1.234X"
+        );
+    }
+
     #[test]
     fn test_format_date() {
         assert_eq!(
-            BudgetFormatter::new(&US_SETTINGS).format_date(NaiveDate::from_ymd(2011, 4, 27)),
+            local_formatter(&US_SETTINGS).format_date(NaiveDate::from_ymd(2011, 4, 27)),
             "04/27/2011"
         );
         assert_eq!(
-            BudgetFormatter::new(&OTHER_SETTINGS).format_date(NaiveDate::from_ymd(2011, 4, 27)),
+            local_formatter(&OTHER_SETTINGS).format_date(NaiveDate::from_ymd(2011, 4, 27)),
             "2011-04-27"
         );
     }
@@ -1,12 +1,26 @@
 use log::debug;
+use rust_decimal::prelude::Zero;
+use rust_decimal::Decimal;
+use std::collections::HashSet;
 use std::ffi::OsStr;
+use std::str::FromStr;
+use std::time::Duration;
 use std::{env, result, str, string};
 
+use crate::budget_formatter::*;
+use crate::clock::*;
 use crate::constants::*;
+use crate::currencies::*;
 use crate::currency_converter_client::*;
+use crate::database::models::*;
 use crate::database::*;
+use crate::ecb_client::*;
 use crate::errors::*;
+use crate::exchange_rate_provider::*;
+use crate::exchange_rates::*;
+use crate::foreign_accounts::*;
 use crate::foreign_transactions_processor::*;
+use crate::types::*;
 use crate::utilities::*;
 use crate::ynab_client::*;
 
@@ -30,6 +44,8 @@ fn initialize() -> Result<()> {
     default_env(DATABASE_FILE_ENV, default_database_file);
     default_env(AUTO_APPROVE_TRANSACTIONS_ENV, false.to_string());
     default_env(AUTO_APPROVE_ADJUSTMENTS_ENV, false.to_string());
+    default_env(YNAB_API_BASE_URL_ENV, DEFAULT_YNAB_API_BASE_URL);
+    default_env(EXCHANGE_RATE_PROVIDER_ENV, DEFAULT_EXCHANGE_RATE_PROVIDER);
     default_env(
         CURRENCY_CONVERTER_API_BASE_URL_ENV,
         DEFAULT_CURRENCY_CONVERTER_API_BASE_URL,
@@ -38,6 +54,24 @@ fn initialize() -> Result<()> {
         CURRENCY_CONVERTER_API_MAX_CURRENCY_PAIRS_PER_REQUEST_ENV,
         DEFAULT_CURRENCY_CONVERTER_API_MAX_CURRENCY_PAIRS_PER_REQUEST.to_string(),
     );
+    default_env(
+        CURRENCY_CONVERTER_API_MAX_RETRIES_ENV,
+        DEFAULT_CURRENCY_CONVERTER_API_MAX_RETRIES.to_string(),
+    );
+    default_env(ECB_BASE_URL_ENV, DEFAULT_ECB_BASE_URL);
+    default_env(
+        RATE_FALLBACK_DAYS_ENV,
+        DEFAULT_RATE_FALLBACK_DAYS.to_string(),
+    );
+    default_env(
+        MAX_RATE_CHANGE_PERCENT_ENV,
+        DEFAULT_MAX_RATE_CHANGE_PERCENT.to_string(),
+    );
+    default_env(RATE_CHANGE_ACTION_ENV, DEFAULT_RATE_CHANGE_ACTION);
+    default_env(RATE_DATE_ENV, DEFAULT_RATE_DATE);
+    default_env(FOREIGN_AMOUNT_STYLE_ENV, DEFAULT_FOREIGN_AMOUNT_STYLE);
+    default_env(OUTPUT_ENV, DEFAULT_OUTPUT);
+    default_env(MISSING_RATE_ENV, DEFAULT_MISSING_RATE);
 
     env_logger::init();
     debug!("Using configuration file path: {:?}", configuration_file);
@@ -50,11 +84,17 @@ fn get_clap_matches() -> clap::ArgMatches<'static> {
         .version(option_env!("CI_BUILD_VERSION").unwrap_or(clap::crate_version!()))
         .author(clap::crate_authors!())
         .about(clap::crate_description!())
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
         .arg(
             clap::Arg::with_name(YES_ARG)
                 .long(YES_ARG)
                 .short("y")
                 .help("Save changes to YNAB budget and database (without this, runs in \"dry run\" mode)"))
+        .arg(
+            clap::Arg::with_name(INTERACTIVE_ARG)
+                .long(INTERACTIVE_ARG)
+                .short("i")
+                .help("Ask whether to accept, skip or change the amount of each new or changed transaction, and save the accepted ones (no need for --yes)"))
         .arg(
             clap::Arg::with_name(AUTO_APPROVE_TRANSACTIONS_ARG)
                 .env(AUTO_APPROVE_TRANSACTIONS_ENV)
@@ -82,14 +122,40 @@ fn get_clap_matches() -> clap::ArgMatches<'static> {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            clap::Arg::with_name(YNAB_API_BASE_URL_ARG)
+                .env(YNAB_API_BASE_URL_ENV)
+                .long(YNAB_API_BASE_URL_ARG)
+                .value_name("URL")
+                .help("YNAB API base URL, without trailing '/' (for testing against a local server)")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            clap::Arg::with_name(EXCHANGE_RATE_PROVIDER_ARG)
+                .env(EXCHANGE_RATE_PROVIDER_ENV)
+                .long(EXCHANGE_RATE_PROVIDER_ARG)
+                .value_name("PROVIDER,...")
+                .help("Exchange rate provider to use ('currency-converter' or 'ecb').  Separate multiple providers with commas to fall back to the next one when a provider fails.")
+                .takes_value(true)
+                .required(true)
+                .validator(|value| {
+                    match value
+                        .split(',')
+                        .find(|name| !POSSIBLE_EXCHANGE_RATE_PROVIDER_VALUES.contains(name))
+                    {
+                        Some(name) => Err(format!("Unknown exchange rate provider: {}", name)),
+                        None => Ok(()),
+                    }
+                }),
+        )
         .arg(
             clap::Arg::with_name(CURRENCY_CONVERTER_API_KEY_ARG)
                 .env(CURRENCY_CONVERTER_API_KEY_ENV)
                 .long(CURRENCY_CONVERTER_API_KEY_ARG)
                 .value_name("KEY")
                 .help("Currency Converter API key (see documentation for setup)")
-                .takes_value(true)
-                .required(true),
+                .takes_value(true),
         )
         .arg(
             clap::Arg::with_name(CURRENCY_CONVERTER_API_BASE_URL_ARG)
@@ -109,6 +175,142 @@ fn get_clap_matches() -> clap::ArgMatches<'static> {
                 .takes_value(true)
                 .validator(|value| map_validator(value.parse::<usize>())),
         )
+        .arg(
+            clap::Arg::with_name(CURRENCY_CONVERTER_API_MAX_RETRIES_ARG)
+                .env(CURRENCY_CONVERTER_API_MAX_RETRIES_ENV)
+                .long(CURRENCY_CONVERTER_API_MAX_RETRIES_ARG)
+                .value_name("NUMBER")
+                .help("Number of times to retry Currency Converter API requests that fail because of connection errors, server errors or rate limiting (waiting longer before each retry)")
+                .takes_value(true)
+                .required(true)
+                .validator(|value| map_validator(value.parse::<u32>())),
+        )
+        .arg(
+            clap::Arg::with_name(ECB_BASE_URL_ARG)
+                .env(ECB_BASE_URL_ENV)
+                .long(ECB_BASE_URL_ARG)
+                .value_name("URL")
+                .help("European Central Bank reference rates base URL, without trailing '/'")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            clap::Arg::with_name(ECB_RATES_FILE_ARG)
+                .env(ECB_RATES_FILE_ENV)
+                .long(ECB_RATES_FILE_ARG)
+                .value_name("PATH")
+                .help("Read European Central Bank reference rates from this local XML file instead of downloading them")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name(FIXED_EXCHANGE_RATES_ARG)
+                .env(FIXED_EXCHANGE_RATES_ENV)
+                .long(FIXED_EXCHANGE_RATES_ARG)
+                .value_name("FROM_TO=RATE,...")
+                .help("Fixed exchange rates for pegged currencies, which are never looked up from the exchange rate provider (e.g. 'EUR_XOF=655.957').  Separate multiple rates with commas.")
+                .takes_value(true)
+                .validator(|value| map_validator(parse_fixed_exchange_rates(value.split(',')))),
+        )
+        .arg(
+            clap::Arg::with_name(PIVOT_CURRENCY_ARG)
+                .env(PIVOT_CURRENCY_ENV)
+                .long(PIVOT_CURRENCY_ARG)
+                .value_name("CURRENCY")
+                .help("Derive exchange rates that the exchange rate provider does not quote directly through this currency (e.g. 'USD')")
+                .takes_value(true)
+                .validator(|value| map_validator(CurrencyCode::from_str(&value.to_uppercase()))),
+        )
+        .arg(
+            clap::Arg::with_name(RATE_FALLBACK_DAYS_ARG)
+                .env(RATE_FALLBACK_DAYS_ENV)
+                .long(RATE_FALLBACK_DAYS_ARG)
+                .value_name("DAYS")
+                .help("When no exchange rate was published for a date (e.g. weekends and bank holidays), use the nearest earlier rate up to this many days before")
+                .takes_value(true)
+                .required(true)
+                .validator(|value| map_validator(value.parse::<u32>())),
+        )
+        .arg(
+            clap::Arg::with_name(MAX_RATE_CHANGE_PERCENT_ARG)
+                .env(MAX_RATE_CHANGE_PERCENT_ENV)
+                .long(MAX_RATE_CHANGE_PERCENT_ARG)
+                .value_name("PERCENT")
+                .help("Largest percentage that a new exchange rate from the exchange rate provider may differ from the previous published rate, to guard against bogus rates")
+                .takes_value(true)
+                .required(true)
+                .validator(|value| map_validator(Decimal::from_str(&value))),
+        )
+        .arg(
+            clap::Arg::with_name(RATE_CHANGE_ACTION_ARG)
+                .env(RATE_CHANGE_ACTION_ENV)
+                .long(RATE_CHANGE_ACTION_ARG)
+                .value_name("ACTION")
+                .help("What to do with a new exchange rate that differs by more than --max-rate-change-percent: refuse it (stopping without saving changes), or ask for confirmation")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&POSSIBLE_RATE_CHANGE_ACTION_VALUES),
+        )
+        .arg(
+            clap::Arg::with_name(RATE_DATE_ARG)
+                .env(RATE_DATE_ENV)
+                .long(RATE_DATE_ARG)
+                .value_name("POLICY")
+                .help("Which date's exchange rate to use for foreign transactions: the transaction's date, the previous business day, or the date from a '<POSTED YYYY-MM-DD>' memo tag.  Accounts can override this with a '<RATE DATE: POLICY>' tag.")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&POSSIBLE_RATE_DATE_VALUES),
+        )
+        .arg(
+            clap::Arg::with_name(CUSTOM_CURRENCIES_ARG)
+                .env(CUSTOM_CURRENCIES_ENV)
+                .long(CUSTOM_CURRENCIES_ARG)
+                .value_name("CODE:DIGITS,...")
+                .help("Currencies that are not in ISO 4217 (e.g. 'USDT:6,DOGE:8'), with their number of decimal digits.  Separate multiple currencies with commas.")
+                .takes_value(true)
+                .validator(|value| map_validator(parse_custom_currencies(&value))),
+        )
+        .arg(
+            clap::Arg::with_name(FOREIGN_AMOUNT_STYLE_ARG)
+                .env(FOREIGN_AMOUNT_STYLE_ENV)
+                .long(FOREIGN_AMOUNT_STYLE_ARG)
+                .value_name("STYLE")
+                .help("How to format foreign amounts in memos and output: the currency code with the budget's number format (e.g. 'EUR 1,234.56'), or the currency's own symbol and number format (e.g. '€1.234,56')")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&POSSIBLE_FOREIGN_AMOUNT_STYLE_VALUES),
+        )
+        .arg(
+            clap::Arg::with_name(OUTPUT_ARG)
+                .env(OUTPUT_ENV)
+                .long(OUTPUT_ARG)
+                .value_name("FORMAT")
+                .help("How the sync command reports what it does: human-readable text, or a JSON document with the planned transactions and a summary (progress messages go to standard error)")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&POSSIBLE_OUTPUT_VALUES),
+        )
+        .arg(
+            clap::Arg::with_name(SAVE_PLAN_ARG)
+                .long(SAVE_PLAN_ARG)
+                .value_name("PATH")
+                .help("On a dry run of the sync command, save the planned changes to this file so that they can be reviewed and saved exactly as planned with the apply command")
+                .takes_value(true)
+                .conflicts_with_all(&[YES_ARG, INTERACTIVE_ARG]),
+        )
+        .arg(
+            clap::Arg::with_name(OFFLINE_ARG)
+                .long(OFFLINE_ARG)
+                .help("Never call exchange rate providers, and only use exchange rates already in the database"))
+        .arg(
+            clap::Arg::with_name(MISSING_RATE_ARG)
+                .env(MISSING_RATE_ENV)
+                .long(MISSING_RATE_ARG)
+                .value_name("POLICY")
+                .help("In offline mode, what to do when an exchange rate is missing from the database: use the known rate for the nearest date, or fail listing the missing rates")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&POSSIBLE_MISSING_RATE_VALUES),
+        )
         .arg(
             clap::Arg::with_name(YNAB_BUDGET_ID_ARG)
                 .env(YNAB_BUDGET_ID_ENV)
@@ -127,6 +329,16 @@ fn get_clap_matches() -> clap::ArgMatches<'static> {
                 .validator(|value| map_validator(parse_iso_date(&value))
                 ),
         )
+        .arg(
+            clap::Arg::with_name(AS_OF_DATE_ARG)
+                .long(AS_OF_DATE_ARG)
+                .value_name("YYYY-MM-DD")
+                .help("Show what a dry run would have done on this date, using this date instead of today's date")
+                .takes_value(true)
+                .conflicts_with_all(&[YES_ARG, INTERACTIVE_ARG, SAVE_PLAN_ARG])
+                .validator(|value| map_validator(parse_iso_date(&value))
+                ),
+        )
         .arg(
             clap::Arg::with_name(DATABASE_FILE_ARG)
                 .env(DATABASE_FILE_ENV)
@@ -135,9 +347,176 @@ fn get_clap_matches() -> clap::ArgMatches<'static> {
                 .help("Set the database file where local data will be stored")
                 .takes_value(true),
         )
+        .subcommand(
+            clap::SubCommand::with_name(SYNC_COMMAND)
+                .about("Create and update difference transactions in the YNAB budget (the default when no command is given)"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name(STATUS_COMMAND)
+                .about("Show the budget's saved state and whether each difference account needs an adjustment, without changing the budget"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name(ACCOUNTS_COMMAND)
+                .about("List the foreign currency and difference accounts found in the YNAB budget"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name(HISTORY_COMMAND)
+                .about("List the difference transactions saved in the database"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name(RESET_COMMAND)
+                .about("Make the next run load all transactions since the start date again (needs --yes to save changes)")
+                .arg(
+                    clap::Arg::with_name(FORGET_BUDGET_ARG)
+                        .long(FORGET_BUDGET_ARG)
+                        .help("Forget the budget and its difference transactions entirely, so that the next run can use a new --start-date"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name(DOCTOR_COMMAND)
+                .about("Check the configuration, database, YNAB budget and exchange rate providers for problems"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name(APPLY_COMMAND)
+                .about("Save the changes from a plan file made by a dry run with --save-plan to the YNAB budget, refusing if the budget has changed since")
+                .arg(
+                    clap::Arg::with_name(PLAN_FILE_ARG)
+                        .help("Plan file to apply")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name(RATES_COMMAND)
+                .about("Manage exchange rates stored in the database")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name(RATES_IMPORT_COMMAND)
+                        .about("Import exchange rates from a CSV file with 'date,from,to,rate' rows (dates formatted YYYY-MM-DD)")
+                        .arg(
+                            clap::Arg::with_name(CSV_FILE_ARG)
+                                .help("CSV file to import")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name(ON_CONFLICT_ARG)
+                                .long(ON_CONFLICT_ARG)
+                                .value_name("ACTION")
+                                .help("What to do when the database already has an exchange rate for the same date and currencies")
+                                .takes_value(true)
+                                .default_value(ON_CONFLICT_FAIL)
+                                .possible_values(&POSSIBLE_ON_CONFLICT_VALUES),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name(RATES_FETCH_COMMAND)
+                        .about("Get exchange rates for a range of dates from the exchange rate provider ahead of time, skipping those already in the database")
+                        .arg(
+                            clap::Arg::with_name(FETCH_FROM_DATE_ARG)
+                                .long(FETCH_FROM_DATE_ARG)
+                                .value_name("YYYY-MM-DD")
+                                .help("First date to get exchange rates for")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(|value| map_validator(parse_iso_date(&value))),
+                        )
+                        .arg(
+                            clap::Arg::with_name(FETCH_TO_DATE_ARG)
+                                .long(FETCH_TO_DATE_ARG)
+                                .value_name("YYYY-MM-DD")
+                                .help("Last date to get exchange rates for.  Defaults to today's date.")
+                                .takes_value(true)
+                                .validator(|value| map_validator(parse_iso_date(&value))),
+                        )
+                        .arg(
+                            clap::Arg::with_name(CURRENCIES_ARG)
+                                .long(CURRENCIES_ARG)
+                                .value_name("CURRENCY,...")
+                                .help("Currencies of your foreign accounts, separated by commas (e.g. 'EUR,GBP')")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(|value| map_validator(parse_currency_codes(&value))),
+                        )
+                        .arg(
+                            clap::Arg::with_name(BUDGET_CURRENCY_ARG)
+                                .long(BUDGET_CURRENCY_ARG)
+                                .value_name("CURRENCY")
+                                .help("Your budget's currency (e.g. 'USD')")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(|value| map_validator(CurrencyCode::from_str(&value.to_uppercase()))),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name(RATES_OVERRIDE_COMMAND)
+                        .about("Manage exchange rate overrides, which take precedence over rates from exchange rate providers")
+                        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                        .subcommand(
+                            clap::SubCommand::with_name(RATES_OVERRIDE_SET_COMMAND)
+                                .about("Set the exchange rate to use for a date and currency pair")
+                                .arg(date_arg())
+                                .arg(from_currency_arg())
+                                .arg(to_currency_arg())
+                                .arg(
+                                    clap::Arg::with_name(RATE_ARG)
+                                        .help("Exchange rate (units of TO currency per unit of FROM currency)")
+                                        .required(true),
+                                ),
+                        )
+                        .subcommand(
+                            clap::SubCommand::with_name(RATES_OVERRIDE_LIST_COMMAND)
+                                .about("List exchange rate overrides"),
+                        )
+                        .subcommand(
+                            clap::SubCommand::with_name(RATES_OVERRIDE_REMOVE_COMMAND)
+                                .about("Remove an exchange rate override")
+                                .arg(date_arg())
+                                .arg(from_currency_arg())
+                                .arg(to_currency_arg()),
+                        ),
+                ),
+        )
         .get_matches()
 }
 
+fn date_arg() -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name(DATE_ARG)
+        .help("Date of the exchange rate (YYYY-MM-DD)")
+        .required(true)
+        .validator(|value| map_validator(parse_iso_date(&value)))
+}
+
+fn from_currency_arg() -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name(FROM_CURRENCY_ARG)
+        .help("Currency code to convert from (e.g. a foreign account's currency)")
+        .required(true)
+}
+
+fn to_currency_arg() -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name(TO_CURRENCY_ARG)
+        .help("Currency code to convert to (e.g. the budget's currency)")
+        .required(true)
+}
+
+fn explain_quota_exhausted<T>(result: Result<T>, saved_state: &str) -> Result<T> {
+    match result {
+        Err(err) if is_quota_exhausted(&err) => Err(err).chain_err(|| {
+            format!(
+                "Exchange rate provider quota exhausted.  {}; run again later to resume.",
+                saved_state
+            )
+        }),
+        other => other,
+    }
+}
+
+fn parse_currency_codes(value: &str) -> Result<HashSet<CurrencyCode>> {
+    value
+        .split(',')
+        .filter(|code| !code.trim().is_empty())
+        .map(|code| CurrencyCode::from_str(&code.trim().to_uppercase()))
+        .collect()
+}
+
 fn map_validator<T, U>(result: result::Result<T, U>) -> result::Result<(), String>
 where
     U: string::ToString,
@@ -146,58 +525,746 @@ where
 }
 
 fn run_clap_matches(matches: clap::ArgMatches) -> Result<()> {
-    let dry_run = !matches.is_present(YES_ARG);
+    match matches.subcommand() {
+        ("", None) | (SYNC_COMMAND, Some(_)) => run_sync_command(&matches),
+        (STATUS_COMMAND, Some(_)) => run_status_command(&matches),
+        (ACCOUNTS_COMMAND, Some(_)) => run_accounts_command(&matches),
+        (HISTORY_COMMAND, Some(_)) => run_history_command(&matches),
+        (RESET_COMMAND, Some(reset_matches)) => run_reset_command(&matches, reset_matches),
+        (DOCTOR_COMMAND, Some(_)) => run_doctor_command(&matches),
+        (APPLY_COMMAND, Some(apply_matches)) => run_apply_command(&matches, apply_matches),
+        (RATES_COMMAND, Some(rates_matches)) => run_rates_command(&matches, rates_matches),
+        (other, _) => panic!("CLAP matches should have valid subcommand: {}", other),
+    }
+}
+
+fn run_sync_command(matches: &clap::ArgMatches) -> Result<()> {
+    let interactive = matches.is_present(INTERACTIVE_ARG);
+    let dry_run = !matches.is_present(YES_ARG) && !interactive;
     let auto_approve_transactions =
         clap::value_t!(matches.value_of(AUTO_APPROVE_TRANSACTIONS_ARG), bool)
             .expect("CLAP matches should have valid AUTO_APPROVE_TRANSACTIONS_ARG");
     let auto_approve_adjustments =
         clap::value_t!(matches.value_of(AUTO_APPROVE_ADJUSTMENTS_ARG), bool)
             .expect("CLAP matches should have valid AUTO_APPROVE_ADJUSTMENTS_ARG");
-    let ynab_budget_id = matches
-        .value_of(YNAB_BUDGET_ID_ARG)
-        .expect("CLAP matches should have YNAB_BUDGET_ID_ARG");
     let start_date_arg = matches
         .value_of(START_DATE_ARG)
         .map(parse_iso_date)
         .transpose()
         .expect("CLAP matches should have valid START_DATE_ARG");
-    let ynab_client = YnabBudgetClient::new(
+    let rate_date_policy = RateDatePolicy::from_str(
         matches
-            .value_of(YNAB_ACCESS_TOKEN_ARG)
-            .expect("CLAP matches should have YNAB_ACCESS_TOKEN_ARG")
-            .to_string(),
-        ynab_budget_id,
+            .value_of(RATE_DATE_ARG)
+            .expect("CLAP matches should have RATE_DATE_ARG"),
+    )
+    .expect("CLAP matches should have valid RATE_DATE_ARG");
+    let output_format = match matches
+        .value_of(OUTPUT_ARG)
+        .expect("CLAP matches should have OUTPUT_ARG")
+    {
+        OUTPUT_TEXT => OutputFormat::Text,
+        OUTPUT_JSON => OutputFormat::Json,
+        other => panic!("CLAP matches should have valid OUTPUT_ARG: {}", other),
+    };
+    set_progress_to_stderr(output_format == OutputFormat::Json);
+    let ynab_client = ynab_client_from_matches(matches, SYNC_COMMAND)?;
+    let exchange_rate_provider = optional_exchange_rate_provider_from_matches(matches)?;
+    let mut database = database_from_matches(matches, dry_run)?;
+    if matches.is_present(AS_OF_DATE_ARG) {
+        // A run as of another date must not make later runs skip changes.
+        database = database.read_only();
+    }
+    let currency_registry = currency_registry_from_matches(matches);
+    let exchange_rates_cache =
+        exchange_rates_cache_from_matches(matches, &database, &exchange_rate_provider)?;
+    // All exchange rates are looked up before anything is saved to the
+    // budget, so a run that runs out of quota can simply be repeated.
+    let plan = explain_quota_exhausted(
+        ForeignTransactionsProcessor::run(
+            &database,
+            &ynab_client,
+            &exchange_rates_cache,
+            &currency_registry,
+            start_date_arg,
+            dry_run,
+            auto_approve_transactions,
+            auto_approve_adjustments,
+            rate_date_policy,
+            foreign_amount_style_from_matches(matches),
+            matches.value_of(SAVE_PLAN_ARG),
+            if interactive {
+                Some(&TerminalPrompter)
+            } else {
+                None
+            },
+            clock_from_matches(matches).as_ref(),
+        ),
+        "No changes were saved to the YNAB budget",
+    )?;
+    if output_format == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&plan).expect("JSON plan should serialize")
+        );
+    }
+    Ok(())
+}
+
+fn run_status_command(matches: &clap::ArgMatches) -> Result<()> {
+    let ynab_client = ynab_client_from_matches(matches, STATUS_COMMAND)?;
+    let exchange_rate_provider = optional_exchange_rate_provider_from_matches(matches)?;
+    let database = database_from_matches(matches, true)?;
+    let currency_registry = currency_registry_from_matches(matches);
+    // Status only reports, so it does not save any fetched exchange rates.
+    let exchange_rates_cache =
+        exchange_rates_cache_from_matches(matches, &database, &exchange_rate_provider)?.read_only();
+    explain_quota_exhausted(
+        ForeignTransactionsProcessor::status(
+            &database,
+            &ynab_client,
+            &exchange_rates_cache,
+            &currency_registry,
+            foreign_amount_style_from_matches(matches),
+            clock_from_matches(matches).as_ref(),
+        ),
+        "Nothing was changed",
+    )
+}
+
+fn run_apply_command(matches: &clap::ArgMatches, apply_matches: &clap::ArgMatches) -> Result<()> {
+    let plan_file = apply_matches
+        .value_of(PLAN_FILE_ARG)
+        .expect("CLAP matches should have PLAN_FILE_ARG");
+    if matches.is_present(AS_OF_DATE_ARG) {
+        bail!(
+            "--{} may not be used with {}",
+            AS_OF_DATE_ARG,
+            APPLY_COMMAND
+        );
+    }
+    let ynab_client = ynab_client_from_matches(matches, APPLY_COMMAND)?;
+    let exchange_rate_provider = optional_exchange_rate_provider_from_matches(matches)?;
+    let database = database_from_matches(matches, false)?;
+    let currency_registry = currency_registry_from_matches(matches);
+    let exchange_rates_cache =
+        exchange_rates_cache_from_matches(matches, &database, &exchange_rate_provider)?;
+    ForeignTransactionsProcessor::apply(
+        &database,
+        &ynab_client,
+        &exchange_rates_cache,
+        &currency_registry,
+        foreign_amount_style_from_matches(matches),
+        plan_file,
+        &SystemClock,
+    )
+}
+
+fn run_accounts_command(matches: &clap::ArgMatches) -> Result<()> {
+    let ynab_client = ynab_client_from_matches(matches, ACCOUNTS_COMMAND)?;
+    let currency_registry = currency_registry_from_matches(matches);
+    progress!("Loading budget settings from YNAB...");
+    let budget_settings = ynab_client.get_budget_settings()?;
+    let budget_formatter = BudgetFormatter::new(
+        &budget_settings,
+        foreign_amount_style_from_matches(matches),
+        &currency_registry,
     );
-    let currency_converter_client = CurrencyConverterClient::new(
-        matches
-            .value_of(CURRENCY_CONVERTER_API_KEY_ARG)
-            .expect("CLAP matches should have CURRENCY_CONVERTER_API_KEY_ARG"),
-        matches
-            .value_of(CURRENCY_CONVERTER_API_BASE_URL_ARG)
-            .expect("CLAP matches should have CURRENCY_CONVERTER_API_BASE_URL_ARG"),
-        matches
-            .value_of(CURRENCY_CONVERTER_API_MAX_CURRENCY_PAIRS_PER_REQUEST_ARG)
-            .expect("CLAP matches should have CURRENCY_CONVERTER_API_MAX_CURRENCY_PAIRS_PER_REQUEST_ARG")
-            .parse()
-            .expect("CLAP matches should have valid CURRENCY_CONVERTER_API_MAX_CURRENCY_PAIRS_PER_REQUEST_ARG"),
+    ForeignAccounts::load(
+        &ynab_client,
+        &budget_formatter,
+        CurrencyCode::from_str(&budget_settings.currency_format.iso_code)?,
+        &currency_registry,
+    )?;
+    Ok(())
+}
+
+fn run_history_command(matches: &clap::ArgMatches) -> Result<()> {
+    let ynab_client = ynab_client_from_matches(matches, HISTORY_COMMAND)?;
+    let ynab_budget_id = ynab_client.budget_id();
+    let database = database_from_matches(matches, true)?;
+    let currency_registry = currency_registry_from_matches(matches);
+    progress!("Loading budget settings from YNAB...");
+    let budget_settings = ynab_client.get_budget_settings()?;
+    let budget_formatter = BudgetFormatter::new(
+        &budget_settings,
+        foreign_amount_style_from_matches(matches),
+        &currency_registry,
     );
-    let database = Database::establish_connection(
+    let entries = database.get_difference_transactions_history(ynab_budget_id)?;
+    if entries.is_empty() {
+        println!(
+            "No difference transactions are saved for budget {}.",
+            ynab_budget_id
+        );
+    }
+    for entry in entries {
+        println!(
+            "{}  {}  {}{}{}{}",
+            entry
+                .inner
+                .exchange_rate_date
+                .map(format_iso_date)
+                .unwrap_or_else(|| "----------".to_string()),
+            entry.inner.difference_key,
+            budget_formatter.format_milliunits(entry.inner.amount),
+            if entry.inner.fee_amount.is_zero() {
+                String::new()
+            } else {
+                format!(
+                    " (fee {})",
+                    budget_formatter.format_milliunits(entry.inner.fee_amount)
+                )
+            },
+            match entry.inner.transfer_key {
+                Some(transfer_key) => format!(", transfer to {}", transfer_key),
+                None => String::new(),
+            },
+            if entry.needs_recompute {
+                ", to be recomputed"
+            } else {
+                ""
+            },
+        );
+        println!(
+            "    foreign transaction {}, difference transaction {}",
+            entry.foreign_transaction_id, entry.inner.difference_transaction_id
+        );
+    }
+    Ok(())
+}
+
+fn run_reset_command(matches: &clap::ArgMatches, reset_matches: &clap::ArgMatches) -> Result<()> {
+    let dry_run = !matches.is_present(YES_ARG);
+    let forget_budget = reset_matches.is_present(FORGET_BUDGET_ARG);
+    let ynab_budget_id = required_value_of(matches, YNAB_BUDGET_ID_ARG, RESET_COMMAND)?;
+    let database = database_from_matches(matches, dry_run)?;
+    ensure!(
+        database.get_budget_state(ynab_budget_id)?.is_some(),
+        "Budget {} has not been synced yet; nothing to reset",
+        ynab_budget_id
+    );
+    let description = if forget_budget {
+        "forget the budget and its difference transactions, so the next run starts over"
+    } else {
+        "make the next run load all transactions since the start date again"
+    };
+    if dry_run {
+        println!(
+            "DRY RUN: would {}.  Use --{} to save this change.",
+            description, YES_ARG
+        );
+    } else if database.reset_budget(ynab_budget_id, forget_budget)? {
+        println!(
+            "Reset budget {}: the tool will {}.",
+            ynab_budget_id, description
+        );
+    } else {
+        println!(
+            "No state found for budget {}; nothing was reset.",
+            ynab_budget_id
+        );
+    }
+    Ok(())
+}
+
+fn run_doctor_command(matches: &clap::ArgMatches) -> Result<()> {
+    let mut problems_count = 0;
+    let mut check = |description: &str, result: Result<String>| match result {
+        Ok(detail) => println!("OK       {}: {}", description, detail),
+        Err(err) => {
+            problems_count += 1;
+            println!(
+                "PROBLEM  {}: {}",
+                description,
+                err.iter()
+                    .map(|cause| cause.to_string())
+                    .collect::<Vec<_>>()
+                    .join(": ")
+            );
+        }
+    };
+    check(
+        "Database",
+        database_from_matches(matches, true).map(|_| {
+            matches
+                .value_of(DATABASE_FILE_ARG)
+                .expect("CLAP matches should have DATABASE_FILE_ARG")
+                .to_string()
+        }),
+    );
+    check(
+        "Exchange rate providers",
+        if matches.is_present(OFFLINE_ARG) {
+            Ok("not used in offline mode".to_string())
+        } else {
+            exchange_rate_provider_from_matches(matches)
+                .map(|exchange_rate_provider| exchange_rate_provider.name().to_string())
+        },
+    );
+    let currency_registry = currency_registry_from_matches(matches);
+    let budget_settings =
+        ynab_client_from_matches(matches, DOCTOR_COMMAND).and_then(|ynab_client| {
+            let budget_settings = ynab_client.get_budget_settings()?;
+            Ok((ynab_client, budget_settings))
+        });
+    match budget_settings {
+        Ok((ynab_client, budget_settings)) => {
+            check(
+                "YNAB budget",
+                Ok(format!(
+                    "{} ({})",
+                    ynab_client.budget_id, budget_settings.currency_format.iso_code
+                )),
+            );
+            let budget_formatter = BudgetFormatter::new(
+                &budget_settings,
+                foreign_amount_style_from_matches(matches),
+                &currency_registry,
+            );
+            check(
+                "Budget accounts",
+                CurrencyCode::from_str(&budget_settings.currency_format.iso_code).and_then(
+                    |local_currency| {
+                        ForeignAccounts::load(
+                            &ynab_client,
+                            &budget_formatter,
+                            local_currency,
+                            &currency_registry,
+                        )?;
+                        Ok("foreign currency and difference accounts are set up".to_string())
+                    },
+                ),
+            );
+        }
+        Err(err) => check("YNAB budget", Err(err)),
+    }
+    ensure!(
+        problems_count == 0,
+        "Found {} problem(s); see above",
+        problems_count
+    );
+    Ok(())
+}
+
+fn run_rates_command(matches: &clap::ArgMatches, rates_matches: &clap::ArgMatches) -> Result<()> {
+    let database = database_from_matches(matches, false)?;
+    match rates_matches.subcommand() {
+        (RATES_IMPORT_COMMAND, Some(import_matches)) => {
+            let exchange_rates = read_exchange_rates_csv(
+                import_matches
+                    .value_of(CSV_FILE_ARG)
+                    .expect("CLAP matches should have CSV_FILE_ARG"),
+            )?;
+            let on_conflict = match import_matches
+                .value_of(ON_CONFLICT_ARG)
+                .expect("CLAP matches should have ON_CONFLICT_ARG")
+            {
+                ON_CONFLICT_SKIP => ExchangeRateConflict::Skip,
+                ON_CONFLICT_OVERWRITE => ExchangeRateConflict::Overwrite,
+                ON_CONFLICT_FAIL => ExchangeRateConflict::Fail,
+                other => panic!("CLAP matches should have valid ON_CONFLICT_ARG: {}", other),
+            };
+            let imported = database.import_exchange_rates(&exchange_rates, on_conflict)?;
+            println!(
+                "Imported {} of {} exchange rates.",
+                imported.imported_count,
+                exchange_rates.len()
+            );
+            print_flagged_for_recompute(imported.flagged_count);
+            Ok(())
+        }
+        (RATES_FETCH_COMMAND, Some(fetch_matches)) => {
+            run_rates_fetch_command(matches, &database, fetch_matches)
+        }
+        (RATES_OVERRIDE_COMMAND, Some(override_matches)) => {
+            run_rates_override_command(&database, override_matches)
+        }
+        _ => panic!("CLAP matches should have valid rates subcommand"),
+    }
+}
+
+fn run_rates_fetch_command(
+    matches: &clap::ArgMatches,
+    database: &Database,
+    fetch_matches: &clap::ArgMatches,
+) -> Result<()> {
+    let start_date = parse_iso_date(
+        fetch_matches
+            .value_of(FETCH_FROM_DATE_ARG)
+            .expect("CLAP matches should have FETCH_FROM_DATE_ARG"),
+    )?;
+    let end_date = fetch_matches
+        .value_of(FETCH_TO_DATE_ARG)
+        .map(parse_iso_date)
+        .transpose()?
+        .unwrap_or_else(|| clock_from_matches(matches).today());
+    ensure!(
+        start_date <= end_date,
+        "--{} date must not be after --{} date",
+        FETCH_FROM_DATE_ARG,
+        FETCH_TO_DATE_ARG
+    );
+    let to_currency = CurrencyCode::from_str(
+        &fetch_matches
+            .value_of(BUDGET_CURRENCY_ARG)
+            .expect("CLAP matches should have BUDGET_CURRENCY_ARG")
+            .to_uppercase(),
+    )?;
+    let fixed_exchange_rates = matches
+        .value_of(FIXED_EXCHANGE_RATES_ARG)
+        .map(|value| parse_fixed_exchange_rates(value.split(',')))
+        .transpose()?
+        .unwrap_or_default();
+    // Currencies pegged with fixed exchange rates are never looked up.
+    let from_currencies: HashSet<CurrencyCode> = parse_currency_codes(
+        fetch_matches
+            .value_of(CURRENCIES_ARG)
+            .expect("CLAP matches should have CURRENCIES_ARG"),
+    )?
+    .into_iter()
+    .filter(|&currency| {
+        currency != to_currency
+            && !fixed_exchange_rates.contains_key(&(currency, to_currency))
+            && !fixed_exchange_rates.contains_key(&(to_currency, currency))
+    })
+    .collect();
+    let exchange_rate_provider = exchange_rate_provider_from_matches(matches)?;
+    println!(
+        "Getting exchange rates from {} to {}...",
+        format_iso_date(start_date),
+        format_iso_date(end_date)
+    );
+    let prefetched = explain_quota_exhausted(
+        prefetch_exchange_rates(
+            &exchange_rate_provider,
+            database,
+            start_date,
+            end_date,
+            &from_currencies,
+            to_currency,
+            clap::value_t!(matches.value_of(RATE_FALLBACK_DAYS_ARG), u32)
+                .expect("CLAP matches should have valid RATE_FALLBACK_DAYS_ARG"),
+            Some(rate_change_check_from_matches(matches)),
+        ),
+        "The exchange rates received so far were saved",
+    )?;
+    println!("Saved {} exchange rates.", prefetched.saved_count);
+    if prefetched.missing_count > 0 {
+        println!(
+            "{} exchange rates are not available from {} (or are not published yet).",
+            prefetched.missing_count,
+            exchange_rate_provider.name()
+        );
+    }
+    Ok(())
+}
+
+fn run_rates_override_command(
+    database: &Database,
+    override_matches: &clap::ArgMatches,
+) -> Result<()> {
+    match override_matches.subcommand() {
+        (RATES_OVERRIDE_SET_COMMAND, Some(set_matches)) => {
+            let exchange_rate_override = parse_dated_exchange_rate(
+                set_matches
+                    .value_of(DATE_ARG)
+                    .expect("CLAP matches should have DATE_ARG"),
+                set_matches
+                    .value_of(FROM_CURRENCY_ARG)
+                    .expect("CLAP matches should have FROM_CURRENCY_ARG"),
+                set_matches
+                    .value_of(TO_CURRENCY_ARG)
+                    .expect("CLAP matches should have TO_CURRENCY_ARG"),
+                set_matches
+                    .value_of(RATE_ARG)
+                    .expect("CLAP matches should have RATE_ARG"),
+            )?;
+            let flagged_count = database.set_exchange_rate_override(&exchange_rate_override)?;
+            println!(
+                "Set exchange rate override from {} to {} on {}: {}",
+                exchange_rate_override.from_currency,
+                exchange_rate_override.to_currency,
+                format_iso_date(exchange_rate_override.date),
+                exchange_rate_override.exchange_rate
+            );
+            print_flagged_for_recompute(flagged_count);
+            Ok(())
+        }
+        (RATES_OVERRIDE_LIST_COMMAND, Some(_)) => {
+            let exchange_rate_overrides = database.get_exchange_rate_overrides()?;
+            if exchange_rate_overrides.is_empty() {
+                println!("No exchange rate overrides.");
+            }
+            for exchange_rate_override in exchange_rate_overrides {
+                println!(
+                    "{} {} {} {}",
+                    format_iso_date(exchange_rate_override.date),
+                    exchange_rate_override.from_currency,
+                    exchange_rate_override.to_currency,
+                    exchange_rate_override.exchange_rate
+                );
+            }
+            Ok(())
+        }
+        (RATES_OVERRIDE_REMOVE_COMMAND, Some(remove_matches)) => {
+            let date = parse_iso_date(
+                remove_matches
+                    .value_of(DATE_ARG)
+                    .expect("CLAP matches should have DATE_ARG"),
+            )?;
+            let from_currency = CurrencyCode::from_str(
+                &remove_matches
+                    .value_of(FROM_CURRENCY_ARG)
+                    .expect("CLAP matches should have FROM_CURRENCY_ARG")
+                    .to_uppercase(),
+            )?;
+            let to_currency = CurrencyCode::from_str(
+                &remove_matches
+                    .value_of(TO_CURRENCY_ARG)
+                    .expect("CLAP matches should have TO_CURRENCY_ARG")
+                    .to_uppercase(),
+            )?;
+            let flagged_count = database
+                .remove_exchange_rate_override(from_currency, to_currency, date)?
+                .chain_err(|| {
+                    format!(
+                        "No exchange rate override from {} to {} on {}",
+                        from_currency,
+                        to_currency,
+                        format_iso_date(date)
+                    )
+                })?;
+            println!(
+                "Removed exchange rate override from {} to {} on {}",
+                from_currency,
+                to_currency,
+                format_iso_date(date)
+            );
+            print_flagged_for_recompute(flagged_count);
+            Ok(())
+        }
+        _ => panic!("CLAP matches should have valid rates override subcommand"),
+    }
+}
+
+fn print_flagged_for_recompute(flagged_count: usize) {
+    if flagged_count > 0 {
+        println!(
+            "Flagged {} difference transactions to be recomputed on the next run.",
+            flagged_count
+        );
+    }
+}
+
+fn rate_change_check_from_matches(matches: &clap::ArgMatches) -> RateChangeCheck {
+    RateChangeCheck {
+        max_change_percent: Decimal::from_str(
+            matches
+                .value_of(MAX_RATE_CHANGE_PERCENT_ARG)
+                .expect("CLAP matches should have MAX_RATE_CHANGE_PERCENT_ARG"),
+        )
+        .expect("CLAP matches should have valid MAX_RATE_CHANGE_PERCENT_ARG"),
+        action: match matches
+            .value_of(RATE_CHANGE_ACTION_ARG)
+            .expect("CLAP matches should have RATE_CHANGE_ACTION_ARG")
+        {
+            RATE_CHANGE_REFUSE => RateChangeAction::Refuse,
+            // Nobody is there to answer the question when run from cron or by
+            // a script reading the JSON output, so it would block the run.
+            RATE_CHANGE_CONFIRM if !atty::is(atty::Stream::Stdin) || progress_to_stderr() => {
+                progress!(
+                    "Exchange rate changes can only be confirmed on a terminal with text output, so rates that changed too much are refused."
+                );
+                RateChangeAction::Refuse
+            }
+            RATE_CHANGE_CONFIRM => RateChangeAction::Confirm,
+            other => panic!(
+                "CLAP matches should have valid RATE_CHANGE_ACTION_ARG: {}",
+                other
+            ),
+        },
+    }
+}
+
+/// Get an option that is only required by some commands, such as the YNAB
+/// budget ID, which is not needed to manage exchange rates.
+fn required_value_of<'a>(
+    matches: &'a clap::ArgMatches,
+    arg_name: &str,
+    command_name: &str,
+) -> Result<&'a str> {
+    matches.value_of(arg_name).chain_err(|| {
+        format!(
+            "--{} is required for the {} command",
+            arg_name, command_name
+        )
+    })
+}
+
+fn ynab_client_from_matches<'a>(
+    matches: &'a clap::ArgMatches,
+    command_name: &str,
+) -> Result<YnabBudgetClient<'a>> {
+    Ok(YnabBudgetClient::new(
+        required_value_of(matches, YNAB_ACCESS_TOKEN_ARG, command_name)?.to_string(),
+        matches
+            .value_of(YNAB_API_BASE_URL_ARG)
+            .expect("CLAP matches should have YNAB_API_BASE_URL_ARG"),
+        required_value_of(matches, YNAB_BUDGET_ID_ARG, command_name)?,
+    ))
+}
+
+fn clock_from_matches(matches: &clap::ArgMatches) -> Box<dyn Clock> {
+    match matches
+        .value_of(AS_OF_DATE_ARG)
+        .map(parse_iso_date)
+        .transpose()
+        .expect("CLAP matches should have valid AS_OF_DATE_ARG")
+    {
+        // New import IDs still use the current time, so that they never
+        // clash with those of earlier runs.
+        Some(as_of_date) => Box::new(FixedClock::new(as_of_date, chrono::Utc::now())),
+        None => Box::new(SystemClock),
+    }
+}
+
+fn database_from_matches(matches: &clap::ArgMatches, dry_run: bool) -> Result<Database> {
+    Database::establish_connection(
         matches
             .value_of(DATABASE_FILE_ARG)
             .expect("CLAP matches should have DATABASE_FILE_ARG"),
         dry_run,
-    )?;
-    ForeignTransactionsProcessor::run(
-        &database,
-        &ynab_client,
-        &currency_converter_client,
-        start_date_arg,
-        dry_run,
-        auto_approve_transactions,
-        auto_approve_adjustments,
     )
 }
 
+fn currency_registry_from_matches(matches: &clap::ArgMatches) -> CurrencyRegistry {
+    CurrencyRegistry::new(
+        &matches
+            .value_of(CUSTOM_CURRENCIES_ARG)
+            .map(parse_custom_currencies)
+            .transpose()
+            .expect("CLAP matches should have valid CUSTOM_CURRENCIES_ARG")
+            .unwrap_or_default(),
+    )
+}
+
+fn foreign_amount_style_from_matches(matches: &clap::ArgMatches) -> ForeignAmountStyle {
+    match matches
+        .value_of(FOREIGN_AMOUNT_STYLE_ARG)
+        .expect("CLAP matches should have FOREIGN_AMOUNT_STYLE_ARG")
+    {
+        FOREIGN_AMOUNT_STYLE_LOCAL => ForeignAmountStyle::Local,
+        FOREIGN_AMOUNT_STYLE_NATIVE => ForeignAmountStyle::Native,
+        other => panic!(
+            "CLAP matches should have valid FOREIGN_AMOUNT_STYLE_ARG: {}",
+            other
+        ),
+    }
+}
+
+/// The exchange rate provider, or `None` in offline mode.
+fn optional_exchange_rate_provider_from_matches<'a>(
+    matches: &'a clap::ArgMatches,
+) -> Result<Option<FallbackExchangeRateProvider<'a>>> {
+    if matches.is_present(OFFLINE_ARG) {
+        Ok(None)
+    } else {
+        exchange_rate_provider_from_matches(matches).map(Some)
+    }
+}
+
+fn exchange_rates_cache_from_matches<'a>(
+    matches: &clap::ArgMatches,
+    database: &'a Database,
+    exchange_rate_provider: &'a Option<FallbackExchangeRateProvider<'a>>,
+) -> Result<ExchangeRatesCache<'a>> {
+    let fixed_exchange_rates = matches
+        .value_of(FIXED_EXCHANGE_RATES_ARG)
+        .map(|value| parse_fixed_exchange_rates(value.split(',')))
+        .transpose()?
+        .unwrap_or_default();
+    let pivot_currency = matches
+        .value_of(PIVOT_CURRENCY_ARG)
+        .map(|value| CurrencyCode::from_str(&value.to_uppercase()))
+        .transpose()
+        .expect("CLAP matches should have valid PIVOT_CURRENCY_ARG");
+    let exchange_rate_source = match exchange_rate_provider {
+        Some(exchange_rate_provider) => ExchangeRateSource::Provider(exchange_rate_provider),
+        None => ExchangeRateSource::Offline(
+            match matches
+                .value_of(MISSING_RATE_ARG)
+                .expect("CLAP matches should have MISSING_RATE_ARG")
+            {
+                MISSING_RATE_NEAREST => MissingRatePolicy::Nearest,
+                MISSING_RATE_FAIL => MissingRatePolicy::Fail,
+                other => panic!("CLAP matches should have valid MISSING_RATE_ARG: {}", other),
+            },
+        ),
+    };
+    Ok(ExchangeRatesCache::new(
+        exchange_rate_source,
+        database,
+        fixed_exchange_rates,
+        pivot_currency,
+        clap::value_t!(matches.value_of(RATE_FALLBACK_DAYS_ARG), u32)
+            .expect("CLAP matches should have valid RATE_FALLBACK_DAYS_ARG"),
+        Some(rate_change_check_from_matches(matches)),
+    ))
+}
+
+fn exchange_rate_provider_from_matches<'a>(
+    matches: &'a clap::ArgMatches,
+) -> Result<FallbackExchangeRateProvider<'a>> {
+    let mut providers: Vec<Box<dyn ExchangeRateProvider + 'a>> = Vec::new();
+    for provider_name in matches
+        .value_of(EXCHANGE_RATE_PROVIDER_ARG)
+        .expect("CLAP matches should have EXCHANGE_RATE_PROVIDER_ARG")
+        .split(',')
+    {
+        providers.push(match provider_name {
+            CURRENCY_CONVERTER_PROVIDER => Box::new(CurrencyConverterClient::new(
+                matches
+                    .value_of(CURRENCY_CONVERTER_API_KEY_ARG)
+                    .chain_err(|| {
+                        format!(
+                            "--{} is required when using the {} exchange rate provider",
+                            CURRENCY_CONVERTER_API_KEY_ARG, CURRENCY_CONVERTER_PROVIDER
+                        )
+                    })?,
+                matches
+                    .value_of(CURRENCY_CONVERTER_API_BASE_URL_ARG)
+                    .expect("CLAP matches should have CURRENCY_CONVERTER_API_BASE_URL_ARG"),
+                matches
+                    .value_of(CURRENCY_CONVERTER_API_MAX_CURRENCY_PAIRS_PER_REQUEST_ARG)
+                    .expect("CLAP matches should have CURRENCY_CONVERTER_API_MAX_CURRENCY_PAIRS_PER_REQUEST_ARG")
+                    .parse()
+                    .expect("CLAP matches should have valid CURRENCY_CONVERTER_API_MAX_CURRENCY_PAIRS_PER_REQUEST_ARG"),
+                RetryPolicy {
+                    max_retries: clap::value_t!(matches.value_of(CURRENCY_CONVERTER_API_MAX_RETRIES_ARG), u32)
+                        .expect("CLAP matches should have valid CURRENCY_CONVERTER_API_MAX_RETRIES_ARG"),
+                    initial_backoff: Duration::from_millis(CURRENCY_CONVERTER_API_INITIAL_BACKOFF_MILLIS),
+                    max_backoff: Duration::from_secs(CURRENCY_CONVERTER_API_MAX_BACKOFF_SECS),
+                },
+            )),
+            ECB_PROVIDER => Box::new(EcbClient::new(
+                match matches.value_of(ECB_RATES_FILE_ARG) {
+                    Some(path) => EcbSource::File(path),
+                    None => EcbSource::BaseUrl(
+                        matches
+                            .value_of(ECB_BASE_URL_ARG)
+                            .expect("CLAP matches should have ECB_BASE_URL_ARG"),
+                    ),
+                },
+            )),
+            _ => panic!(
+                "CLAP matches should have valid EXCHANGE_RATE_PROVIDER_ARG: {}",
+                provider_name
+            ),
+        });
+    }
+    Ok(FallbackExchangeRateProvider::new(providers))
+}
+
 fn default_env<V: AsRef<OsStr>>(var_name: &str, default_value: V) {
     if let Err(env::VarError::NotPresent) = env::var(var_name) {
         env::set_var(var_name, default_value);
@@ -0,0 +1,45 @@
+use chrono::{DateTime, NaiveDate, Utc};
+
+/// Where the current date and time come from, so that a sync can be run as of
+/// another date and tests are reproducible.
+pub trait Clock {
+    /// Today's date, which transactions and adjustments are processed as of.
+    fn today(&self) -> NaiveDate;
+
+    /// The current time, which makes new import IDs unique.
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn today(&self) -> NaiveDate {
+        chrono::Local::today().naive_utc()
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that is stopped at a given date and time.
+pub struct FixedClock {
+    today: NaiveDate,
+    now: DateTime<Utc>,
+}
+
+impl FixedClock {
+    pub fn new(today: NaiveDate, now: DateTime<Utc>) -> FixedClock {
+        FixedClock { today, now }
+    }
+}
+
+impl Clock for FixedClock {
+    fn today(&self) -> NaiveDate {
+        self.today
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        self.now
+    }
+}
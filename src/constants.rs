@@ -7,9 +7,43 @@ pub const IMPORT_ID_PREFIX: &str = "FCAY";
 pub const ADJUSTMENT_MEMO_PREFIX: &str = "Exchange rate adjustment: ";
 pub const DIFFERENCE_MEMO_TAG_PREFIX: &str = "CONVERT: ";
 pub const DEFAULT_DATABASE_FILENAME: &str = "data.sqlite3";
+pub const DEFAULT_YNAB_API_BASE_URL: &str = "https://api.youneedabudget.com/v1";
 pub const DEFAULT_CURRENCY_CONVERTER_API_BASE_URL: &str = "https://free.currconv.com";
 pub const DEFAULT_CURRENCY_CONVERTER_API_MAX_CURRENCY_PAIRS_PER_REQUEST: usize = 2;
+pub const CURRENCY_CONVERTER_API_MAX_DAYS_PER_REQUEST: i64 = 8;
+pub const DEFAULT_CURRENCY_CONVERTER_API_MAX_RETRIES: u32 = 3;
+pub const CURRENCY_CONVERTER_API_INITIAL_BACKOFF_MILLIS: u64 = 1000;
+pub const CURRENCY_CONVERTER_API_MAX_BACKOFF_SECS: u64 = 60;
+/// Start of the error message the Currency Converter API responds with (with a
+/// 400 status) once the free plan's quota is used up.
+pub const CURRENCY_CONVERTER_API_QUOTA_ERROR_PREFIX: &str = "Free API limit reached";
 pub const SAVE_TRANSACTIONS_CHUNK_SIZE: usize = 50;
+pub const DEFAULT_ECB_BASE_URL: &str = "https://www.ecb.europa.eu/stats/eurofxref";
+pub const ECB_DAILY_RATES_FILENAME: &str = "eurofxref-daily.xml";
+pub const ECB_HISTORICAL_RATES_FILENAME: &str = "eurofxref-hist.xml";
+pub const ECB_BASE_CURRENCY: &str = "EUR";
+pub const CURRENCY_CONVERTER_PROVIDER: &str = "currency-converter";
+pub const ECB_PROVIDER: &str = "ecb";
+pub const DEFAULT_EXCHANGE_RATE_PROVIDER: &str = CURRENCY_CONVERTER_PROVIDER;
+pub const DEFAULT_RATE_FALLBACK_DAYS: u32 = 4;
+pub const MISSING_RATE_NEAREST: &str = "nearest";
+pub const MISSING_RATE_FAIL: &str = "fail";
+pub const DEFAULT_MISSING_RATE: &str = MISSING_RATE_NEAREST;
+pub const DEFAULT_MAX_RATE_CHANGE_PERCENT: u32 = 10;
+pub const RATE_CHANGE_REFUSE: &str = "refuse";
+pub const RATE_CHANGE_CONFIRM: &str = "confirm";
+pub const DEFAULT_RATE_CHANGE_ACTION: &str = RATE_CHANGE_REFUSE;
+pub const RATE_DATE_SAME_DAY: &str = "same-day";
+pub const RATE_DATE_PREVIOUS_BUSINESS_DAY: &str = "previous-business-day";
+pub const RATE_DATE_POSTING_DATE: &str = "posting-date";
+pub const DEFAULT_RATE_DATE: &str = RATE_DATE_SAME_DAY;
+pub const MAX_MINOR_UNITS: u32 = 3;
+pub const FOREIGN_AMOUNT_STYLE_LOCAL: &str = "local";
+pub const FOREIGN_AMOUNT_STYLE_NATIVE: &str = "native";
+pub const DEFAULT_FOREIGN_AMOUNT_STYLE: &str = FOREIGN_AMOUNT_STYLE_LOCAL;
+pub const OUTPUT_TEXT: &str = "text";
+pub const OUTPUT_JSON: &str = "json";
+pub const DEFAULT_OUTPUT: &str = OUTPUT_TEXT;
 
 pub const YES_ARG: &str = "yes";
 pub const AUTO_APPROVE_TRANSACTIONS_ARG: &str = "auto-approve-transactions";
@@ -18,6 +52,10 @@ pub const AUTO_APPROVE_ADJUSTMENTS_ARG: &str = "auto-approve-adjustments";
 pub const AUTO_APPROVE_ADJUSTMENTS_ENV: &str = "FCAY_AUTO_APPROVE_ADJUSTMENTS";
 pub const YNAB_ACCESS_TOKEN_ARG: &str = "ynab-access-token";
 pub const YNAB_ACCESS_TOKEN_ENV: &str = "YNAB_ACCESS_TOKEN";
+pub const YNAB_API_BASE_URL_ARG: &str = "ynab-base-url";
+pub const YNAB_API_BASE_URL_ENV: &str = "FCAY_YNAB_BASE_URL";
+pub const EXCHANGE_RATE_PROVIDER_ARG: &str = "exchange-rate-provider";
+pub const EXCHANGE_RATE_PROVIDER_ENV: &str = "FCAY_EXCHANGE_RATE_PROVIDER";
 pub const CURRENCY_CONVERTER_API_KEY_ARG: &str = "currency-converter-api-key";
 pub const CURRENCY_CONVERTER_API_KEY_ENV: &str = "CURRENCY_CONVERTER_API_KEY";
 pub const CURRENCY_CONVERTER_API_BASE_URL_ARG: &str = "currency-converter-base-url";
@@ -26,23 +64,108 @@ pub const CURRENCY_CONVERTER_API_MAX_CURRENCY_PAIRS_PER_REQUEST_ARG: &str =
     "currency-converter-max-currency-pairs-per-request";
 pub const CURRENCY_CONVERTER_API_MAX_CURRENCY_PAIRS_PER_REQUEST_ENV: &str =
     "CURRENCY_CONVERTER_API_MAX_CURRENCY_PAIRS_PER_REQUEST";
+pub const CURRENCY_CONVERTER_API_MAX_RETRIES_ARG: &str = "currency-converter-max-retries";
+pub const CURRENCY_CONVERTER_API_MAX_RETRIES_ENV: &str = "CURRENCY_CONVERTER_API_MAX_RETRIES";
+pub const ECB_BASE_URL_ARG: &str = "ecb-base-url";
+pub const ECB_BASE_URL_ENV: &str = "FCAY_ECB_BASE_URL";
+pub const ECB_RATES_FILE_ARG: &str = "ecb-rates-file";
+pub const ECB_RATES_FILE_ENV: &str = "FCAY_ECB_RATES_FILE";
+pub const FIXED_EXCHANGE_RATES_ARG: &str = "fixed-exchange-rates";
+pub const FIXED_EXCHANGE_RATES_ENV: &str = "FCAY_FIXED_EXCHANGE_RATES";
+pub const PIVOT_CURRENCY_ARG: &str = "pivot-currency";
+pub const PIVOT_CURRENCY_ENV: &str = "FCAY_PIVOT_CURRENCY";
+pub const RATE_FALLBACK_DAYS_ARG: &str = "rate-fallback-days";
+pub const RATE_FALLBACK_DAYS_ENV: &str = "FCAY_RATE_FALLBACK_DAYS";
+pub const MAX_RATE_CHANGE_PERCENT_ARG: &str = "max-rate-change-percent";
+pub const MAX_RATE_CHANGE_PERCENT_ENV: &str = "FCAY_MAX_RATE_CHANGE_PERCENT";
+pub const RATE_CHANGE_ACTION_ARG: &str = "rate-change-action";
+pub const RATE_CHANGE_ACTION_ENV: &str = "FCAY_RATE_CHANGE_ACTION";
+pub const RATE_DATE_ARG: &str = "rate-date";
+pub const RATE_DATE_ENV: &str = "FCAY_RATE_DATE";
+pub const CUSTOM_CURRENCIES_ARG: &str = "custom-currencies";
+pub const CUSTOM_CURRENCIES_ENV: &str = "FCAY_CUSTOM_CURRENCIES";
+pub const FOREIGN_AMOUNT_STYLE_ARG: &str = "foreign-amount-style";
+pub const FOREIGN_AMOUNT_STYLE_ENV: &str = "FCAY_FOREIGN_AMOUNT_STYLE";
+pub const OUTPUT_ARG: &str = "output";
+pub const OUTPUT_ENV: &str = "FCAY_OUTPUT";
+pub const SAVE_PLAN_ARG: &str = "save-plan";
+pub const INTERACTIVE_ARG: &str = "interactive";
+pub const OFFLINE_ARG: &str = "offline";
+pub const MISSING_RATE_ARG: &str = "missing-rate";
+pub const MISSING_RATE_ENV: &str = "FCAY_MISSING_RATE";
 pub const YNAB_BUDGET_ID_ARG: &str = "budget-id";
 pub const YNAB_BUDGET_ID_ENV: &str = "YNAB_BUDGET_ID";
 pub const START_DATE_ARG: &str = "start-date";
+pub const AS_OF_DATE_ARG: &str = "as-of-date";
 pub const DATABASE_FILE_ARG: &str = "database-file";
 pub const DATABASE_FILE_ENV: &str = "FCAY_DATABASE_FILE";
+pub const SYNC_COMMAND: &str = "sync";
+pub const STATUS_COMMAND: &str = "status";
+pub const ACCOUNTS_COMMAND: &str = "accounts";
+pub const HISTORY_COMMAND: &str = "history";
+pub const RESET_COMMAND: &str = "reset";
+pub const FORGET_BUDGET_ARG: &str = "forget-budget";
+pub const DOCTOR_COMMAND: &str = "doctor";
+pub const APPLY_COMMAND: &str = "apply";
+pub const PLAN_FILE_ARG: &str = "PLAN-FILE";
+pub const RATES_COMMAND: &str = "rates";
+pub const RATES_IMPORT_COMMAND: &str = "import";
+pub const RATES_FETCH_COMMAND: &str = "fetch";
+pub const RATES_OVERRIDE_COMMAND: &str = "override";
+pub const RATES_OVERRIDE_SET_COMMAND: &str = "set";
+pub const RATES_OVERRIDE_LIST_COMMAND: &str = "list";
+pub const RATES_OVERRIDE_REMOVE_COMMAND: &str = "remove";
+pub const CSV_FILE_ARG: &str = "CSV-FILE";
+pub const DATE_ARG: &str = "DATE";
+pub const FROM_CURRENCY_ARG: &str = "FROM";
+pub const TO_CURRENCY_ARG: &str = "TO";
+pub const RATE_ARG: &str = "RATE";
+pub const ON_CONFLICT_ARG: &str = "on-conflict";
+pub const FETCH_FROM_DATE_ARG: &str = "from";
+pub const FETCH_TO_DATE_ARG: &str = "to";
+pub const CURRENCIES_ARG: &str = "currencies";
+pub const BUDGET_CURRENCY_ARG: &str = "budget-currency";
+pub const ON_CONFLICT_SKIP: &str = "skip";
+pub const ON_CONFLICT_OVERWRITE: &str = "overwrite";
+pub const ON_CONFLICT_FAIL: &str = "fail";
 pub const POSSIBLE_BOOL_VALUES: [&str; 2] = ["true", "false"];
+pub const POSSIBLE_ON_CONFLICT_VALUES: [&str; 3] =
+    [ON_CONFLICT_SKIP, ON_CONFLICT_OVERWRITE, ON_CONFLICT_FAIL];
+pub const POSSIBLE_RATE_CHANGE_ACTION_VALUES: [&str; 2] = [RATE_CHANGE_REFUSE, RATE_CHANGE_CONFIRM];
+pub const POSSIBLE_RATE_DATE_VALUES: [&str; 3] = [
+    RATE_DATE_SAME_DAY,
+    RATE_DATE_PREVIOUS_BUSINESS_DAY,
+    RATE_DATE_POSTING_DATE,
+];
+pub const POSSIBLE_FOREIGN_AMOUNT_STYLE_VALUES: [&str; 2] =
+    [FOREIGN_AMOUNT_STYLE_LOCAL, FOREIGN_AMOUNT_STYLE_NATIVE];
+pub const POSSIBLE_OUTPUT_VALUES: [&str; 2] = [OUTPUT_TEXT, OUTPUT_JSON];
+pub const POSSIBLE_MISSING_RATE_VALUES: [&str; 2] = [MISSING_RATE_NEAREST, MISSING_RATE_FAIL];
+pub const POSSIBLE_EXCHANGE_RATE_PROVIDER_VALUES: [&str; 2] =
+    [CURRENCY_CONVERTER_PROVIDER, ECB_PROVIDER];
 
 lazy_static! {
     pub static ref FORCE_CONVERT_REGEX: Regex =
         Regex::new(r"(?i)<CONVERT>").expect("FORCE_CONVERT_REGEX should be valid");
     pub static ref FORCE_NO_CONVERT_REGEX: Regex =
         Regex::new(r"(?i)<NO[\s-]*CONVERT>").expect("FORCE_NO_CONVERT_REGEX should be valid");
+    pub static ref POSTING_DATE_REGEX: Regex =
+        Regex::new(r"(?i)<POSTED:?\s*([0-9]{4}-[0-9]{2}-[0-9]{2})>")
+            .expect("POSTING_DATE_REGEX should be valid");
+    pub static ref ACCOUNT_RATE_DATE_REGEX: Regex =
+        Regex::new(r"(?i)<RATE[\s-]+DATE:?\s*([[:alpha:]-]+)>")
+            .expect("ACCOUNT_RATE_DATE_REGEX should be valid");
+    pub static ref ACCOUNT_FEE_REGEX: Regex = Regex::new(r"(?i)<FEE\s+([0-9.%+\s]+?)\s*>")
+        .expect("ACCOUNT_FEE_REGEX should be valid");
     pub static ref ACCOUNT_CURRENCY_REGEX: Regex =
-        Regex::new(r"(?i)<([[:alpha:]]{3})>").expect("ACCOUNT_CURRENCY_REGEX should be valid");
+        Regex::new(r"(?i)<([A-Z][A-Z0-9]{2,5})>").expect("ACCOUNT_CURRENCY_REGEX should be valid");
     pub static ref DIFFERENCE_ACCOUNT_CURRENCY_REGEX: Regex =
-        Regex::new(r"(?i)<([[:alpha:]]{3})[\s-]+DIFFERENCE>")
+        Regex::new(r"(?i)<([A-Z][A-Z0-9]{2,5})[\s-]+DIFFERENCE>")
             .expect("DIFFERENCE_ACCOUNT_CURRENCY_REGEX should be valid");
+    pub static ref ECB_CUBE_REGEX: Regex = Regex::new(
+        r#"<Cube\s+time=['"]([0-9]{4}-[0-9]{2}-[0-9]{2})['"]|<Cube\s+currency=['"]([[:alpha:]]{3})['"]\s+rate=['"]([0-9.]+)['"]"#
+    )
+    .expect("ECB_CUBE_REGEX should be valid");
 }
 
 pub fn format_adjustment_payee_name(key: DifferenceKey) -> String {
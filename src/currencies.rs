@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+
+use crate::constants::*;
+use crate::errors::*;
+use crate::types::*;
+
+/// ISO 4217 currency codes and their number of minor unit digits.  Precious
+/// metals and other units without minor units use the most digits YNAB can
+/// store, so that they are never rounded.
+const ISO_4217_CURRENCIES: [(&str, u32); 171] = [
+    ("AED", 2),
+    ("AFN", 2),
+    ("ALL", 2),
+    ("AMD", 2),
+    ("ANG", 2),
+    ("AOA", 2),
+    ("ARS", 2),
+    ("AUD", 2),
+    ("AWG", 2),
+    ("AZN", 2),
+    ("BAM", 2),
+    ("BBD", 2),
+    ("BDT", 2),
+    ("BGN", 2),
+    ("BHD", 3),
+    ("BIF", 0),
+    ("BMD", 2),
+    ("BND", 2),
+    ("BOB", 2),
+    ("BOV", 2),
+    ("BRL", 2),
+    ("BSD", 2),
+    ("BTN", 2),
+    ("BWP", 2),
+    ("BYN", 2),
+    ("BZD", 2),
+    ("CAD", 2),
+    ("CDF", 2),
+    ("CHE", 2),
+    ("CHF", 2),
+    ("CHW", 2),
+    ("CLF", 4),
+    ("CLP", 0),
+    ("CNY", 2),
+    ("COP", 2),
+    ("COU", 2),
+    ("CRC", 2),
+    ("CUC", 2),
+    ("CUP", 2),
+    ("CVE", 2),
+    ("CZK", 2),
+    ("DJF", 0),
+    ("DKK", 2),
+    ("DOP", 2),
+    ("DZD", 2),
+    ("EGP", 2),
+    ("ERN", 2),
+    ("ETB", 2),
+    ("EUR", 2),
+    ("FJD", 2),
+    ("FKP", 2),
+    ("GBP", 2),
+    ("GEL", 2),
+    ("GHS", 2),
+    ("GIP", 2),
+    ("GMD", 2),
+    ("GNF", 0),
+    ("GTQ", 2),
+    ("GYD", 2),
+    ("HKD", 2),
+    ("HNL", 2),
+    ("HRK", 2),
+    ("HTG", 2),
+    ("HUF", 2),
+    ("IDR", 2),
+    ("ILS", 2),
+    ("INR", 2),
+    ("IQD", 3),
+    ("IRR", 2),
+    ("ISK", 0),
+    ("JMD", 2),
+    ("JOD", 3),
+    ("JPY", 0),
+    ("KES", 2),
+    ("KGS", 2),
+    ("KHR", 2),
+    ("KMF", 0),
+    ("KPW", 2),
+    ("KRW", 0),
+    ("KWD", 3),
+    ("KYD", 2),
+    ("KZT", 2),
+    ("LAK", 2),
+    ("LBP", 2),
+    ("LKR", 2),
+    ("LRD", 2),
+    ("LSL", 2),
+    ("LYD", 3),
+    ("MAD", 2),
+    ("MDL", 2),
+    ("MGA", 2),
+    ("MKD", 2),
+    ("MMK", 2),
+    ("MNT", 2),
+    ("MOP", 2),
+    ("MRU", 2),
+    ("MUR", 2),
+    ("MVR", 2),
+    ("MWK", 2),
+    ("MXN", 2),
+    ("MXV", 2),
+    ("MYR", 2),
+    ("MZN", 2),
+    ("NAD", 2),
+    ("NGN", 2),
+    ("NIO", 2),
+    ("NOK", 2),
+    ("NPR", 2),
+    ("NZD", 2),
+    ("OMR", 3),
+    ("PAB", 2),
+    ("PEN", 2),
+    ("PGK", 2),
+    ("PHP", 2),
+    ("PKR", 2),
+    ("PLN", 2),
+    ("PYG", 0),
+    ("QAR", 2),
+    ("RON", 2),
+    ("RSD", 2),
+    ("RUB", 2),
+    ("RWF", 0),
+    ("SAR", 2),
+    ("SBD", 2),
+    ("SCR", 2),
+    ("SDG", 2),
+    ("SEK", 2),
+    ("SGD", 2),
+    ("SHP", 2),
+    ("SLL", 2),
+    ("SOS", 2),
+    ("SRD", 2),
+    ("SSP", 2),
+    ("STN", 2),
+    ("SVC", 2),
+    ("SYP", 2),
+    ("SZL", 2),
+    ("THB", 2),
+    ("TJS", 2),
+    ("TMT", 2),
+    ("TND", 3),
+    ("TOP", 2),
+    ("TRY", 2),
+    ("TTD", 2),
+    ("TWD", 2),
+    ("TZS", 2),
+    ("UAH", 2),
+    ("UGX", 0),
+    ("USD", 2),
+    ("USN", 2),
+    ("UYI", 0),
+    ("UYU", 2),
+    ("UYW", 4),
+    ("UZS", 2),
+    ("VES", 2),
+    ("VND", 0),
+    ("VUV", 0),
+    ("WST", 2),
+    ("XAF", 0),
+    ("XAG", 3),
+    ("XAU", 3),
+    ("XCD", 2),
+    ("XDR", 3),
+    ("XOF", 0),
+    ("XPD", 3),
+    ("XPF", 0),
+    ("XPT", 3),
+    ("YER", 2),
+    ("ZAR", 2),
+    ("ZMW", 2),
+    ("ZWL", 2),
+];
+
+/// How amounts are conventionally written in some common currencies: code,
+/// symbol, whether the symbol comes first, spacer between symbol and number,
+/// decimal separator, and group separator.
+#[allow(clippy::type_complexity)]
+const NATIVE_CURRENCY_FORMATS: [(&str, &str, bool, &str, &str, &str); 31] = [
+    ("AUD", "A$", true, "", ".", ","),
+    ("BRL", "R$", true, " ", ",", "."),
+    ("CAD", "CA$", true, "", ".", ","),
+    ("CHF", "CHF", true, " ", ".", "'"),
+    ("CNY", "CN¥", true, "", ".", ","),
+    ("CZK", "Kč", false, " ", ",", " "),
+    ("DKK", "kr.", false, " ", ",", "."),
+    ("EUR", "€", true, "", ",", "."),
+    ("GBP", "£", true, "", ".", ","),
+    ("HKD", "HK$", true, "", ".", ","),
+    ("HUF", "Ft", false, " ", ",", " "),
+    ("IDR", "Rp", true, "", ",", "."),
+    ("ILS", "₪", true, "", ".", ","),
+    ("INR", "₹", true, "", ".", ","),
+    ("ISK", "kr", false, " ", ",", "."),
+    ("JPY", "¥", true, "", ".", ","),
+    ("KRW", "₩", true, "", ".", ","),
+    ("MXN", "MX$", true, "", ".", ","),
+    ("NOK", "kr", true, " ", ",", " "),
+    ("NZD", "NZ$", true, "", ".", ","),
+    ("PHP", "₱", true, "", ".", ","),
+    ("PLN", "zł", false, " ", ",", " "),
+    ("RUB", "₽", false, " ", ",", " "),
+    ("SEK", "kr", false, " ", ",", " "),
+    ("SGD", "S$", true, "", ".", ","),
+    ("THB", "฿", true, "", ".", ","),
+    ("TRY", "₺", true, "", ",", "."),
+    ("UAH", "₴", false, " ", ",", " "),
+    ("USD", "$", true, "", ".", ","),
+    ("VND", "₫", false, " ", ",", "."),
+    ("ZAR", "R", true, " ", ",", " "),
+];
+
+/// Known currencies: the ISO 4217 currencies, plus any custom currencies
+/// (e.g. crypto currencies) given on the command line.
+#[derive(Debug)]
+pub struct CurrencyRegistry {
+    minor_units: HashMap<CurrencyCode, u32>,
+    native_formats: HashMap<CurrencyCode, NativeCurrencyFormat>,
+}
+
+/// How amounts in a currency are conventionally written (e.g. `€1.234,56`).
+#[derive(Debug)]
+pub struct NativeCurrencyFormat {
+    pub symbol: &'static str,
+    pub symbol_first: bool,
+    pub symbol_spacer: &'static str,
+    pub decimal_separator: &'static str,
+    pub group_separator: &'static str,
+}
+
+impl CurrencyRegistry {
+    pub fn new(custom_currencies: &[(CurrencyCode, u32)]) -> CurrencyRegistry {
+        let mut minor_units: HashMap<CurrencyCode, u32> = ISO_4217_CURRENCIES
+            .iter()
+            .map(|&(code, digits)| {
+                (
+                    CurrencyCode::from_str(code)
+                        .expect("ISO_4217_CURRENCIES should have valid currency codes"),
+                    digits,
+                )
+            })
+            .collect();
+        minor_units.extend(custom_currencies.iter().cloned());
+        let native_formats = NATIVE_CURRENCY_FORMATS
+            .iter()
+            .map(
+                |&(
+                    code,
+                    symbol,
+                    symbol_first,
+                    symbol_spacer,
+                    decimal_separator,
+                    group_separator,
+                )| {
+                    (
+                        CurrencyCode::from_str(code)
+                            .expect("NATIVE_CURRENCY_FORMATS should have valid currency codes"),
+                        NativeCurrencyFormat {
+                            symbol,
+                            symbol_first,
+                            symbol_spacer,
+                            decimal_separator,
+                            group_separator,
+                        },
+                    )
+                },
+            )
+            .collect();
+        CurrencyRegistry {
+            minor_units,
+            native_formats,
+        }
+    }
+
+    pub fn contains(&self, currency: CurrencyCode) -> bool {
+        self.minor_units.contains_key(&currency)
+    }
+
+    /// Number of decimal digits that amounts in the currency are rounded to,
+    /// which is never more than YNAB's milliunits can hold.
+    pub fn minor_units(&self, currency: CurrencyCode) -> Option<u32> {
+        self.minor_units
+            .get(&currency)
+            .map(|&digits| digits.min(MAX_MINOR_UNITS))
+    }
+
+    pub fn native_format(&self, currency: CurrencyCode) -> Option<&NativeCurrencyFormat> {
+        self.native_formats.get(&currency)
+    }
+}
+
+/// Parse custom currencies formatted `CODE:DIGITS,...`, where `DIGITS` is the
+/// number of minor unit digits.  The digits are required, since assets such as
+/// crypto currencies often have more than the usual two.
+pub fn parse_custom_currencies(value: &str) -> Result<Vec<(CurrencyCode, u32)>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|currency| !currency.is_empty())
+        .map(|currency| {
+            let mut code_and_digits = currency.splitn(2, ':');
+            let code = CurrencyCode::from_str(code_and_digits.next().unwrap_or_default().trim())?;
+            let digits = code_and_digits
+                .next()
+                .chain_err(|| {
+                    format!(
+                        "Missing minor unit digits (e.g. {}:2): {}",
+                        code.to_str(),
+                        currency
+                    )
+                })?
+                .trim()
+                .parse()
+                .chain_err(|| format!("Invalid minor unit digits: {}", currency))?;
+            Ok((code, digits))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_currency_registry() {
+        let registry = CurrencyRegistry::new(&[(code("USDT"), 6), (code("ABC"), 1)]);
+        assert_eq!(registry.minor_units(code("EUR")), Some(2));
+        assert_eq!(registry.minor_units(code("JPY")), Some(0));
+        assert_eq!(registry.minor_units(code("BHD")), Some(3));
+        assert_eq!(registry.minor_units(code("CLF")), Some(3));
+        assert_eq!(registry.minor_units(code("USDT")), Some(3));
+        assert_eq!(registry.minor_units(code("ABC")), Some(1));
+        assert!(registry.contains(code("XOF")));
+        assert!(!registry.contains(code("XYZ")));
+        assert!(!CurrencyRegistry::new(&[]).contains(code("USDT")));
+    }
+
+    #[test]
+    fn test_parse_custom_currencies() {
+        assert_eq!(
+            parse_custom_currencies("usdt:6, DOGE:8").unwrap(),
+            vec![(code("USDT"), 6), (code("DOGE"), 8)]
+        );
+        assert_eq!(parse_custom_currencies("").unwrap(), vec![]);
+        assert!(parse_custom_currencies("USDT").is_err());
+        assert!(parse_custom_currencies("USDT:x").is_err());
+        assert!(parse_custom_currencies("U").is_err());
+    }
+}
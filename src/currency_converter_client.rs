@@ -1,8 +1,13 @@
 use chrono::NaiveDate;
-use log::debug;
+use log::{debug, warn};
+use serde_json::value::RawValue;
 use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use std::{cmp, thread};
 
+use crate::constants::*;
 use crate::errors::*;
+use crate::exchange_rate_provider::*;
 use crate::types::*;
 use crate::utilities::*;
 
@@ -11,25 +16,106 @@ pub struct CurrencyConverterClient<'a> {
     api_key: &'a str,
     base_url: &'a str,
     max_pairs_per_request: usize,
+    retry_policy: RetryPolicy,
+}
+
+/// How to retry requests that fail for possibly transient reasons (connection
+/// errors, server errors and rate limiting).
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Delay before the first retry, which doubles for each further retry.
+    pub initial_backoff: Duration,
+    /// Longest delay to wait before a retry.  If the API asks to wait longer
+    /// than this, its quota is treated as exhausted.
+    pub max_backoff: Duration,
 }
 
 impl<'a> CurrencyConverterClient<'a> {
-    pub fn new(api_key: &'a str, base_url: &'a str, max_pairs_per_request: usize) -> Self {
+    pub fn new(
+        api_key: &'a str,
+        base_url: &'a str,
+        max_pairs_per_request: usize,
+        retry_policy: RetryPolicy,
+    ) -> Self {
         CurrencyConverterClient {
             api_key,
             base_url,
             max_pairs_per_request,
+            retry_policy,
+        }
+    }
+
+    /// Get the body of a successful response, retrying with exponential
+    /// backoff if the request fails for a possibly transient reason.
+    fn get_response_body(&self, url: &str) -> Result<String> {
+        let mut backoff = self.retry_policy.initial_backoff;
+        let mut retry_count = 0;
+        loop {
+            let retry_error = match reqwest::get(url) {
+                Err(err) => Error::with_chain(err, "Failed to get response"),
+                Ok(mut response) => {
+                    let status = response.status();
+                    if status.is_success() {
+                        return response.text().chain_err(|| "Failed to read response");
+                    }
+                    let retry_after = response
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.trim().parse::<u64>().ok())
+                        .map(Duration::from_secs);
+                    let message = response
+                        .text()
+                        .ok()
+                        .and_then(|body| response_error_message(&body))
+                        .unwrap_or_else(|| status.to_string());
+                    if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                        let quota_exhausted =
+                            ErrorKind::QuotaExhausted(self.name().to_string(), message);
+                        match retry_after {
+                            Some(retry_after) if retry_after > self.retry_policy.max_backoff => {
+                                bail!(quota_exhausted)
+                            }
+                            Some(retry_after) => backoff = cmp::max(backoff, retry_after),
+                            None => (),
+                        }
+                        Error::from(quota_exhausted)
+                    } else if status.is_client_error() {
+                        if status == reqwest::StatusCode::BAD_REQUEST
+                            && message.starts_with(CURRENCY_CONVERTER_API_QUOTA_ERROR_PREFIX)
+                        {
+                            bail!(ErrorKind::QuotaExhausted(self.name().to_string(), message));
+                        }
+                        bail!(message);
+                    } else {
+                        Error::from(format!("{} response: {}", status, message))
+                    }
+                }
+            };
+            if retry_count >= self.retry_policy.max_retries {
+                return Err(retry_error);
+            }
+            retry_count += 1;
+            warn!(
+                "{}; retrying in {:?} (retry {} of {})",
+                retry_error, backoff, retry_count, self.retry_policy.max_retries
+            );
+            thread::sleep(backoff);
+            backoff = cmp::min(backoff * 2, self.retry_policy.max_backoff);
         }
     }
 
-    pub fn get_date_exchange_rates(
+    /// Get exchange rates for the dates from `start_date` to `end_date`
+    /// (inclusive, and at most `CURRENCY_CONVERTER_API_MAX_DAYS_PER_REQUEST`
+    /// days), making one request per chunk of currency pairs.
+    fn get_exchange_rates(
         &self,
-        date: NaiveDate,
+        start_date: NaiveDate,
+        end_date: NaiveDate,
         from_to_currency_pairs: &HashSet<(CurrencyCode, CurrencyCode)>,
-    ) -> Result<HashMap<(CurrencyCode, CurrencyCode), ExchangeRate>> {
-        let date_iso = format_iso_date(date);
-        println!("  Getting exchange rates from API for {}...", date);
-        let mut results = HashMap::new();
+    ) -> Result<HashMap<NaiveDate, CurrencyPairExchangeRates>> {
+        let mut results: HashMap<NaiveDate, CurrencyPairExchangeRates> = HashMap::new();
         for currency_pairs_chunk in from_to_currency_pairs
             .iter()
             .cloned()
@@ -37,54 +123,233 @@ impl<'a> CurrencyConverterClient<'a> {
             .chunks(self.max_pairs_per_request)
         {
             let currency_api_url = format!(
-                "{}/api/v7/convert?q={}&compact=ultra&date={}&apiKey={}",
+                "{}/api/v7/convert?q={}&compact=ultra&date={}{}&apiKey={}",
                 self.base_url,
                 currency_pairs_chunk
                     .iter()
                     .map(|(from, to)| format!("{}_{}", from, to))
                     .collect::<Vec<_>>()
                     .join(","),
-                date_iso,
+                format_iso_date(start_date),
+                if end_date > start_date {
+                    format!("&endDate={}", format_iso_date(end_date))
+                } else {
+                    "".to_string()
+                },
                 self.api_key
             );
             debug!(
                 "Currency converter API historical exchange rates URL: {}",
                 currency_api_url
             );
-            let mut response =
-                reqwest::get(&currency_api_url).chain_err(|| "Failed to get response")?;
-            if response.status().is_client_error() {
-                return Err(Error::from(
-                    response
-                        .json::<HashMap<String, serde_json::Value>>()
-                        .chain_err(|| "Failed to parse response")?
-                        .get("error")
-                        .chain_err(|| {
-                            format!("{} response missing error field", response.status())
-                        })?
-                        .to_string(),
-                ));
-            } else {
-                let results_chunk: HashMap<(CurrencyCode, CurrencyCode), ExchangeRate> = response
-                    .error_for_status()
-                    .chain_err(|| "Error response")?
-                    .json::<HashMap<String, HashMap<String, f64>>>()
-                    .chain_err(|| "Failed to parse response")?
-                    .into_iter()
-                    .map(|(code_pair, rate_map)| {
-                        let &rate = rate_map
-                            .get(&date_iso)
-                            .chain_err(|| "Requested date missing from response")?;
-                        let from = CurrencyCode::from_str(&code_pair[0..3])
-                            .chain_err(|| "Invalid \"from\" currency in response response")?;
-                        let to = CurrencyCode::from_str(&code_pair[4..7])
-                            .chain_err(|| "Invalid \"to\" currency in response response")?;
-                        Ok(((from, to), ExchangeRate::from_f64(rate)))
-                    })
-                    .collect::<Result<_>>()?;
-                results.extend(results_chunk);
+            let body = self.get_response_body(&currency_api_url)?;
+            // Keep the rates as raw JSON numbers, since deserializing them to
+            // `f64` loses precision.
+            let response_rates =
+                serde_json::from_str::<HashMap<String, HashMap<String, Box<RawValue>>>>(&body)
+                    .chain_err(|| "Failed to parse response")?;
+            for (code_pair, rate_map) in response_rates {
+                let mut codes = code_pair.splitn(2, '_');
+                let from = CurrencyCode::from_str(codes.next().unwrap_or_default())
+                    .chain_err(|| "Invalid \"from\" currency in response response")?;
+                let to = CurrencyCode::from_str(codes.next().unwrap_or_default())
+                    .chain_err(|| "Invalid \"to\" currency in response response")?;
+                // No rate is published for some dates (e.g. weekends), so
+                // those are simply missing from the response.
+                for (date_iso, rate) in rate_map {
+                    let date = parse_iso_date(&date_iso)
+                        .chain_err(|| "Invalid date in response response")?;
+                    let rate = ExchangeRate::from_str(rate.get())
+                        .chain_err(|| "Invalid exchange rate in response")?;
+                    results.entry(date).or_default().insert((from, to), rate);
+                }
             }
         }
         Ok(results)
     }
 }
+
+impl<'a> ExchangeRateProvider for CurrencyConverterClient<'a> {
+    fn name(&self) -> &str {
+        "Currency Converter API"
+    }
+
+    fn get_date_exchange_rates(
+        &self,
+        date: NaiveDate,
+        from_to_currency_pairs: &HashSet<(CurrencyCode, CurrencyCode)>,
+    ) -> Result<HashMap<(CurrencyCode, CurrencyCode), ExchangeRate>> {
+        progress!("  Getting exchange rates from API for {}...", date);
+        Ok(self
+            .get_exchange_rates(date, date, from_to_currency_pairs)?
+            .remove(&date)
+            .unwrap_or_default())
+    }
+
+    fn get_date_range_exchange_rates(
+        &self,
+        start_date: NaiveDate,
+        end_date: NaiveDate,
+        from_to_currency_pairs: &HashSet<(CurrencyCode, CurrencyCode)>,
+        on_date_rates: &mut dyn FnMut(NaiveDate, CurrencyPairExchangeRates) -> Result<()>,
+    ) -> Result<()> {
+        let mut chunk_start_date = start_date;
+        while chunk_start_date <= end_date {
+            let chunk_end_date = cmp::min(
+                end_date,
+                chunk_start_date
+                    + chrono::Duration::days(CURRENCY_CONVERTER_API_MAX_DAYS_PER_REQUEST - 1),
+            );
+            progress!(
+                "  Getting exchange rates from API for {} to {}...",
+                chunk_start_date,
+                chunk_end_date
+            );
+            let mut chunk_results =
+                self.get_exchange_rates(chunk_start_date, chunk_end_date, from_to_currency_pairs)?;
+            let mut date = chunk_start_date;
+            while date <= chunk_end_date {
+                on_date_rates(date, chunk_results.remove(&date).unwrap_or_default())?;
+                date = date.succ();
+            }
+            chunk_start_date = chunk_end_date.succ();
+        }
+        Ok(())
+    }
+}
+
+/// Get the `error` field of an error response.
+fn response_error_message(body: &str) -> Option<String> {
+    let error = serde_json::from_str::<HashMap<String, serde_json::Value>>(body)
+        .ok()?
+        .remove("error")?;
+    Some(match error {
+        serde_json::Value::String(message) => message,
+        other => other.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    const TEST_RETRY_POLICY: RetryPolicy = RetryPolicy {
+        max_retries: 2,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_secs(1),
+    };
+
+    fn gbp_eur_pairs() -> HashSet<(CurrencyCode, CurrencyCode)> {
+        [(code("GBP"), code("EUR"))].iter().cloned().collect()
+    }
+
+    #[test]
+    fn test_retries_server_error() {
+        let (base_url, server) = serve_http_responses(vec![
+            http_response("503 Service Unavailable", "{}"),
+            http_response("200 OK", r#"{"GBP_EUR":{"2020-01-02":1.123456789012}}"#),
+        ]);
+        let client = CurrencyConverterClient::new("KEY", &base_url, 2, TEST_RETRY_POLICY);
+        let rates = client
+            .get_date_exchange_rates(NaiveDate::from_ymd(2020, 1, 2), &gbp_eur_pairs())
+            .unwrap();
+        assert_eq!(rates[&(code("GBP"), code("EUR"))], rate("1.123456789012"));
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_quota_exhausted() {
+        let (base_url, server) = serve_http_responses(vec![http_response(
+            "400 Bad Request",
+            r#"{"status":400,"error":"Free API limit reached. Please upgrade."}"#,
+        )]);
+        let client = CurrencyConverterClient::new("KEY", &base_url, 2, TEST_RETRY_POLICY);
+        let err = client
+            .get_date_exchange_rates(NaiveDate::from_ymd(2020, 1, 2), &gbp_eur_pairs())
+            .unwrap_err();
+        assert!(is_quota_exhausted(&err));
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_rate_limited() {
+        let rate_limited =
+            http_response("429 Too Many Requests", r#"{"error":"Too many requests"}"#);
+        let (base_url, server) = serve_http_responses(vec![rate_limited.clone(); 3]);
+        let client = CurrencyConverterClient::new("KEY", &base_url, 2, TEST_RETRY_POLICY);
+        let err = client
+            .get_date_exchange_rates(NaiveDate::from_ymd(2020, 1, 2), &gbp_eur_pairs())
+            .unwrap_err();
+        assert!(is_quota_exhausted(&err));
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_other_client_error() {
+        let (base_url, server) = serve_http_responses(vec![
+            http_response(
+                "400 Bad Request",
+                r#"{"status":400,"error":"Invalid query"}"#,
+            ),
+            http_response(
+                "400 Bad Request",
+                r#"{"status":400,"error":"Invalid limit parameter"}"#,
+            ),
+        ]);
+        let client = CurrencyConverterClient::new("KEY", &base_url, 2, TEST_RETRY_POLICY);
+        let err = client
+            .get_date_exchange_rates(NaiveDate::from_ymd(2020, 1, 2), &gbp_eur_pairs())
+            .unwrap_err();
+        assert!(!is_quota_exhausted(&err));
+        assert_eq!(err.to_string(), "Invalid query");
+        let err = client
+            .get_date_exchange_rates(NaiveDate::from_ymd(2020, 1, 2), &gbp_eur_pairs())
+            .unwrap_err();
+        assert!(!is_quota_exhausted(&err));
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_is_quota_exhausted_chained() {
+        let err: Result<()> =
+            Err(ErrorKind::QuotaExhausted("Test".to_string(), "Used up".to_string()).into());
+        let err = err
+            .chain_err(|| "Failed to get exchange rate")
+            .chain_err(|| "Failed to process transaction")
+            .unwrap_err();
+        assert!(is_quota_exhausted(&err));
+        assert!(!is_quota_exhausted(&Error::from("Rate limit")));
+    }
+
+    #[test]
+    fn test_date_range() {
+        let (base_url, server) = serve_http_responses(vec![http_response(
+            "200 OK",
+            r#"{"GBP_EUR":{"2020-01-02":1.15,"2020-01-03":1.16}}"#,
+        )]);
+        let client = CurrencyConverterClient::new("KEY", &base_url, 2, TEST_RETRY_POLICY);
+        let mut date_rates = Vec::new();
+        client
+            .get_date_range_exchange_rates(
+                NaiveDate::from_ymd(2020, 1, 2),
+                NaiveDate::from_ymd(2020, 1, 4),
+                &gbp_eur_pairs(),
+                &mut |date, rates| {
+                    date_rates.push((date, rates.len()));
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            date_rates,
+            vec![
+                (NaiveDate::from_ymd(2020, 1, 2), 1),
+                (NaiveDate::from_ymd(2020, 1, 3), 1),
+                (NaiveDate::from_ymd(2020, 1, 4), 0),
+            ]
+        );
+        let request_paths = server.join().unwrap();
+        assert!(request_paths[0].contains("&date=2020-01-02&endDate=2020-01-04&"));
+    }
+}
@@ -16,6 +16,7 @@ embed_migrations!("migrations");
 pub struct Database {
     connection: SqliteConnection,
     dry_run: bool,
+    read_only: bool,
 }
 
 pub struct BudgetDatabase<'a> {
@@ -23,9 +24,21 @@ pub struct BudgetDatabase<'a> {
     run_state: BudgetRunState,
 }
 
+type DifferenceTransactionRow = (
+    String,
+    i64,
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<i32>,
+    i64,
+);
+
 #[derive(Debug)]
 enum BudgetRunState {
     DryRun(Option<i32>),
+    ReadOnly(Option<i32>),
     Live(i32),
 }
 
@@ -47,25 +60,38 @@ impl Database {
         Ok(Database {
             connection,
             dry_run,
+            read_only: false,
         })
     }
 
+    /// Make the database read-only for budgets: like a dry run, except that
+    /// a run without changes does not save the budget's server knowledge and
+    /// last run date either, so a later run still fetches the same changes.
+    pub fn read_only(self) -> Database {
+        Database {
+            dry_run: true,
+            read_only: true,
+            ..self
+        }
+    }
+
     pub fn get_exchange_rate(
         &self,
         from_currency: CurrencyCode,
         to_currency: CurrencyCode,
         date_: NaiveDate,
-    ) -> Result<Option<ExchangeRate>> {
+    ) -> Result<Option<ExchangeRateInfo>> {
         use schema::exchange_rates::dsl::*;
         schema::exchange_rates::table
-            .select(exchange_rate)
+            .select((exchange_rate, pivot_currency_code, fallback_date))
             .filter(from_currency_code.eq(from_currency.to_str()))
             .filter(to_currency_code.eq(to_currency.to_str()))
             .filter(date.eq(date_.num_days_from_ce()))
-            .first::<i64>(&self.connection)
+            .first::<(String, Option<String>, Option<i32>)>(&self.connection)
             .optional()
-            .chain_err(|| "Failed to load exchange rate from database")
-            .map(|res| res.map(ExchangeRate::from_scaled_i64))
+            .chain_err(|| "Failed to load exchange rate from database")?
+            .map(exchange_rate_info_from_row)
+            .transpose()
     }
 
     pub fn get_known_exchange_rates(
@@ -73,29 +99,132 @@ impl Database {
         from_currencies: &HashSet<CurrencyCode>,
         to_currency: CurrencyCode,
         date_: NaiveDate,
-    ) -> Result<HashMap<CurrencyCode, ExchangeRate>> {
+    ) -> Result<HashMap<CurrencyCode, ExchangeRateInfo>> {
         use schema::exchange_rates::dsl::*;
         schema::exchange_rates::table
-            .select((from_currency_code, exchange_rate))
+            .select((
+                from_currency_code,
+                (exchange_rate, pivot_currency_code, fallback_date),
+            ))
             .filter(from_currency_code.eq_any(from_currencies.iter().map(|cur| cur.to_str())))
             .filter(to_currency_code.eq(to_currency.to_str()))
             .filter(date.eq(date_.num_days_from_ce()))
-            .load::<(String, i64)>(&self.connection)
+            .load::<(String, (String, Option<String>, Option<i32>))>(&self.connection)
             .chain_err(|| "Failed to load exchange rates from database")?
             .into_iter()
-            .map(|(currency, rate)| {
-                CurrencyCode::from_str(&currency)
-                    .map(|code| (code, ExchangeRate::from_scaled_i64(rate)))
+            .map(|(currency, row)| {
+                Ok((
+                    CurrencyCode::from_str(&currency)?,
+                    exchange_rate_info_from_row(row)?,
+                ))
             })
             .collect::<Result<_>>()
     }
 
+    /// Get the known exchange rate closest to a date (preferring the earlier
+    /// one if two are equally close), along with the date it is for.
+    pub fn get_nearest_exchange_rate(
+        &self,
+        from_currency: CurrencyCode,
+        to_currency: CurrencyCode,
+        date_: NaiveDate,
+    ) -> Result<Option<(NaiveDate, ExchangeRateInfo)>> {
+        use schema::exchange_rates::dsl::*;
+        let query = || {
+            schema::exchange_rates::table
+                .select((date, (exchange_rate, pivot_currency_code, fallback_date)))
+                .filter(from_currency_code.eq(from_currency.to_str()))
+                .filter(to_currency_code.eq(to_currency.to_str()))
+        };
+        let days_from_ce = date_.num_days_from_ce();
+        let on_or_before = query()
+            .filter(date.le(days_from_ce))
+            .order(date.desc())
+            .first::<(i32, (String, Option<String>, Option<i32>))>(&self.connection)
+            .optional()
+            .chain_err(|| "Failed to load exchange rate from database")?;
+        let after = query()
+            .filter(date.gt(days_from_ce))
+            .order(date.asc())
+            .first::<(i32, (String, Option<String>, Option<i32>))>(&self.connection)
+            .optional()
+            .chain_err(|| "Failed to load exchange rate from database")?;
+        let nearest = match (on_or_before, after) {
+            (Some(before_row), Some(after_row)) => {
+                if days_from_ce - before_row.0 <= after_row.0 - days_from_ce {
+                    Some(before_row)
+                } else {
+                    Some(after_row)
+                }
+            }
+            (before_row, after_row) => before_row.or(after_row),
+        };
+        nearest
+            .map(|(days, row)| {
+                Ok((
+                    NaiveDate::from_num_days_from_ce(days),
+                    exchange_rate_info_from_row(row)?,
+                ))
+            })
+            .transpose()
+    }
+
+    /// Get the latest rate published (rather than fallen back to) for a date
+    /// before the given one, along with the date it is for.
+    pub fn get_previous_published_exchange_rate(
+        &self,
+        from_currency: CurrencyCode,
+        to_currency: CurrencyCode,
+        date_: NaiveDate,
+    ) -> Result<Option<(NaiveDate, ExchangeRateInfo)>> {
+        use schema::exchange_rates::dsl::*;
+        schema::exchange_rates::table
+            .select((date, (exchange_rate, pivot_currency_code, fallback_date)))
+            .filter(from_currency_code.eq(from_currency.to_str()))
+            .filter(to_currency_code.eq(to_currency.to_str()))
+            .filter(date.lt(date_.num_days_from_ce()))
+            .filter(fallback_date.is_null())
+            .order(date.desc())
+            .first::<(i32, (String, Option<String>, Option<i32>))>(&self.connection)
+            .optional()
+            .chain_err(|| "Failed to load exchange rate from database")?
+            .map(|(days, row)| {
+                Ok((
+                    NaiveDate::from_num_days_from_ce(days),
+                    exchange_rate_info_from_row(row)?,
+                ))
+            })
+            .transpose()
+    }
+
+    /// Whether a rate published (rather than fallen back to) for a date after
+    /// the given one is known, in which case the date itself will never get
+    /// a published rate.
+    pub fn has_later_published_exchange_rate(
+        &self,
+        from_currency: CurrencyCode,
+        to_currency: CurrencyCode,
+        date_: NaiveDate,
+    ) -> Result<bool> {
+        use schema::exchange_rates::dsl::*;
+        schema::exchange_rates::table
+            .select(date)
+            .filter(from_currency_code.eq(from_currency.to_str()))
+            .filter(to_currency_code.eq(to_currency.to_str()))
+            .filter(date.gt(date_.num_days_from_ce()))
+            .filter(fallback_date.is_null())
+            .first::<i32>(&self.connection)
+            .optional()
+            .map(|row| row.is_some())
+            .chain_err(|| "Failed to load exchange rate from database")
+    }
+
     pub fn create_exchange_rate(
         &self,
         from_currency: CurrencyCode,
         to_currency: CurrencyCode,
         date_: NaiveDate,
-        exchange_rate_: ExchangeRate,
+        rate_info: ExchangeRateInfo,
     ) -> Result<()> {
         use schema::exchange_rates::dsl::*;
         diesel::insert_into(schema::exchange_rates::table)
@@ -103,13 +232,315 @@ impl Database {
                 date.eq(date_.num_days_from_ce()),
                 from_currency_code.eq(from_currency.to_str()),
                 to_currency_code.eq(to_currency.to_str()),
-                exchange_rate.eq(exchange_rate_.to_scaled_i64()),
+                exchange_rate.eq(rate_info.exchange_rate.to_string()),
+                pivot_currency_code.eq(rate_info.pivot_currency.as_ref().map(|code| code.to_str())),
+                fallback_date.eq(rate_info.fallback_date.map(|d| d.num_days_from_ce())),
             ))
             .execute(&self.connection)
             .chain_err(|| "Failed to save exchange rate to database")?;
         Ok(())
     }
 
+    /// Save exchange rates from a file.  Overwriting a stored rate with a
+    /// different one flags the difference transactions that used it for
+    /// recomputation.
+    pub fn import_exchange_rates(
+        &self,
+        exchange_rates_: &[DatedExchangeRate],
+        on_conflict: ExchangeRateConflict,
+    ) -> Result<ImportedExchangeRates> {
+        if on_conflict == ExchangeRateConflict::Fail {
+            for new_rate in exchange_rates_ {
+                ensure!(
+                    self.get_exchange_rate(
+                        new_rate.from_currency,
+                        new_rate.to_currency,
+                        new_rate.date
+                    )?
+                    .is_none(),
+                    "Exchange rate from {} to {} on {} is already in the database",
+                    new_rate.from_currency,
+                    new_rate.to_currency,
+                    new_rate.date
+                );
+            }
+        }
+        use schema::exchange_rates::dsl::*;
+        self.connection
+            .transaction::<_, diesel::result::Error, _>(|| {
+                let mut imported = ImportedExchangeRates::default();
+                for new_rate in exchange_rates_ {
+                    // Imported rates are published rates, so a replaced row
+                    // loses any pivot currency or fallback date.
+                    let values = (
+                        date.eq(new_rate.date.num_days_from_ce()),
+                        from_currency_code.eq(new_rate.from_currency.to_str()),
+                        to_currency_code.eq(new_rate.to_currency.to_str()),
+                        exchange_rate.eq(new_rate.exchange_rate.to_string()),
+                        pivot_currency_code.eq(None::<String>),
+                        fallback_date.eq(None::<i32>),
+                    );
+                    imported.imported_count += match on_conflict {
+                        ExchangeRateConflict::Skip => {
+                            diesel::insert_or_ignore_into(schema::exchange_rates::table)
+                                .values(values)
+                                .execute(&self.connection)?
+                        }
+                        ExchangeRateConflict::Overwrite => {
+                            let opt_stored_rate = schema::exchange_rates::table
+                                .select((exchange_rate, pivot_currency_code, fallback_date))
+                                .filter(date.eq(new_rate.date.num_days_from_ce()))
+                                .filter(from_currency_code.eq(new_rate.from_currency.to_str()))
+                                .filter(to_currency_code.eq(new_rate.to_currency.to_str()))
+                                .first::<(String, Option<String>, Option<i32>)>(&self.connection)
+                                .optional()?;
+                            let changed = match opt_stored_rate {
+                                Some((stored_rate, stored_pivot, stored_fallback_date)) => {
+                                    stored_rate != new_rate.exchange_rate.to_string()
+                                        || stored_pivot.is_some()
+                                        || stored_fallback_date.is_some()
+                                }
+                                None => false,
+                            };
+                            if changed {
+                                imported.flagged_count += self
+                                    .flag_difference_transactions_for_recompute(
+                                        new_rate.from_currency,
+                                        new_rate.to_currency,
+                                        new_rate.date,
+                                    )?;
+                            }
+                            diesel::replace_into(schema::exchange_rates::table)
+                                .values(values)
+                                .execute(&self.connection)?
+                        }
+                        ExchangeRateConflict::Fail => {
+                            diesel::insert_into(schema::exchange_rates::table)
+                                .values(values)
+                                .execute(&self.connection)?
+                        }
+                    };
+                }
+                Ok(imported)
+            })
+            .chain_err(|| "Failed to import exchange rates into database")
+    }
+
+    pub fn get_exchange_rate_override(
+        &self,
+        from_currency: CurrencyCode,
+        to_currency: CurrencyCode,
+        date_: NaiveDate,
+    ) -> Result<Option<ExchangeRate>> {
+        use schema::exchange_rate_overrides::dsl::*;
+        schema::exchange_rate_overrides::table
+            .select(exchange_rate)
+            .filter(from_currency_code.eq(from_currency.to_str()))
+            .filter(to_currency_code.eq(to_currency.to_str()))
+            .filter(date.eq(date_.num_days_from_ce()))
+            .first::<String>(&self.connection)
+            .optional()
+            .chain_err(|| "Failed to load exchange rate override from database")?
+            .map(|rate| ExchangeRate::from_str(&rate))
+            .transpose()
+    }
+
+    pub fn get_exchange_rate_overrides(&self) -> Result<Vec<DatedExchangeRate>> {
+        use schema::exchange_rate_overrides::dsl::*;
+        schema::exchange_rate_overrides::table
+            .select((date, from_currency_code, to_currency_code, exchange_rate))
+            .order((date, from_currency_code, to_currency_code))
+            .load::<(i32, String, String, String)>(&self.connection)
+            .chain_err(|| "Failed to load exchange rate overrides from database")?
+            .into_iter()
+            .map(|(days_from_ce, from_currency, to_currency, rate)| {
+                Ok(DatedExchangeRate {
+                    date: NaiveDate::from_num_days_from_ce(days_from_ce),
+                    from_currency: CurrencyCode::from_str(&from_currency)?,
+                    to_currency: CurrencyCode::from_str(&to_currency)?,
+                    exchange_rate: ExchangeRate::from_str(&rate)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Set an exchange rate override, and flag the difference transactions
+    /// that used the overridden date's rate for recomputation.  Returns the
+    /// number of flagged difference transactions.
+    pub fn set_exchange_rate_override(&self, override_: &DatedExchangeRate) -> Result<usize> {
+        self.connection
+            .transaction(|| {
+                use schema::exchange_rate_overrides::dsl::*;
+                diesel::replace_into(schema::exchange_rate_overrides::table)
+                    .values((
+                        date.eq(override_.date.num_days_from_ce()),
+                        from_currency_code.eq(override_.from_currency.to_str()),
+                        to_currency_code.eq(override_.to_currency.to_str()),
+                        exchange_rate.eq(override_.exchange_rate.to_string()),
+                    ))
+                    .execute(&self.connection)?;
+                self.flag_difference_transactions_for_recompute(
+                    override_.from_currency,
+                    override_.to_currency,
+                    override_.date,
+                )
+            })
+            .chain_err(|| "Failed to save exchange rate override to database")
+    }
+
+    /// Remove an exchange rate override, and flag the difference transactions
+    /// that used it for recomputation.  Returns the number of flagged
+    /// difference transactions, or `None` if there was no such override.
+    pub fn remove_exchange_rate_override(
+        &self,
+        from_currency: CurrencyCode,
+        to_currency: CurrencyCode,
+        date_: NaiveDate,
+    ) -> Result<Option<usize>> {
+        self.connection
+            .transaction(|| {
+                use schema::exchange_rate_overrides::dsl::*;
+                let deleted_count = diesel::delete(schema::exchange_rate_overrides::table)
+                    .filter(from_currency_code.eq(from_currency.to_str()))
+                    .filter(to_currency_code.eq(to_currency.to_str()))
+                    .filter(date.eq(date_.num_days_from_ce()))
+                    .execute(&self.connection)?;
+                if deleted_count == 0 {
+                    Ok(None)
+                } else {
+                    self.flag_difference_transactions_for_recompute(
+                        from_currency,
+                        to_currency,
+                        date_,
+                    )
+                    .map(Some)
+                }
+            })
+            .chain_err(|| "Failed to remove exchange rate override from database")
+    }
+
+    /// Flag the difference transactions that used the exchange rate from
+    /// `from_currency` to `to_currency` for a date for recomputation.
+    ///
+    /// Budgets that have not been run since their currency was stored, and
+    /// difference transactions saved before their exchange rate date was, may
+    /// have used the rate, so they are flagged too (recomputing them stores the
+    /// missing information).
+    fn flag_difference_transactions_for_recompute(
+        &self,
+        from_currency: CurrencyCode,
+        to_currency: CurrencyCode,
+        exchange_rate_date_: NaiveDate,
+    ) -> QueryResult<usize> {
+        use schema::budgets;
+        use schema::difference_transactions::dsl::*;
+        let to_currency_budget_ids = schema::budgets::table.select(budgets::id).filter(
+            budgets::currency_code
+                .eq(to_currency.to_str())
+                .or(budgets::currency_code.is_null()),
+        );
+        diesel::update(schema::difference_transactions::table)
+            .filter(budget_id.eq_any(to_currency_budget_ids))
+            .filter(difference_currency_code.eq(from_currency.to_str()))
+            .filter(
+                exchange_rate_date
+                    .eq(exchange_rate_date_.num_days_from_ce())
+                    .or(exchange_rate_date.is_null()),
+            )
+            .set(needs_recompute.eq(true))
+            .execute(&self.connection)
+    }
+
+    /// The saved state of a budget, or `None` if the tool has never been run
+    /// for it.
+    pub fn get_budget_state(&self, ynab_budget_id_: &str) -> Result<Option<BudgetState>> {
+        Ok(self.get_budget(ynab_budget_id_)?.map(|(state, _)| state))
+    }
+
+    /// All saved difference transactions of a budget, in order of exchange
+    /// rate date.
+    pub fn get_difference_transactions_history(
+        &self,
+        ynab_budget_id_: &str,
+    ) -> Result<Vec<DifferenceTransactionHistoryEntry>> {
+        use schema::budgets;
+        use schema::difference_transactions::dsl::*;
+        schema::difference_transactions::table
+            .inner_join(schema::budgets::table)
+            .select((
+                foreign_ynab_transaction_id,
+                (
+                    difference_ynab_transaction_id,
+                    difference_amount_milliunits,
+                    difference_currency_code,
+                    difference_account_class,
+                    transfer_currency_code,
+                    transfer_account_class,
+                    exchange_rate_date,
+                    fee_amount_milliunits,
+                ),
+                needs_recompute,
+            ))
+            .filter(budgets::ynab_budget_id.eq(ynab_budget_id_))
+            .order((exchange_rate_date, id))
+            .load::<(String, DifferenceTransactionRow, bool)>(&self.connection)
+            .chain_err(|| "Failed to load difference transactions from database")
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|(foreign_transaction_id, row, needs_recompute_)| {
+                        DifferenceTransactionHistoryEntry {
+                            foreign_transaction_id: YnabTransactionId::new(foreign_transaction_id),
+                            inner: difference_transaction_from_row(row),
+                            needs_recompute: needs_recompute_,
+                        }
+                    })
+                    .collect()
+            })
+    }
+
+    /// Forget a budget's YNAB server knowledge and last run date, so that the
+    /// next run loads all transactions since the start date again.  With
+    /// `forget_budget`, forget the budget and its difference transactions
+    /// entirely instead.  Returns `false` if there was no such budget.
+    pub fn reset_budget(&self, ynab_budget_id_: &str, forget_budget: bool) -> Result<bool> {
+        use schema::budgets::dsl::*;
+        let opt_db_budget_id = schema::budgets::table
+            .select(id)
+            .filter(ynab_budget_id.eq(ynab_budget_id_))
+            .first::<i32>(&self.connection)
+            .optional()
+            .chain_err(|| "Failed to load budget state from database")?;
+        if let Some(db_budget_id) = opt_db_budget_id {
+            self.connection
+                .transaction::<_, diesel::result::Error, _>(|| {
+                    if forget_budget {
+                        diesel::delete(schema::difference_transactions::table)
+                            .filter(schema::difference_transactions::budget_id.eq(db_budget_id))
+                            .execute(&self.connection)?;
+                        diesel::delete(schema::skipped_foreign_transactions::table)
+                            .filter(
+                                schema::skipped_foreign_transactions::budget_id.eq(db_budget_id),
+                            )
+                            .execute(&self.connection)?;
+                        diesel::delete(schema::budgets::table.filter(id.eq(db_budget_id)))
+                            .execute(&self.connection)?;
+                    } else {
+                        diesel::update(schema::budgets::table.filter(id.eq(db_budget_id)))
+                            .set((
+                                ynab_server_knowledge.eq(None::<i64>),
+                                last_run_date.eq(None::<i32>),
+                            ))
+                            .execute(&self.connection)?;
+                    }
+                    Ok(())
+                })
+                .chain_err(|| "Failed to reset budget state in database")?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     pub fn get_or_create_budget<'a>(
         &'a self,
         ynab_budget_id_: &'a str,
@@ -138,7 +569,11 @@ impl Database {
         if self.dry_run {
             return Ok(BudgetDatabase {
                 connection: &self.connection,
-                run_state: BudgetRunState::DryRun(None),
+                run_state: if self.read_only {
+                    BudgetRunState::ReadOnly(None)
+                } else {
+                    BudgetRunState::DryRun(None)
+                },
             });
         }
         use schema::budgets::dsl::*;
@@ -181,7 +616,9 @@ impl Database {
                 },
                 BudgetDatabase {
                     connection: &self.connection,
-                    run_state: if self.dry_run {
+                    run_state: if self.read_only {
+                        BudgetRunState::ReadOnly(Some(db_budget_id))
+                    } else if self.dry_run {
                         BudgetRunState::DryRun(Some(db_budget_id))
                     } else {
                         BudgetRunState::Live(db_budget_id)
@@ -204,7 +641,7 @@ impl<'a> BudgetDatabase<'a> {
         let opt_db_budget_id = if update_state.had_changes {
             self.run_state.live_database_budget_id()
         } else {
-            self.run_state.dry_run_database_budget_id()
+            self.run_state.unchanged_state_database_budget_id()
         };
         if let Some(db_budget_id) = opt_db_budget_id {
             self.connection
@@ -223,7 +660,20 @@ impl<'a> BudgetDatabase<'a> {
                         db_budget_id,
                         &update_state.update_difference_transactions,
                     )?;
-                    self.update_budget(db_budget_id, ynab_server_knowledge, last_run_date)
+                    self.clear_recompute_flags(
+                        db_budget_id,
+                        update_state.recomputed_foreign_transaction_ids,
+                    )?;
+                    self.create_skipped_foreign_transactions(
+                        db_budget_id,
+                        update_state.skipped_foreign_transaction_ids,
+                    )?;
+                    self.update_budget(
+                        db_budget_id,
+                        ynab_server_knowledge,
+                        last_run_date,
+                        update_state.currency,
+                    )
                 })
                 .chain_err(|| "Failed to save budget state in database")
         } else {
@@ -238,57 +688,109 @@ impl<'a> BudgetDatabase<'a> {
         if let Some(db_budget_id) = self.run_state.dry_run_database_budget_id() {
             use schema::difference_transactions::dsl::*;
             schema::difference_transactions::table
-                .select((difference_ynab_transaction_id,
-                         difference_amount_milliunits,
-                         difference_currency_code,
-                         difference_account_class,
-                         transfer_currency_code,
-                         transfer_account_class))
+                .select((
+                    difference_ynab_transaction_id,
+                    difference_amount_milliunits,
+                    difference_currency_code,
+                    difference_account_class,
+                    transfer_currency_code,
+                    transfer_account_class,
+                    exchange_rate_date,
+                    fee_amount_milliunits,
+                ))
                 .filter(budget_id.eq(db_budget_id))
                 .filter(foreign_ynab_transaction_id.eq(&foreign_ynab_transaction_id_.raw))
-                .first::<(String, i64, String, String, Option<String>, Option<String>)>(self.connection)
+                .first::<DifferenceTransactionRow>(self.connection)
                 .optional()
-                .map(|opt| {
-                    opt.map(|(difference_transaction_id,
-                              amount,
-                              difference_currency_code_,
-                              difference_account_class_,
-                              transfer_currency_code_,
-                              transfer_account_class_)| DifferenceTransaction {
-                        difference_transaction_id: YnabTransactionId::new(difference_transaction_id),
-                        amount: Milliunits::from_scaled_i64(amount),
-                        difference_key: DifferenceKey {
-                            currency: CurrencyCode::from_str(&difference_currency_code_)
-                                .expect("difference_transactions.difference_currency_code should be valid currency code"),
-                            account_class: account_class_from_str(&difference_account_class_)
-                                .expect("difference_transactions.difference_account_class should be valid character"),
-                        },
-                        transfer_key: transfer_currency_code_.map(|code| DifferenceKey {
-                            currency: CurrencyCode::from_str(&code)
-                                .expect("difference_transactions.transfer_currency_code should be valid currency code"),
-                            account_class: account_class_from_str(&transfer_account_class_
-                                .expect("difference_transactions.transfer_account_class should not be null when transfer_currency_code is non-null"))
-                                .expect("difference_transactions.transfer_account_class should be a valid character"),
-                        }),
-                    })
-                })
+                .map(|opt| opt.map(difference_transaction_from_row))
                 .chain_err(|| "Failed to load existing difference transaction from database")
         } else {
             Ok(None)
         }
     }
 
+    /// Total fees of the saved difference transactions for each difference
+    /// account.
+    pub fn get_fees_totals(&self) -> Result<HashMap<DifferenceKey, Milliunits>> {
+        let mut fees_totals = HashMap::new();
+        if let Some(db_budget_id) = self.run_state.dry_run_database_budget_id() {
+            use schema::difference_transactions::dsl::*;
+            for (currency_code, account_class, fee_amount) in schema::difference_transactions::table
+                .select((
+                    difference_currency_code,
+                    difference_account_class,
+                    fee_amount_milliunits,
+                ))
+                .filter(budget_id.eq(db_budget_id))
+                .filter(fee_amount_milliunits.ne(0))
+                .load::<(String, String, i64)>(self.connection)
+                .chain_err(|| "Failed to load difference transaction fees from database")?
+            {
+                let difference_key = DifferenceKey {
+                    currency: CurrencyCode::from_str(&currency_code).expect(
+                        "difference_transactions.difference_currency_code should be valid currency code",
+                    ),
+                    account_class: account_class_from_str(&account_class).expect(
+                        "difference_transactions.difference_account_class should be valid character",
+                    ),
+                };
+                *fees_totals
+                    .entry(difference_key)
+                    .or_insert_with(Milliunits::zero) += Milliunits::from_scaled_i64(fee_amount);
+            }
+        }
+        Ok(fees_totals)
+    }
+
+    /// Foreign transactions to process again: those whose difference
+    /// transactions were flagged for recomputation, and those skipped in
+    /// `--interactive` mode.
+    pub fn get_recompute_foreign_transaction_ids(
+        &self,
+    ) -> Result<HashSet<YnabTransactionId<'static>>> {
+        if let Some(db_budget_id) = self.run_state.dry_run_database_budget_id() {
+            let flagged_ids = {
+                use schema::difference_transactions::dsl::*;
+                schema::difference_transactions::table
+                    .select(foreign_ynab_transaction_id)
+                    .filter(budget_id.eq(db_budget_id))
+                    .filter(needs_recompute.eq(true))
+                    .load::<String>(self.connection)
+                    .chain_err(|| {
+                        "Failed to load difference transactions to recompute from database"
+                    })?
+            };
+            let skipped_ids = {
+                use schema::skipped_foreign_transactions::dsl::*;
+                schema::skipped_foreign_transactions::table
+                    .select(foreign_ynab_transaction_id)
+                    .filter(budget_id.eq(db_budget_id))
+                    .load::<String>(self.connection)
+                    .chain_err(|| "Failed to load skipped transactions from database")?
+            };
+            Ok(flagged_ids
+                .into_iter()
+                .chain(skipped_ids)
+                .map(YnabTransactionId::new)
+                .collect())
+        } else {
+            Ok(HashSet::new())
+        }
+    }
+
     fn update_budget(
         &self,
         db_budget_id: i32,
         ynab_server_knowledge_: i64,
         last_run_date_: NaiveDate,
+        currency: CurrencyCode,
     ) -> QueryResult<()> {
         use schema::budgets::dsl::*;
         diesel::update(schema::budgets::table.filter(id.eq(db_budget_id)))
             .set((
                 ynab_server_knowledge.eq(Some(ynab_server_knowledge_)),
                 last_run_date.eq(Some(last_run_date_.num_days_from_ce())),
+                currency_code.eq(Some(currency.to_str())),
             ))
             .execute(self.connection)?;
         Ok(())
@@ -310,6 +812,50 @@ impl<'a> BudgetDatabase<'a> {
         Ok(())
     }
 
+    /// Clear the recompute flags of, and forget any earlier skips of, the
+    /// foreign transactions that were processed.
+    fn clear_recompute_flags(
+        &self,
+        db_budget_id: i32,
+        foreign_transaction_ids: HashSet<YnabTransactionId>,
+    ) -> QueryResult<()> {
+        let raw_ids = foreign_transaction_ids
+            .into_iter()
+            .map(|v| v.raw)
+            .collect::<Vec<_>>();
+        {
+            use schema::difference_transactions::dsl::*;
+            diesel::update(schema::difference_transactions::table)
+                .filter(budget_id.eq(db_budget_id))
+                .filter(foreign_ynab_transaction_id.eq_any(&raw_ids))
+                .set(needs_recompute.eq(false))
+                .execute(self.connection)?;
+        }
+        use schema::skipped_foreign_transactions::dsl::*;
+        diesel::delete(schema::skipped_foreign_transactions::table)
+            .filter(budget_id.eq(db_budget_id))
+            .filter(foreign_ynab_transaction_id.eq_any(&raw_ids))
+            .execute(self.connection)?;
+        Ok(())
+    }
+
+    fn create_skipped_foreign_transactions(
+        &self,
+        db_budget_id: i32,
+        foreign_transaction_ids: HashSet<YnabTransactionId>,
+    ) -> QueryResult<()> {
+        use schema::skipped_foreign_transactions::dsl::*;
+        for foreign_transaction_id in foreign_transaction_ids {
+            diesel::insert_or_ignore_into(schema::skipped_foreign_transactions::table)
+                .values((
+                    budget_id.eq(db_budget_id),
+                    foreign_ynab_transaction_id.eq(&foreign_transaction_id.raw),
+                ))
+                .execute(self.connection)?;
+        }
+        Ok(())
+    }
+
     fn create_difference_transactions(
         &self,
         db_budget_id: i32,
@@ -337,6 +883,11 @@ impl<'a> BudgetDatabase<'a> {
                         .inner
                         .transfer_key
                         .map(|k| account_class_to_str(k.account_class))),
+                    exchange_rate_date.eq(transaction
+                        .inner
+                        .exchange_rate_date
+                        .map(|d| d.num_days_from_ce())),
+                    fee_amount_milliunits.eq(transaction.inner.fee_amount.to_scaled_i64()),
                 ))
                 .execute(self.connection)?;
         }
@@ -368,6 +919,10 @@ impl<'a> BudgetDatabase<'a> {
                     transfer_account_class.eq(transaction
                         .transfer_key
                         .map(|k| account_class_to_str(k.account_class))),
+                    exchange_rate_date
+                        .eq(transaction.exchange_rate_date.map(|d| d.num_days_from_ce())),
+                    fee_amount_milliunits.eq(transaction.fee_amount.to_scaled_i64()),
+                    needs_recompute.eq(false),
                 ))
                 .execute(self.connection)?;
         }
@@ -378,19 +933,73 @@ impl<'a> BudgetDatabase<'a> {
 impl BudgetRunState {
     fn live_database_budget_id(&self) -> Option<i32> {
         match self {
-            BudgetRunState::DryRun(_) => None,
+            BudgetRunState::DryRun(_) | BudgetRunState::ReadOnly(_) => None,
             BudgetRunState::Live(id) => Some(*id),
         }
     }
 
     fn dry_run_database_budget_id(&self) -> Option<i32> {
+        match self {
+            BudgetRunState::DryRun(option_id) | BudgetRunState::ReadOnly(option_id) => *option_id,
+            BudgetRunState::Live(id) => Some(*id),
+        }
+    }
+
+    /// The budget to save the state of a run without changes to.
+    fn unchanged_state_database_budget_id(&self) -> Option<i32> {
         match self {
             BudgetRunState::DryRun(option_id) => *option_id,
+            BudgetRunState::ReadOnly(_) => None,
             BudgetRunState::Live(id) => Some(*id),
         }
     }
 }
 
+fn exchange_rate_info_from_row(
+    (rate, pivot_currency_code, fallback_days_from_ce): (String, Option<String>, Option<i32>),
+) -> Result<ExchangeRateInfo> {
+    Ok(ExchangeRateInfo {
+        exchange_rate: ExchangeRate::from_str(&rate)?,
+        pivot_currency: pivot_currency_code
+            .map(|code| CurrencyCode::from_str(&code))
+            .transpose()?,
+        fallback_date: fallback_days_from_ce.map(NaiveDate::from_num_days_from_ce),
+    })
+}
+
+fn difference_transaction_from_row(
+    (
+        difference_transaction_id,
+        amount,
+        difference_currency_code,
+        difference_account_class,
+        transfer_currency_code,
+        transfer_account_class,
+        exchange_rate_days_from_ce,
+        fee_amount,
+    ): DifferenceTransactionRow,
+) -> DifferenceTransaction<'static> {
+    DifferenceTransaction {
+        difference_transaction_id: YnabTransactionId::new(difference_transaction_id),
+        amount: Milliunits::from_scaled_i64(amount),
+        difference_key: DifferenceKey {
+            currency: CurrencyCode::from_str(&difference_currency_code)
+                .expect("difference_transactions.difference_currency_code should be valid currency code"),
+            account_class: account_class_from_str(&difference_account_class)
+                .expect("difference_transactions.difference_account_class should be valid character"),
+        },
+        transfer_key: transfer_currency_code.map(|code| DifferenceKey {
+            currency: CurrencyCode::from_str(&code)
+                .expect("difference_transactions.transfer_currency_code should be valid currency code"),
+            account_class: account_class_from_str(&transfer_account_class
+                .expect("difference_transactions.transfer_account_class should not be null when transfer_currency_code is non-null"))
+                .expect("difference_transactions.transfer_account_class should be a valid character"),
+        }),
+        exchange_rate_date: exchange_rate_days_from_ce.map(NaiveDate::from_num_days_from_ce),
+        fee_amount: Milliunits::from_scaled_i64(fee_amount),
+    }
+}
+
 fn account_class_to_str(value: AccountClass) -> &'static str {
     match value {
         AccountClass::Debit => "D",
@@ -3,6 +3,53 @@ use std::collections::HashSet;
 
 use crate::types::*;
 
+#[derive(Debug)]
+pub struct DatedExchangeRate {
+    pub date: NaiveDate,
+    pub from_currency: CurrencyCode,
+    pub to_currency: CurrencyCode,
+    pub exchange_rate: ExchangeRate,
+}
+
+/// An exchange rate, along with how it was derived.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExchangeRateInfo {
+    pub exchange_rate: ExchangeRate,
+    /// Currency the rate was triangulated through, if the provider did not
+    /// quote the pair directly.
+    pub pivot_currency: Option<CurrencyCode>,
+    /// Earlier date that the rate was published on, if none was published on
+    /// the requested date.
+    pub fallback_date: Option<NaiveDate>,
+}
+
+impl ExchangeRateInfo {
+    pub fn new(exchange_rate: ExchangeRate) -> ExchangeRateInfo {
+        ExchangeRateInfo {
+            exchange_rate,
+            pivot_currency: None,
+            fallback_date: None,
+        }
+    }
+}
+
+/// What to do when an exchange rate for the same date and currencies is
+/// already in the database.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExchangeRateConflict {
+    Skip,
+    Overwrite,
+    Fail,
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ImportedExchangeRates {
+    pub imported_count: usize,
+    /// Difference transactions flagged for recomputation because an
+    /// overwritten exchange rate changed.
+    pub flagged_count: usize,
+}
+
 #[derive(Debug)]
 pub struct BudgetState {
     pub start_date: NaiveDate,
@@ -13,9 +60,14 @@ pub struct BudgetState {
 #[derive(Debug)]
 pub struct UpdateBudgetState<'a> {
     pub had_changes: bool,
+    pub currency: CurrencyCode,
     pub create_difference_transactions: Vec<CreateDifferenceTransaction<'a>>,
     pub update_difference_transactions: Vec<DifferenceTransaction<'a>>,
     pub delete_difference_transaction_ids: HashSet<YnabTransactionId<'a>>,
+    pub recomputed_foreign_transaction_ids: HashSet<YnabTransactionId<'a>>,
+    /// Foreign transactions skipped in `--interactive` mode, to process again
+    /// on the next run.
+    pub skipped_foreign_transaction_ids: HashSet<YnabTransactionId<'a>>,
 }
 
 #[derive(Debug)]
@@ -24,10 +76,21 @@ pub struct CreateDifferenceTransaction<'a> {
     pub inner: DifferenceTransaction<'a>,
 }
 
+/// A saved difference transaction, as listed by the `history` command.
+#[derive(Debug)]
+pub struct DifferenceTransactionHistoryEntry {
+    pub foreign_transaction_id: YnabTransactionId<'static>,
+    pub inner: DifferenceTransaction<'static>,
+    pub needs_recompute: bool,
+}
+
 #[derive(Debug)]
 pub struct DifferenceTransaction<'a> {
     pub difference_transaction_id: YnabTransactionId<'a>,
     pub amount: Milliunits,
     pub difference_key: DifferenceKey,
     pub transfer_key: Option<DifferenceKey>,
+    pub exchange_rate_date: Option<NaiveDate>,
+    /// Part of the amount that is the account's fee (see `AccountFee`).
+    pub fee_amount: Milliunits,
 }
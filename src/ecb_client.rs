@@ -0,0 +1,262 @@
+use chrono::NaiveDate;
+use log::debug;
+use rust_decimal::Decimal;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::str::FromStr;
+
+use crate::constants::*;
+use crate::errors::*;
+use crate::exchange_rate_provider::*;
+use crate::types::*;
+use crate::utilities::*;
+
+/// Where to read European Central Bank euro reference rates from.
+#[derive(Debug)]
+pub enum EcbSource<'a> {
+    /// A local copy of a daily or historical reference rates XML file.
+    File(&'a str),
+    /// Base URL that serves the daily and historical reference rates XML files.
+    BaseUrl(&'a str),
+}
+
+#[derive(Debug)]
+pub struct EcbClient<'a> {
+    source: EcbSource<'a>,
+    rates_table: RefCell<Option<EcbRatesTable>>,
+}
+
+#[derive(Debug)]
+struct EcbRatesTable {
+    euro_rates: HashMap<NaiveDate, HashMap<CurrencyCode, Decimal>>,
+    is_complete: bool,
+}
+
+impl<'a> EcbClient<'a> {
+    pub fn new(source: EcbSource<'a>) -> Self {
+        EcbClient {
+            source,
+            rates_table: RefCell::new(None),
+        }
+    }
+
+    fn get_date_euro_rates(
+        &self,
+        date: NaiveDate,
+    ) -> Result<Option<HashMap<CurrencyCode, Decimal>>> {
+        let mut rates_table = self.rates_table.borrow_mut();
+        // Start with the small daily file, and only fall back to the (much
+        // larger) historical file if the date is not in it.
+        if rates_table.is_none() {
+            *rates_table = Some(self.load_rates_table(false)?);
+        }
+        let needs_historical = match &*rates_table {
+            Some(table) => !table.is_complete && !table.euro_rates.contains_key(&date),
+            None => false,
+        };
+        if needs_historical {
+            *rates_table = Some(self.load_rates_table(true)?);
+        }
+        Ok(rates_table
+            .as_ref()
+            .expect("EcbClient rates table should be loaded")
+            .euro_rates
+            .get(&date)
+            .cloned())
+    }
+
+    fn load_rates_table(&self, load_historical: bool) -> Result<EcbRatesTable> {
+        let (xml, is_complete) = match self.source {
+            EcbSource::File(path) => {
+                progress!("  Reading ECB exchange rates from {}...", path);
+                (
+                    fs::read_to_string(path).chain_err(|| {
+                        format!("Failed to read ECB exchange rates file: {}", path)
+                    })?,
+                    true,
+                )
+            }
+            EcbSource::BaseUrl(base_url) => {
+                let url = format!(
+                    "{}/{}",
+                    base_url,
+                    if load_historical {
+                        ECB_HISTORICAL_RATES_FILENAME
+                    } else {
+                        ECB_DAILY_RATES_FILENAME
+                    }
+                );
+                progress!("  Getting exchange rates from ECB...");
+                debug!("ECB reference rates URL: {}", url);
+                (
+                    reqwest::get(&url)
+                        .and_then(|response| response.error_for_status())
+                        .and_then(|mut response| response.text())
+                        .chain_err(|| "Failed to get response")?,
+                    load_historical,
+                )
+            }
+        };
+        Ok(EcbRatesTable {
+            euro_rates: parse_ecb_rates_xml(&xml)?,
+            is_complete,
+        })
+    }
+}
+
+impl<'a> ExchangeRateProvider for EcbClient<'a> {
+    fn name(&self) -> &str {
+        "European Central Bank"
+    }
+
+    fn get_date_exchange_rates(
+        &self,
+        date: NaiveDate,
+        from_to_currency_pairs: &HashSet<(CurrencyCode, CurrencyCode)>,
+    ) -> Result<HashMap<(CurrencyCode, CurrencyCode), ExchangeRate>> {
+        let mut results = HashMap::new();
+        let euro_rates = match self.get_date_euro_rates(date)? {
+            Some(euro_rates) => euro_rates,
+            None => return Ok(results),
+        };
+        let euro = CurrencyCode::from_str(ECB_BASE_CURRENCY)?;
+        let euro_rate = |currency: CurrencyCode| {
+            if currency == euro {
+                Some(Decimal::new(1, 0))
+            } else {
+                euro_rates.get(&currency).cloned()
+            }
+        };
+        for &(from, to) in from_to_currency_pairs {
+            // ECB rates are quoted as units of currency per euro, so any other
+            // pair is derived as a cross rate through the euro.
+            if let (Some(from_euro_rate), Some(to_euro_rate)) = (euro_rate(from), euro_rate(to)) {
+                if !from_euro_rate.is_zero() {
+                    results.insert(
+                        (from, to),
+                        ExchangeRate::from_decimal(to_euro_rate / from_euro_rate),
+                    );
+                }
+            }
+        }
+        Ok(results)
+    }
+}
+
+fn parse_ecb_rates_xml(xml: &str) -> Result<HashMap<NaiveDate, HashMap<CurrencyCode, Decimal>>> {
+    let mut results = HashMap::new();
+    let mut opt_date = None;
+    for captures in ECB_CUBE_REGEX.captures_iter(xml) {
+        if let Some(date) = captures.get(1) {
+            opt_date = Some(parse_iso_date(date.as_str())?);
+        } else if let (Some(currency), Some(rate)) = (captures.get(2), captures.get(3)) {
+            let date = opt_date.chain_err(|| "ECB exchange rate is not inside a dated Cube")?;
+            results.entry(date).or_insert_with(HashMap::new).insert(
+                CurrencyCode::from_str(currency.as_str())?,
+                Decimal::from_str(rate.as_str())
+                    .chain_err(|| format!("Invalid ECB exchange rate: {}", rate.as_str()))?,
+            );
+        }
+    }
+    ensure!(
+        !results.is_empty(),
+        "ECB exchange rates XML does not contain any rates"
+    );
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    const TEST_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gesmes:Envelope xmlns:gesmes="http://www.gesmes.org/xml/2002-08-01" xmlns="http://www.ecb.int/vocabulary/2002-08-01/eurofxref">
+	<gesmes:subject>Reference rates</gesmes:subject>
+	<Cube>
+		<Cube time='2019-09-20'>
+			<Cube currency='USD' rate='1.1020'/>
+			<Cube currency='JPY' rate='118.79'/>
+		</Cube>
+		<Cube time="2019-09-19">
+			<Cube currency="USD" rate="1.1034"/>
+		</Cube>
+	</Cube>
+</gesmes:Envelope>"#;
+
+    const TEST_DAILY_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gesmes:Envelope xmlns:gesmes="http://www.gesmes.org/xml/2002-08-01" xmlns="http://www.ecb.int/vocabulary/2002-08-01/eurofxref">
+	<Cube>
+		<Cube time='2019-09-20'>
+			<Cube currency='USD' rate='1.1020'/>
+		</Cube>
+	</Cube>
+</gesmes:Envelope>"#;
+
+    #[test]
+    fn test_parse_ecb_rates_xml() {
+        let rates = parse_ecb_rates_xml(TEST_XML).unwrap();
+        assert_eq!(rates.len(), 2);
+        assert_eq!(
+            rates[&NaiveDate::from_ymd(2019, 9, 20)][&code("JPY")],
+            Decimal::new(11_879, 2)
+        );
+        assert_eq!(
+            rates[&NaiveDate::from_ymd(2019, 9, 19)][&code("USD")],
+            Decimal::new(11_034, 4)
+        );
+    }
+
+    #[test]
+    fn test_ecb_cross_rates() {
+        let file = TempFile::new("ecb.xml");
+        fs::write(file.path(), TEST_XML).unwrap();
+        let client = EcbClient::new(EcbSource::File(file.path()));
+        let pairs = [
+            (code("EUR"), code("USD")),
+            (code("USD"), code("EUR")),
+            (code("USD"), code("JPY")),
+            (code("USD"), code("CAD")),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let rates = client
+            .get_date_exchange_rates(NaiveDate::from_ymd(2019, 9, 20), &pairs)
+            .unwrap();
+        assert_eq!(rates.len(), 3);
+        assert_eq!(rates[&(code("EUR"), code("USD"))], rate("1.102"));
+        assert_eq!(rates[&(code("USD"), code("EUR"))], rate("0.907441016334"));
+        assert_eq!(rates[&(code("USD"), code("JPY"))], rate("107.794918330309"));
+    }
+
+    #[test]
+    fn test_ecb_historical_fallback() {
+        let (base_url, server) = serve_http_responses(vec![
+            http_response("200 OK", TEST_DAILY_XML),
+            http_response("200 OK", TEST_XML),
+        ]);
+        let client = EcbClient::new(EcbSource::BaseUrl(&base_url));
+        let pairs = [(code("EUR"), code("USD"))].iter().cloned().collect();
+        // The first lookup of a date that is not in the daily file also loads
+        // the historical file.
+        let rates = client
+            .get_date_exchange_rates(NaiveDate::from_ymd(2019, 9, 19), &pairs)
+            .unwrap();
+        assert_eq!(rates[&(code("EUR"), code("USD"))], rate("1.1034"));
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                format!("/{}", ECB_DAILY_RATES_FILENAME),
+                format!("/{}", ECB_HISTORICAL_RATES_FILENAME)
+            ]
+        );
+        // The historical file has every date, so it is not loaded again (the
+        // server has stopped, so another request would fail).
+        assert!(client
+            .get_date_exchange_rates(NaiveDate::from_ymd(2019, 9, 18), &pairs)
+            .unwrap()
+            .is_empty());
+    }
+}
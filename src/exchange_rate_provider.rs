@@ -0,0 +1,222 @@
+use chrono::NaiveDate;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+
+use crate::errors::*;
+use crate::types::*;
+
+/// Exchange rates for one date, keyed by `(from, to)` currency pair.
+pub type CurrencyPairExchangeRates = HashMap<(CurrencyCode, CurrencyCode), ExchangeRate>;
+
+pub trait ExchangeRateProvider {
+    fn name(&self) -> &str;
+
+    /// Get exchange rates for the given date.  Pairs that the provider does
+    /// not quote are left out of the result, rather than causing an error.
+    fn get_date_exchange_rates(
+        &self,
+        date: NaiveDate,
+        from_to_currency_pairs: &HashSet<(CurrencyCode, CurrencyCode)>,
+    ) -> Result<HashMap<(CurrencyCode, CurrencyCode), ExchangeRate>>;
+
+    /// Get exchange rates for each date from `start_date` to `end_date`
+    /// (inclusive), passing each date's rates to `on_date_rates` as they are
+    /// received so that they can be saved even if a later request fails.
+    /// Providers that can get several dates in one request should override
+    /// this.
+    fn get_date_range_exchange_rates(
+        &self,
+        start_date: NaiveDate,
+        end_date: NaiveDate,
+        from_to_currency_pairs: &HashSet<(CurrencyCode, CurrencyCode)>,
+        on_date_rates: &mut dyn FnMut(NaiveDate, CurrencyPairExchangeRates) -> Result<()>,
+    ) -> Result<()> {
+        let mut date = start_date;
+        while date <= end_date {
+            on_date_rates(
+                date,
+                self.get_date_exchange_rates(date, from_to_currency_pairs)?,
+            )?;
+            date = date.succ();
+        }
+        Ok(())
+    }
+}
+
+/// Asks each provider in order of preference, only moving on to the next
+/// provider for pairs that the previous ones failed to supply.
+pub struct FallbackExchangeRateProvider<'a> {
+    name: String,
+    providers: Vec<Box<dyn ExchangeRateProvider + 'a>>,
+}
+
+impl<'a> FallbackExchangeRateProvider<'a> {
+    pub fn new(providers: Vec<Box<dyn ExchangeRateProvider + 'a>>) -> Self {
+        FallbackExchangeRateProvider {
+            name: providers
+                .iter()
+                .map(|provider| provider.name())
+                .collect::<Vec<_>>()
+                .join(", "),
+            providers,
+        }
+    }
+}
+
+impl<'a> ExchangeRateProvider for FallbackExchangeRateProvider<'a> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_date_exchange_rates(
+        &self,
+        date: NaiveDate,
+        from_to_currency_pairs: &HashSet<(CurrencyCode, CurrencyCode)>,
+    ) -> Result<HashMap<(CurrencyCode, CurrencyCode), ExchangeRate>> {
+        let mut results = HashMap::new();
+        let mut remaining_pairs = from_to_currency_pairs.clone();
+        let mut first_error = None;
+        for provider in &self.providers {
+            if remaining_pairs.is_empty() {
+                break;
+            }
+            match provider.get_date_exchange_rates(date, &remaining_pairs) {
+                Ok(provider_results) => {
+                    for pair in provider_results.keys() {
+                        remaining_pairs.remove(pair);
+                    }
+                    results.extend(provider_results);
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to get exchange rates from {}: {}",
+                        provider.name(),
+                        err
+                    );
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+        // A pair that no provider supplied may be missing because of the
+        // failure (e.g. an exhausted quota), so report that rather than
+        // leaving the pair out as if it were not quoted.
+        match first_error {
+            Some(err) if !remaining_pairs.is_empty() => Err(err).chain_err(|| {
+                let mut pairs: Vec<String> = remaining_pairs
+                    .iter()
+                    .map(|(from_currency, to_currency)| {
+                        format!("{} to {}", from_currency, to_currency)
+                    })
+                    .collect();
+                pairs.sort();
+                format!("Failed to get exchange rates for: {}", pairs.join(", "))
+            }),
+            _ => Ok(results),
+        }
+    }
+
+    fn get_date_range_exchange_rates(
+        &self,
+        start_date: NaiveDate,
+        end_date: NaiveDate,
+        from_to_currency_pairs: &HashSet<(CurrencyCode, CurrencyCode)>,
+        on_date_rates: &mut dyn FnMut(NaiveDate, CurrencyPairExchangeRates) -> Result<()>,
+    ) -> Result<()> {
+        // Pairs that each date is still missing, for the next provider to try.
+        let mut remaining_date_pairs: HashMap<NaiveDate, HashSet<(CurrencyCode, CurrencyCode)>> =
+            HashMap::new();
+        let mut date = start_date;
+        while date <= end_date {
+            remaining_date_pairs.insert(date, from_to_currency_pairs.clone());
+            date = date.succ();
+        }
+        let mut got_any_rates = false;
+        let mut last_error = None;
+        for provider in &self.providers {
+            let remaining_pairs: HashSet<(CurrencyCode, CurrencyCode)> = remaining_date_pairs
+                .values()
+                .flat_map(|pairs| pairs.iter().cloned())
+                .collect();
+            if remaining_pairs.is_empty() {
+                break;
+            }
+            let remaining_dates: Vec<NaiveDate> = remaining_date_pairs
+                .iter()
+                .filter(|(_, pairs)| !pairs.is_empty())
+                .map(|(&date, _)| date)
+                .collect();
+            let provider_start_date = *remaining_dates.iter().min().expect("remaining dates");
+            let provider_end_date = *remaining_dates.iter().max().expect("remaining dates");
+            let result = provider.get_date_range_exchange_rates(
+                provider_start_date,
+                provider_end_date,
+                &remaining_pairs,
+                &mut |date, provider_results| {
+                    let date_remaining_pairs = remaining_date_pairs
+                        .get_mut(&date)
+                        .chain_err(|| format!("Unexpected exchange rates date: {}", date))?;
+                    let date_results: CurrencyPairExchangeRates = provider_results
+                        .into_iter()
+                        .filter(|(pair, _)| date_remaining_pairs.remove(pair))
+                        .collect();
+                    if !date_results.is_empty() {
+                        got_any_rates = true;
+                        on_date_rates(date, date_results)?;
+                    }
+                    Ok(())
+                },
+            );
+            if let Err(err) = result {
+                warn!(
+                    "Failed to get exchange rates from {}: {}",
+                    provider.name(),
+                    err
+                );
+                last_error = Some(err);
+            }
+        }
+        match last_error {
+            Some(err) if !got_any_rates => {
+                Err(err).chain_err(|| "All exchange rate providers failed")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_fallback_reports_error_for_missing_pairs() {
+        let date = NaiveDate::from_ymd(2020, 1, 2);
+        let provider = FallbackExchangeRateProvider::new(vec![
+            Box::new(FailingExchangeRateProvider),
+            Box::new(TestExchangeRateProvider {
+                dates: vec![date],
+                rates: vec![((code("GBP"), code("EUR")), rate("1.15"))]
+                    .into_iter()
+                    .collect(),
+            }),
+        ]);
+        // The next provider supplies every pair, so the failure does not
+        // matter.
+        let pairs = [(code("GBP"), code("EUR"))].iter().cloned().collect();
+        let rates = provider.get_date_exchange_rates(date, &pairs).unwrap();
+        assert_eq!(rates[&(code("GBP"), code("EUR"))], rate("1.15"));
+        let pairs = [(code("GBP"), code("EUR")), (code("USD"), code("EUR"))]
+            .iter()
+            .cloned()
+            .collect();
+        let error = provider.get_date_exchange_rates(date, &pairs).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to get exchange rates for: USD to EUR"
+        );
+        assert!(error
+            .iter()
+            .any(|cause| cause.to_string() == FailingExchangeRateProvider::ERROR));
+    }
+}
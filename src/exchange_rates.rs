@@ -1,87 +1,1079 @@
 use chrono::NaiveDate;
+use rust_decimal::Decimal;
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
 
-use crate::currency_converter_client::*;
+use crate::database::models::*;
 use crate::database::*;
 use crate::errors::*;
+use crate::exchange_rate_provider::*;
 use crate::types::*;
+use crate::utilities::*;
+
+/// Where to get exchange rates that are not already in the database.
+pub enum ExchangeRateSource<'a> {
+    Provider(&'a dyn ExchangeRateProvider),
+    /// Never call an exchange rate provider, and handle rates missing from the
+    /// database according to the policy.
+    Offline(MissingRatePolicy),
+}
+
+/// What to do in offline mode when the database has no exchange rate for a
+/// date.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MissingRatePolicy {
+    /// Use the known rate for the closest date.
+    Nearest,
+    /// Fail, listing all the missing rates.
+    Fail,
+}
+
+/// Guards against bogus exchange rates from a provider by comparing them with
+/// the previous published rate before they are saved.
+#[derive(Clone, Copy, Debug)]
+pub struct RateChangeCheck {
+    pub max_change_percent: Decimal,
+    pub action: RateChangeAction,
+}
+
+/// What to do with a new exchange rate that changed too much.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RateChangeAction {
+    Refuse,
+    /// Ask the user whether to use the rate anyway.
+    Confirm,
+}
 
 pub struct ExchangeRatesCache<'a> {
-    currency_converter_client: &'a CurrencyConverterClient<'a>,
+    source: ExchangeRateSource<'a>,
     database: &'a Database,
-    cache: RefCell<HashMap<(CurrencyCode, NaiveDate), ExchangeRate>>,
+    fixed_exchange_rates: HashMap<(CurrencyCode, CurrencyCode), ExchangeRate>,
+    pivot_currency: Option<CurrencyCode>,
+    fallback_days: u32,
+    rate_change_check: Option<RateChangeCheck>,
+    save_exchange_rates: bool,
+    cache: RefCell<HashMap<(CurrencyCode, NaiveDate), ExchangeRateInfo>>,
+    missing_exchange_rates: RefCell<BTreeSet<(NaiveDate, CurrencyCode, CurrencyCode)>>,
 }
 
 impl<'a> ExchangeRatesCache<'a> {
     pub fn new(
-        currency_converter_client: &'a CurrencyConverterClient<'a>,
+        source: ExchangeRateSource<'a>,
         database: &'a Database,
+        fixed_exchange_rates: HashMap<(CurrencyCode, CurrencyCode), ExchangeRate>,
+        pivot_currency: Option<CurrencyCode>,
+        fallback_days: u32,
+        rate_change_check: Option<RateChangeCheck>,
     ) -> ExchangeRatesCache<'a> {
         ExchangeRatesCache {
-            currency_converter_client,
+            source,
             database,
+            fixed_exchange_rates,
+            pivot_currency,
+            fallback_days,
+            rate_change_check,
+            save_exchange_rates: true,
             cache: RefCell::new(HashMap::new()),
+            missing_exchange_rates: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    /// Make the cache read-only: rates fetched from the provider are used but
+    /// not saved to the database, and are not checked for rate changes (so
+    /// there is never a confirmation prompt).
+    pub fn read_only(self) -> ExchangeRatesCache<'a> {
+        ExchangeRatesCache {
+            rate_change_check: None,
+            save_exchange_rates: false,
+            ..self
         }
     }
 
+    /// Get the exchange rate for a date.  If no rate was published for the
+    /// date (e.g. weekends and bank holidays), falls back to the nearest
+    /// earlier published rate within the look-back window.  Fallback rates are
+    /// only saved to the database once a later date has a published rate.
+    ///
+    /// In offline mode, returns `None` if the rate is missing from the
+    /// database; the missing rates are reported by
+    /// `check_missing_exchange_rates`.
     pub fn get_exchange_rate(
         &self,
-        anticipate_from_currencies: &'a HashSet<CurrencyCode>,
+        anticipate_from_currencies: &HashSet<CurrencyCode>,
         from_currency: CurrencyCode,
         to_currency: CurrencyCode,
         date: NaiveDate,
-    ) -> Result<ExchangeRate> {
+    ) -> Result<Option<ExchangeRateInfo>> {
         let cache_key = (from_currency, date);
         let mut cache = self.cache.borrow_mut();
-        if let Some(&rate) = cache.get(&cache_key) {
-            return Ok(rate);
+        if let Some(&rate_info) = cache.get(&cache_key) {
+            return Ok(Some(rate_info));
+        }
+        let override_result =
+            self.database
+                .get_exchange_rate_override(from_currency, to_currency, date)?;
+        if let Some(rate) = override_result {
+            let rate_info = ExchangeRateInfo::new(rate);
+            cache.insert(cache_key, rate_info);
+            return Ok(Some(rate_info));
+        }
+        if let Some(rate) = self.get_fixed_exchange_rate(from_currency, to_currency) {
+            let rate_info = ExchangeRateInfo::new(rate);
+            cache.insert(cache_key, rate_info);
+            return Ok(Some(rate_info));
         }
         let rate_result = self
             .database
             .get_exchange_rate(from_currency, to_currency, date)?;
-        if let Some(rate) = rate_result {
-            cache.insert(cache_key, rate);
-            return Ok(rate);
+        if let Some(rate_info) = rate_result {
+            cache.insert(cache_key, rate_info);
+            return Ok(Some(rate_info));
         }
-        let mut loaded_rates = self.database.get_known_exchange_rates(
-            anticipate_from_currencies,
+        let exchange_rate_provider = match self.source {
+            ExchangeRateSource::Provider(exchange_rate_provider) => exchange_rate_provider,
+            ExchangeRateSource::Offline(missing_rate_policy) => {
+                let opt_rate_info = match missing_rate_policy {
+                    MissingRatePolicy::Nearest => self
+                        .database
+                        .get_nearest_exchange_rate(from_currency, to_currency, date)?
+                        .map(|(nearest_date, rate_info)| ExchangeRateInfo {
+                            fallback_date: rate_info.fallback_date.or(Some(nearest_date)),
+                            ..rate_info
+                        }),
+                    MissingRatePolicy::Fail => None,
+                };
+                match opt_rate_info {
+                    // Not saved to the database, so that a later online run
+                    // gets the real rate for the date.
+                    Some(rate_info) => {
+                        cache.insert(cache_key, rate_info);
+                    }
+                    None => {
+                        self.missing_exchange_rates.borrow_mut().insert((
+                            date,
+                            from_currency,
+                            to_currency,
+                        ));
+                    }
+                }
+                return Ok(opt_rate_info);
+            }
+        };
+        // Currencies with fixed exchange rates never need to be looked up, so
+        // leave them out to avoid using up the provider's quota.
+        let mut anticipate_from_currencies: HashSet<CurrencyCode> = anticipate_from_currencies
+            .iter()
+            .cloned()
+            .filter(|&currency| {
+                self.get_fixed_exchange_rate(currency, to_currency)
+                    .is_none()
+            })
+            .collect();
+        anticipate_from_currencies.insert(from_currency);
+        let mut loaded_rates = self.load_date_exchange_rates(
+            exchange_rate_provider,
+            &anticipate_from_currencies,
             to_currency,
             date,
         )?;
-        let currencies_to_get_from_api: HashSet<(CurrencyCode, CurrencyCode)> =
-            anticipate_from_currencies
-                .difference(&loaded_rates.keys().cloned().collect())
-                .map(|&code| (code, to_currency))
-                .collect();
-        let currency_converter_response = self
-            .currency_converter_client
-            .get_date_exchange_rates(date, &currencies_to_get_from_api)
-            .chain_err(|| "Failed to get exchange rate from Currency Converter API")?;
-        for (got_currency, _) in currencies_to_get_from_api {
-            let &got_exchange_rate = currency_converter_response
-                .get(&(got_currency, to_currency))
-                .chain_err(|| {
-                    format!(
-                        "Response is missing exchange rate for currency: {}",
-                        got_currency
-                    )
-                })?;
-            self.database.create_exchange_rate(
-                got_currency,
+        let mut fallback_currencies: HashSet<CurrencyCode> = anticipate_from_currencies
+            .difference(&loaded_rates.keys().cloned().collect())
+            .cloned()
+            .collect();
+        let mut fallback_date = date;
+        for _ in 0..self.fallback_days {
+            if fallback_currencies.is_empty() {
+                break;
+            }
+            fallback_date = fallback_date.pred();
+            for (currency, fallback_rate_info) in self.load_date_exchange_rates(
+                exchange_rate_provider,
+                &fallback_currencies,
                 to_currency,
-                date,
-                got_exchange_rate,
-            )?;
-            loaded_rates.insert(got_currency, got_exchange_rate);
+                fallback_date,
+            )? {
+                let rate_info = ExchangeRateInfo {
+                    fallback_date: fallback_rate_info.fallback_date.or(Some(fallback_date)),
+                    ..fallback_rate_info
+                };
+                // Until a later date has a published rate, the provider may
+                // still publish one for this date (e.g. today's rate later in
+                // the day), so only save the fallback once that is ruled out.
+                if self.save_exchange_rates
+                    && self.database.has_later_published_exchange_rate(
+                        currency,
+                        to_currency,
+                        date,
+                    )?
+                {
+                    self.database
+                        .create_exchange_rate(currency, to_currency, date, rate_info)?;
+                }
+                fallback_currencies.remove(&currency);
+                loaded_rates.insert(currency, rate_info);
+            }
         }
-        let &rate = loaded_rates.get(&from_currency).chain_err(|| {
+        let &rate_info = loaded_rates.get(&from_currency).chain_err(|| {
             format!(
-                "Response is missing exchange rate for currency: {}",
-                from_currency
+                "Response is missing exchange rate for currency: {} (looked back {} days from {})",
+                from_currency,
+                self.fallback_days,
+                format_iso_date(date)
             )
         })?;
-        cache.insert(cache_key, rate);
-        Ok(rate)
+        cache.insert(cache_key, rate_info);
+        Ok(Some(rate_info))
+    }
+
+    /// Fail if any exchange rates were missing in offline mode, listing them.
+    pub fn check_missing_exchange_rates(&self) -> Result<()> {
+        let missing_exchange_rates = self.missing_exchange_rates.borrow();
+        if missing_exchange_rates.is_empty() {
+            return Ok(());
+        }
+        bail!(
+            "Exchange rates are missing from the database in offline mode:\n{}",
+            missing_exchange_rates
+                .iter()
+                .map(|(date, from_currency, to_currency)| format!(
+                    "  {} {} to {}",
+                    format_iso_date(*date),
+                    from_currency,
+                    to_currency
+                ))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    }
+
+    /// Load exchange rates published for exactly the given date, from the
+    /// database or else from the provider (saving them to the database).
+    /// Currencies without a rate for the date are left out of the result.
+    fn load_date_exchange_rates(
+        &self,
+        exchange_rate_provider: &dyn ExchangeRateProvider,
+        from_currencies: &HashSet<CurrencyCode>,
+        to_currency: CurrencyCode,
+        date: NaiveDate,
+    ) -> Result<HashMap<CurrencyCode, ExchangeRateInfo>> {
+        let mut loaded_rates =
+            self.database
+                .get_known_exchange_rates(from_currencies, to_currency, date)?;
+        let currencies_to_get_from_api: HashSet<(CurrencyCode, CurrencyCode)> = from_currencies
+            .difference(&loaded_rates.keys().cloned().collect())
+            .map(|&code| (code, to_currency))
+            .collect();
+        if currencies_to_get_from_api.is_empty() {
+            return Ok(loaded_rates);
+        }
+        let provider_response = exchange_rate_provider
+            .get_date_exchange_rates(date, &currencies_to_get_from_api)
+            .chain_err(|| {
+                format!(
+                    "Failed to get exchange rate from {}",
+                    exchange_rate_provider.name()
+                )
+            })?;
+        let missing_currencies: Vec<CurrencyCode> = currencies_to_get_from_api
+            .iter()
+            .filter(|pair| !provider_response.contains_key(pair))
+            .map(|&(currency, _)| currency)
+            .collect();
+        let pivot_rates = self.get_pivot_exchange_rates(
+            exchange_rate_provider,
+            &missing_currencies,
+            to_currency,
+            date,
+        )?;
+        for (got_currency, _) in currencies_to_get_from_api {
+            let opt_rate_info = match provider_response.get(&(got_currency, to_currency)) {
+                Some(&rate) => Some(ExchangeRateInfo::new(rate)),
+                None => pivot_rates
+                    .get(&got_currency)
+                    .map(|&rate| ExchangeRateInfo {
+                        pivot_currency: self.pivot_currency,
+                        ..ExchangeRateInfo::new(rate)
+                    }),
+            };
+            if let Some(rate_info) = opt_rate_info {
+                if let Some(rate_change_check) = self.rate_change_check {
+                    check_rate_change(
+                        self.database,
+                        rate_change_check,
+                        got_currency,
+                        to_currency,
+                        date,
+                        rate_info.exchange_rate,
+                    )?;
+                }
+                if self.save_exchange_rates {
+                    self.database.create_exchange_rate(
+                        got_currency,
+                        to_currency,
+                        date,
+                        rate_info,
+                    )?;
+                }
+                loaded_rates.insert(got_currency, rate_info);
+            }
+        }
+        Ok(loaded_rates)
+    }
+
+    /// Derive exchange rates that the provider does not quote directly by
+    /// going through the pivot currency (`FROM` to pivot to `TO`).
+    fn get_pivot_exchange_rates(
+        &self,
+        exchange_rate_provider: &dyn ExchangeRateProvider,
+        from_currencies: &[CurrencyCode],
+        to_currency: CurrencyCode,
+        date: NaiveDate,
+    ) -> Result<HashMap<CurrencyCode, ExchangeRate>> {
+        let pivot_currency = match self.pivot_currency {
+            Some(pivot_currency) if pivot_currency != to_currency => pivot_currency,
+            _ => return Ok(HashMap::new()),
+        };
+        let from_currencies: Vec<CurrencyCode> = from_currencies
+            .iter()
+            .cloned()
+            .filter(|&currency| currency != pivot_currency)
+            .collect();
+        if from_currencies.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let mut pivot_pairs: HashSet<(CurrencyCode, CurrencyCode)> = from_currencies
+            .iter()
+            .map(|&currency| (currency, pivot_currency))
+            .collect();
+        pivot_pairs.insert((pivot_currency, to_currency));
+        let provider_response = exchange_rate_provider
+            .get_date_exchange_rates(date, &pivot_pairs)
+            .chain_err(|| {
+                format!(
+                    "Failed to get exchange rates via pivot currency {} from {}",
+                    pivot_currency,
+                    exchange_rate_provider.name()
+                )
+            })?;
+        let pivot_to_rate = match provider_response.get(&(pivot_currency, to_currency)) {
+            Some(rate) => rate.to_decimal(),
+            None => return Ok(HashMap::new()),
+        };
+        Ok(from_currencies
+            .into_iter()
+            .filter_map(|currency| {
+                provider_response
+                    .get(&(currency, pivot_currency))
+                    .map(|from_pivot_rate| {
+                        (
+                            currency,
+                            ExchangeRate::from_decimal(
+                                from_pivot_rate.to_decimal() * pivot_to_rate,
+                            ),
+                        )
+                    })
+            })
+            .collect())
+    }
+
+    fn get_fixed_exchange_rate(
+        &self,
+        from_currency: CurrencyCode,
+        to_currency: CurrencyCode,
+    ) -> Option<ExchangeRate> {
+        if let Some(&rate) = self.fixed_exchange_rates.get(&(from_currency, to_currency)) {
+            Some(rate)
+        } else if let Some(&rate) = self.fixed_exchange_rates.get(&(to_currency, from_currency)) {
+            Some(ExchangeRate::from_decimal(
+                Decimal::new(1, 0) / rate.to_decimal(),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Parse fixed exchange rates formatted `FROM_TO=RATE` (e.g. `EUR_XOF=655.957`).
+/// The inverse pair uses the reciprocal rate, so only one direction may be given.
+pub fn parse_fixed_exchange_rates<'b, I>(
+    values: I,
+) -> Result<HashMap<(CurrencyCode, CurrencyCode), ExchangeRate>>
+where
+    I: IntoIterator<Item = &'b str>,
+{
+    let mut results = HashMap::new();
+    for value in values {
+        if value.trim().is_empty() {
+            continue;
+        }
+        let (from_currency, to_currency, exchange_rate) = parse_fixed_exchange_rate(value)
+            .chain_err(|| format!("Invalid fixed exchange rate: {}", value))?;
+        ensure!(
+            !results.contains_key(&(from_currency, to_currency))
+                && !results.contains_key(&(to_currency, from_currency)),
+            "Fixed exchange rate between {} and {} is specified more than once",
+            from_currency,
+            to_currency
+        );
+        results.insert((from_currency, to_currency), exchange_rate);
+    }
+    Ok(results)
+}
+
+fn parse_fixed_exchange_rate(value: &str) -> Result<(CurrencyCode, CurrencyCode, ExchangeRate)> {
+    let mut pair_and_rate = value.splitn(2, '=');
+    let pair = pair_and_rate.next().unwrap_or_default();
+    let rate = pair_and_rate.next().chain_err(|| "Expected FROM_TO=RATE")?;
+    let mut currencies = pair.splitn(2, '_');
+    let from = currencies.next().unwrap_or_default();
+    let to = currencies
+        .next()
+        .chain_err(|| "Expected currency pair formatted FROM_TO")?;
+    parse_currencies_and_exchange_rate(from.trim(), to.trim(), rate.trim())
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct PrefetchedExchangeRates {
+    pub saved_count: usize,
+    pub missing_count: usize,
+}
+
+/// Get exchange rates for every date from `start_date` to `end_date` into the
+/// database ahead of time, skipping those that are already there.  Rates are
+/// saved as they are received, so running again after a failure (e.g. the
+/// provider's quota ran out) resumes where it left off.  Dates without a
+/// published rate get the nearest earlier rate within `fallback_days`, as
+/// they would during processing, except for dates after the last published
+/// rate, which may still get one and are counted as missing.
+#[allow(clippy::too_many_arguments)]
+pub fn prefetch_exchange_rates(
+    exchange_rate_provider: &dyn ExchangeRateProvider,
+    database: &Database,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+    from_currencies: &HashSet<CurrencyCode>,
+    to_currency: CurrencyCode,
+    fallback_days: u32,
+    rate_change_check: Option<RateChangeCheck>,
+) -> Result<PrefetchedExchangeRates> {
+    let mut result = PrefetchedExchangeRates::default();
+    let mut dates_missing_currencies: BTreeMap<NaiveDate, HashSet<CurrencyCode>> = BTreeMap::new();
+    let mut date = start_date;
+    while date <= end_date {
+        let known_rates = database.get_known_exchange_rates(from_currencies, to_currency, date)?;
+        dates_missing_currencies.insert(
+            date,
+            from_currencies
+                .iter()
+                .filter(|currency| !known_rates.contains_key(currency))
+                .cloned()
+                .collect(),
+        );
+        date = date.succ();
+    }
+    // Only request runs of consecutive dates that are missing rates, so that
+    // a partially complete range does not use up the provider's quota again.
+    let mut missing_date_runs: Vec<(NaiveDate, NaiveDate)> = Vec::new();
+    for (&date, missing_currencies) in &dates_missing_currencies {
+        if missing_currencies.is_empty() {
+            continue;
+        }
+        match missing_date_runs.last_mut() {
+            Some((_, run_end_date)) if run_end_date.succ() == date => *run_end_date = date,
+            _ => missing_date_runs.push((date, date)),
+        }
+    }
+    for (run_start_date, run_end_date) in missing_date_runs {
+        let pairs: HashSet<(CurrencyCode, CurrencyCode)> = dates_missing_currencies
+            .range(run_start_date..=run_end_date)
+            .flat_map(|(_, currencies)| currencies.iter().map(|&currency| (currency, to_currency)))
+            .collect();
+        exchange_rate_provider
+            .get_date_range_exchange_rates(
+                run_start_date,
+                run_end_date,
+                &pairs,
+                &mut |date, rates| {
+                    let missing_currencies = dates_missing_currencies
+                        .get_mut(&date)
+                        .chain_err(|| format!("Unexpected exchange rates date: {}", date))?;
+                    for ((from_currency, _), rate) in rates {
+                        if missing_currencies.remove(&from_currency) {
+                            if let Some(rate_change_check) = rate_change_check {
+                                check_rate_change(
+                                    database,
+                                    rate_change_check,
+                                    from_currency,
+                                    to_currency,
+                                    date,
+                                    rate,
+                                )?;
+                            }
+                            database.create_exchange_rate(
+                                from_currency,
+                                to_currency,
+                                date,
+                                ExchangeRateInfo::new(rate),
+                            )?;
+                            result.saved_count += 1;
+                        }
+                    }
+                    Ok(())
+                },
+            )
+            .chain_err(|| {
+                format!(
+                    "Failed to get exchange rates from {}",
+                    exchange_rate_provider.name()
+                )
+            })?;
+    }
+    for (&date, missing_currencies) in &dates_missing_currencies {
+        for &currency in missing_currencies {
+            if !database.has_later_published_exchange_rate(currency, to_currency, date)? {
+                result.missing_count += 1;
+                continue;
+            }
+            let mut fallback_date = date;
+            let mut opt_rate_info = None;
+            for _ in 0..fallback_days {
+                fallback_date = fallback_date.pred();
+                if let Some(fallback_rate_info) =
+                    database.get_exchange_rate(currency, to_currency, fallback_date)?
+                {
+                    opt_rate_info = Some(ExchangeRateInfo {
+                        fallback_date: fallback_rate_info.fallback_date.or(Some(fallback_date)),
+                        ..fallback_rate_info
+                    });
+                    break;
+                }
+            }
+            match opt_rate_info {
+                Some(rate_info) => {
+                    database.create_exchange_rate(currency, to_currency, date, rate_info)?;
+                    result.saved_count += 1;
+                }
+                None => result.missing_count += 1,
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Check a newly received exchange rate against the previous published rate
+/// for the same currencies, failing if it changed by more than allowed (unless
+/// the user confirms it).
+fn check_rate_change(
+    database: &Database,
+    rate_change_check: RateChangeCheck,
+    from_currency: CurrencyCode,
+    to_currency: CurrencyCode,
+    date: NaiveDate,
+    new_rate: ExchangeRate,
+) -> Result<()> {
+    let (known_date, known_rate_info) =
+        match database.get_previous_published_exchange_rate(from_currency, to_currency, date)? {
+            Some(previous) => previous,
+            None => return Ok(()),
+        };
+    let known_rate = known_rate_info.exchange_rate.to_decimal();
+    if known_rate.is_zero() {
+        return Ok(());
+    }
+    let change_percent = ((new_rate.to_decimal() - known_rate) / known_rate * Decimal::new(100, 0))
+        .abs()
+        .round_dp(1);
+    if change_percent <= rate_change_check.max_change_percent {
+        return Ok(());
+    }
+    let description = format!(
+        "Exchange rate from {} to {} for {} ({}) differs from the previous rate for {} ({}) by {}%",
+        from_currency,
+        to_currency,
+        format_iso_date(date),
+        new_rate,
+        format_iso_date(known_date),
+        known_rate_info.exchange_rate,
+        change_percent
+    );
+    if rate_change_check.action == RateChangeAction::Confirm {
+        progress!("{}.", description);
+        if prompt_yes_no("Use this exchange rate anyway?")? {
+            return Ok(());
+        }
+    }
+    bail!(
+        "{}, which is more than {}%.  If the rate is correct, set an exchange rate override for the date.",
+        description,
+        rate_change_check.max_change_percent
+    )
+}
+
+/// Read exchange rates from a CSV file with `date,from,to,rate` rows (and an
+/// optional header row).
+pub fn read_exchange_rates_csv(path: &str) -> Result<Vec<DatedExchangeRate>> {
+    let csv = fs::read_to_string(path)
+        .chain_err(|| format!("Failed to read exchange rates file: {}", path))?;
+    parse_exchange_rates_csv(&csv).chain_err(|| format!("Invalid exchange rates file: {}", path))
+}
+
+fn parse_exchange_rates_csv(csv: &str) -> Result<Vec<DatedExchangeRate>> {
+    let mut results = Vec::new();
+    for (line_index, line) in csv.trim_start_matches('\u{feff}').lines().enumerate() {
+        let fields: Vec<&str> = line
+            .split(',')
+            .map(|field| field.trim().trim_matches('"'))
+            .collect();
+        if fields.iter().all(|field| field.is_empty())
+            || (line_index == 0 && fields[0].eq_ignore_ascii_case("date"))
+        {
+            continue;
+        }
+        results.push(
+            parse_exchange_rate_csv_fields(&fields)
+                .chain_err(|| format!("Invalid exchange rate on line {}", line_index + 1))?,
+        );
+    }
+    Ok(results)
+}
+
+fn parse_exchange_rate_csv_fields(fields: &[&str]) -> Result<DatedExchangeRate> {
+    match fields {
+        [date, from, to, rate] => parse_dated_exchange_rate(date, from, to, rate),
+        _ => bail!("Expected four fields (date,from,to,rate)"),
+    }
+}
+
+pub fn parse_dated_exchange_rate(
+    date: &str,
+    from: &str,
+    to: &str,
+    rate: &str,
+) -> Result<DatedExchangeRate> {
+    let (from_currency, to_currency, exchange_rate) =
+        parse_currencies_and_exchange_rate(from, to, rate)?;
+    Ok(DatedExchangeRate {
+        date: parse_iso_date(date)?,
+        from_currency,
+        to_currency,
+        exchange_rate,
+    })
+}
+
+fn parse_currencies_and_exchange_rate(
+    from: &str,
+    to: &str,
+    rate: &str,
+) -> Result<(CurrencyCode, CurrencyCode, ExchangeRate)> {
+    let from_currency = CurrencyCode::from_str(&from.to_uppercase())?;
+    let to_currency = CurrencyCode::from_str(&to.to_uppercase())?;
+    ensure!(
+        from_currency != to_currency,
+        "From and to currencies must be different"
+    );
+    let exchange_rate =
+        ExchangeRate::from_str(rate).chain_err(|| format!("Invalid exchange rate: {}", rate))?;
+    ensure!(
+        exchange_rate.to_decimal() > Decimal::zero(),
+        "Exchange rate must be positive: {}",
+        rate
+    );
+    Ok((from_currency, to_currency, exchange_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_exchange_rates_cache_fixed_and_pivot() {
+        with_test_database("cache", |database| {
+            let date = NaiveDate::from_ymd(2020, 1, 2);
+            let provider = TestExchangeRateProvider {
+                dates: vec![date],
+                rates: vec![
+                    ((code("GBP"), code("EUR")), rate("1.15")),
+                    ((code("XYZ"), code("USD")), rate("2")),
+                    ((code("USD"), code("EUR")), rate("0.9")),
+                ]
+                .into_iter()
+                .collect(),
+            };
+            let cache = ExchangeRatesCache::new(
+                ExchangeRateSource::Provider(&provider),
+                database,
+                parse_fixed_exchange_rates(vec!["EUR_XOF=655.957"]).unwrap(),
+                Some(code("USD")),
+                0,
+                None,
+            );
+            let currencies = [code("GBP"), code("XYZ"), code("XOF")]
+                .iter()
+                .cloned()
+                .collect();
+            let get_rate = |currency| {
+                cache
+                    .get_exchange_rate(&currencies, code(currency), code("EUR"), date)
+                    .unwrap()
+                    .unwrap()
+            };
+            assert_eq!(get_rate("GBP"), ExchangeRateInfo::new(rate("1.15")));
+            assert_eq!(
+                get_rate("XYZ"),
+                ExchangeRateInfo {
+                    pivot_currency: Some(code("USD")),
+                    ..ExchangeRateInfo::new(rate("1.8"))
+                }
+            );
+            assert_eq!(
+                get_rate("XOF"),
+                ExchangeRateInfo::new(rate("0.001524490172"))
+            );
+            assert_eq!(
+                database
+                    .get_exchange_rate(code("XYZ"), code("EUR"), date)
+                    .unwrap()
+                    .map(|rate_info| rate_info.pivot_currency),
+                Some(Some(code("USD")))
+            );
+            assert_eq!(
+                database
+                    .get_exchange_rate(code("XOF"), code("EUR"), date)
+                    .unwrap(),
+                None
+            );
+        });
+    }
+
+    #[test]
+    fn test_exchange_rates_cache_fallback() {
+        with_test_database("fallback", |database| {
+            let friday = NaiveDate::from_ymd(2020, 1, 3);
+            let sunday = NaiveDate::from_ymd(2020, 1, 5);
+            let monday = NaiveDate::from_ymd(2020, 1, 6);
+            let provider = TestExchangeRateProvider {
+                dates: vec![friday],
+                rates: vec![((code("GBP"), code("EUR")), rate("1.15"))]
+                    .into_iter()
+                    .collect(),
+            };
+            let currencies = [code("GBP")].iter().cloned().collect();
+            let no_fallback_cache = ExchangeRatesCache::new(
+                ExchangeRateSource::Provider(&provider),
+                database,
+                HashMap::new(),
+                None,
+                1,
+                None,
+            );
+            assert!(no_fallback_cache
+                .get_exchange_rate(&currencies, code("GBP"), code("EUR"), sunday)
+                .is_err());
+            let new_cache = || {
+                ExchangeRatesCache::new(
+                    ExchangeRateSource::Provider(&provider),
+                    database,
+                    HashMap::new(),
+                    None,
+                    4,
+                    None,
+                )
+            };
+            let expected = ExchangeRateInfo {
+                fallback_date: Some(friday),
+                ..ExchangeRateInfo::new(rate("1.15"))
+            };
+            assert_eq!(
+                new_cache()
+                    .get_exchange_rate(&currencies, code("GBP"), code("EUR"), sunday)
+                    .unwrap(),
+                Some(expected)
+            );
+            // Monday's rate is not published yet, so Sunday might still get
+            // one and the fallback is not saved.
+            assert_eq!(
+                database
+                    .get_exchange_rate(code("GBP"), code("EUR"), sunday)
+                    .unwrap(),
+                None
+            );
+            database
+                .create_exchange_rate(
+                    code("GBP"),
+                    code("EUR"),
+                    monday,
+                    ExchangeRateInfo::new(rate("1.16")),
+                )
+                .unwrap();
+            assert_eq!(
+                new_cache()
+                    .get_exchange_rate(&currencies, code("GBP"), code("EUR"), sunday)
+                    .unwrap(),
+                Some(expected)
+            );
+            assert_eq!(
+                database
+                    .get_exchange_rate(code("GBP"), code("EUR"), sunday)
+                    .unwrap(),
+                Some(expected)
+            );
+            assert_eq!(
+                database
+                    .get_exchange_rate(code("GBP"), code("EUR"), friday)
+                    .unwrap(),
+                Some(ExchangeRateInfo::new(rate("1.15")))
+            );
+        });
+    }
+
+    #[test]
+    fn test_exchange_rates_cache_rate_change_check() {
+        with_test_database("rate-change", |database| {
+            let tuesday = NaiveDate::from_ymd(2019, 12, 31);
+            let friday = NaiveDate::from_ymd(2020, 1, 3);
+            let saturday = NaiveDate::from_ymd(2020, 1, 4);
+            database
+                .create_exchange_rate(
+                    code("GBP"),
+                    code("EUR"),
+                    tuesday,
+                    ExchangeRateInfo::new(rate("1.15")),
+                )
+                .unwrap();
+            // Closer, but later than the new rate, so not what it is compared
+            // with.
+            database
+                .create_exchange_rate(
+                    code("GBP"),
+                    code("EUR"),
+                    saturday,
+                    ExchangeRateInfo::new(rate("1.5")),
+                )
+                .unwrap();
+            let provider = TestExchangeRateProvider {
+                dates: vec![friday],
+                rates: vec![((code("GBP"), code("EUR")), rate("1.5"))]
+                    .into_iter()
+                    .collect(),
+            };
+            let currencies = [code("GBP")].iter().cloned().collect();
+            let new_cache = |max_change_percent| {
+                ExchangeRatesCache::new(
+                    ExchangeRateSource::Provider(&provider),
+                    database,
+                    HashMap::new(),
+                    None,
+                    0,
+                    Some(RateChangeCheck {
+                        max_change_percent: Decimal::new(max_change_percent, 0),
+                        action: RateChangeAction::Refuse,
+                    }),
+                )
+            };
+            // A read-only cache neither checks nor saves the new rate.
+            assert_eq!(
+                new_cache(10)
+                    .read_only()
+                    .get_exchange_rate(&currencies, code("GBP"), code("EUR"), friday)
+                    .unwrap(),
+                Some(ExchangeRateInfo::new(rate("1.5")))
+            );
+            assert_eq!(
+                database
+                    .get_exchange_rate(code("GBP"), code("EUR"), friday)
+                    .unwrap(),
+                None
+            );
+            let error = new_cache(10)
+                .get_exchange_rate(&currencies, code("GBP"), code("EUR"), friday)
+                .unwrap_err()
+                .to_string();
+            assert!(error.contains("previous rate for 2019-12-31 (1.15) by 30.4%"));
+            assert_eq!(
+                database
+                    .get_exchange_rate(code("GBP"), code("EUR"), friday)
+                    .unwrap(),
+                None
+            );
+            assert_eq!(
+                new_cache(50)
+                    .get_exchange_rate(&currencies, code("GBP"), code("EUR"), friday)
+                    .unwrap(),
+                Some(ExchangeRateInfo::new(rate("1.5")))
+            );
+        });
+    }
+
+    #[test]
+    fn test_exchange_rates_cache_offline() {
+        with_test_database("offline", |database| {
+            let friday = NaiveDate::from_ymd(2020, 1, 3);
+            let monday = NaiveDate::from_ymd(2020, 1, 6);
+            database
+                .create_exchange_rate(
+                    code("GBP"),
+                    code("EUR"),
+                    friday,
+                    ExchangeRateInfo::new(rate("1.15")),
+                )
+                .unwrap();
+            let currencies = [code("GBP"), code("USD")].iter().cloned().collect();
+            let nearest_cache = ExchangeRatesCache::new(
+                ExchangeRateSource::Offline(MissingRatePolicy::Nearest),
+                database,
+                HashMap::new(),
+                None,
+                0,
+                None,
+            );
+            assert_eq!(
+                nearest_cache
+                    .get_exchange_rate(&currencies, code("GBP"), code("EUR"), monday)
+                    .unwrap(),
+                Some(ExchangeRateInfo {
+                    fallback_date: Some(friday),
+                    ..ExchangeRateInfo::new(rate("1.15"))
+                })
+            );
+            assert_eq!(
+                database
+                    .get_exchange_rate(code("GBP"), code("EUR"), monday)
+                    .unwrap(),
+                None
+            );
+            assert!(nearest_cache.check_missing_exchange_rates().is_ok());
+            let fail_cache = ExchangeRatesCache::new(
+                ExchangeRateSource::Offline(MissingRatePolicy::Fail),
+                database,
+                HashMap::new(),
+                None,
+                0,
+                None,
+            );
+            for &(currency, date) in &[
+                (code("GBP"), friday),
+                (code("GBP"), monday),
+                (code("USD"), monday),
+            ] {
+                fail_cache
+                    .get_exchange_rate(&currencies, currency, code("EUR"), date)
+                    .unwrap();
+            }
+            let error = fail_cache
+                .check_missing_exchange_rates()
+                .unwrap_err()
+                .to_string();
+            assert!(!error.contains("2020-01-03"));
+            assert!(error.contains("2020-01-06 GBP to EUR"));
+            assert!(error.contains("2020-01-06 USD to EUR"));
+        });
+    }
+
+    #[test]
+    fn test_prefetch_exchange_rates() {
+        with_test_database("prefetch", |database| {
+            let friday = NaiveDate::from_ymd(2020, 1, 3);
+            let sunday = NaiveDate::from_ymd(2020, 1, 5);
+            let monday = NaiveDate::from_ymd(2020, 1, 6);
+            let wednesday = NaiveDate::from_ymd(2020, 1, 8);
+            let provider = TestExchangeRateProvider {
+                dates: vec![friday, monday],
+                rates: vec![((code("GBP"), code("EUR")), rate("1.15"))]
+                    .into_iter()
+                    .collect(),
+            };
+            let currencies = [code("GBP"), code("USD")].iter().cloned().collect();
+            let prefetch = || {
+                prefetch_exchange_rates(
+                    &provider,
+                    database,
+                    friday,
+                    wednesday,
+                    &currencies,
+                    code("EUR"),
+                    4,
+                    None,
+                )
+                .unwrap()
+            };
+            assert_eq!(
+                prefetch(),
+                PrefetchedExchangeRates {
+                    saved_count: 4,
+                    missing_count: 8,
+                }
+            );
+            assert_eq!(
+                database
+                    .get_exchange_rate(code("GBP"), code("EUR"), sunday)
+                    .unwrap(),
+                Some(ExchangeRateInfo {
+                    fallback_date: Some(friday),
+                    ..ExchangeRateInfo::new(rate("1.15"))
+                })
+            );
+            assert_eq!(
+                prefetch(),
+                PrefetchedExchangeRates {
+                    saved_count: 0,
+                    missing_count: 8,
+                }
+            );
+            // Tuesday and Wednesday have not been published yet (as far as
+            // is known), so they do not get Monday's rate.
+            assert_eq!(
+                database
+                    .get_exchange_rate(code("GBP"), code("EUR"), wednesday)
+                    .unwrap(),
+                None
+            );
+        });
+    }
+
+    #[test]
+    fn test_parse_exchange_rates_csv() {
+        let rates = parse_exchange_rates_csv(
+            "date,from,to,rate\r\n2019-12-31,EUR,USD,1.123456789\r\n\r\n2019-11-30, \"GBP\" ,USD,1.29\r\n",
+        )
+        .unwrap();
+        assert_eq!(rates.len(), 2);
+        assert_eq!(rates[0].date, NaiveDate::from_ymd(2019, 12, 31));
+        assert_eq!(rates[0].from_currency, code("EUR"));
+        assert_eq!(rates[0].to_currency, code("USD"));
+        assert_eq!(rates[0].exchange_rate, rate("1.123456789"));
+        assert_eq!(rates[1].from_currency, code("GBP"));
+        assert_eq!(rates[1].exchange_rate, rate("1.29"));
+    }
+
+    #[test]
+    fn test_parse_exchange_rates_csv_errors() {
+        assert!(parse_exchange_rates_csv("2019-12-31,EUR,USD").is_err());
+        assert!(parse_exchange_rates_csv("2019-12-31,EUR,USD,abc").is_err());
+        assert!(parse_exchange_rates_csv("2019-12-31,EUR,USD,-1.1").is_err());
+        assert!(parse_exchange_rates_csv("2019-12-31,EUR,EUR,1").is_err());
+        assert!(parse_exchange_rates_csv("31/12/2019,EUR,USD,1.1").is_err());
+    }
+
+    #[test]
+    fn test_parse_dated_exchange_rate() {
+        let dated_rate = parse_dated_exchange_rate("2020-01-02", "eur", "usd", "1.1198").unwrap();
+        assert_eq!(dated_rate.from_currency, code("EUR"));
+        assert_eq!(dated_rate.to_currency, code("USD"));
+        assert_eq!(dated_rate.exchange_rate, rate("1.1198"));
+        assert!(parse_dated_exchange_rate("2020-01-02", "EUR", "USD", "0").is_err());
+    }
+
+    #[test]
+    fn test_parse_fixed_exchange_rates() {
+        let rates =
+            parse_fixed_exchange_rates(vec!["EUR_XOF=655.957", "hkd_usd=0.128205"]).unwrap();
+        assert_eq!(rates.len(), 2);
+        assert_eq!(rates[&(code("EUR"), code("XOF"))], rate("655.957"));
+        assert!(parse_fixed_exchange_rates(vec!["EUR_XOF=655.957", "XOF_EUR=0.001524"]).is_err());
+        assert!(parse_fixed_exchange_rates(vec!["EURXOF=655.957"]).is_err());
+        assert!(parse_fixed_exchange_rates(vec!["EUR_XOF"]).is_err());
+        assert!(parse_fixed_exchange_rates(vec![""]).unwrap().is_empty());
     }
 }
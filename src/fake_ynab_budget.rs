@@ -0,0 +1,480 @@
+use chrono::NaiveDate;
+use std::cell::RefCell;
+use ynab_api::models;
+
+use crate::errors::*;
+use crate::utilities::*;
+use crate::ynab_client::*;
+
+/// Convert between the API's per-model enums, which all use the same strings.
+macro_rules! convert_enum {
+    ($value:expr) => {
+        serde_json::from_value(serde_json::to_value($value).expect("Enum should serialize"))
+            .expect("Enum should deserialize")
+    };
+}
+
+/// An in-memory YNAB budget for tests.  Like the real API, it keeps track of
+/// server knowledge so that only changed transactions are returned, turns
+/// transactions with an account's transfer payee into transfers, and skips
+/// new transactions whose import ID already exists.
+pub struct FakeYnabBudget {
+    settings: models::BudgetSettings,
+    state: RefCell<FakeYnabBudgetState>,
+}
+
+struct FakeYnabBudgetState {
+    server_knowledge: i64,
+    next_id: u32,
+    accounts: Vec<models::Account>,
+    /// Each transaction, with the server knowledge when it last changed.
+    transactions: Vec<(i64, models::TransactionDetail)>,
+}
+
+impl FakeYnabBudget {
+    pub const BUDGET_ID: &'static str = "fake-budget";
+
+    pub fn new(iso_code: &str) -> FakeYnabBudget {
+        FakeYnabBudget {
+            settings: models::BudgetSettings {
+                date_format: models::DateFormat {
+                    format: "YYYY-MM-DD".to_string(),
+                },
+                currency_format: models::CurrencyFormat {
+                    iso_code: iso_code.to_string(),
+                    example_format: "123,456.78".to_string(),
+                    decimal_digits: 2,
+                    decimal_separator: ".".to_string(),
+                    symbol_first: true,
+                    group_separator: ",".to_string(),
+                    currency_symbol: "$".to_string(),
+                    display_symbol: true,
+                },
+            },
+            state: RefCell::new(FakeYnabBudgetState {
+                server_knowledge: 0,
+                next_id: 1,
+                accounts: Vec::new(),
+                transactions: Vec::new(),
+            }),
+        }
+    }
+
+    /// Load a budget from a JSON fixture with `settings`, `accounts`,
+    /// `transactions` and `server_knowledge` keys, in the API's format.
+    /// Account balances are computed from the transactions.
+    pub fn from_fixture(fixture: &str) -> Result<FakeYnabBudget> {
+        let mut fixture =
+            serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(fixture)
+                .chain_err(|| "Fixture is not a JSON object")?;
+        let mut field = |name: &str| fixture.remove(name).unwrap_or(serde_json::Value::Null);
+        let settings = serde_json::from_value::<models::BudgetSettings>(field("settings"))
+            .chain_err(|| "Invalid settings in fixture")?;
+        let accounts = serde_json::from_value::<Vec<models::Account>>(field("accounts"))
+            .chain_err(|| "Invalid accounts in fixture")?;
+        let transactions =
+            serde_json::from_value::<Vec<models::TransactionDetail>>(field("transactions"))
+                .chain_err(|| "Invalid transactions in fixture")?;
+        let server_knowledge = serde_json::from_value::<i64>(field("server_knowledge"))
+            .chain_err(|| "Invalid server_knowledge in fixture")?;
+        Ok(FakeYnabBudget {
+            settings,
+            state: RefCell::new(FakeYnabBudgetState {
+                server_knowledge,
+                next_id: 1,
+                accounts,
+                transactions: transactions
+                    .into_iter()
+                    .map(|transaction| (server_knowledge, transaction))
+                    .collect(),
+            }),
+        })
+    }
+
+    pub fn server_knowledge(&self) -> i64 {
+        self.state.borrow().server_knowledge
+    }
+
+    pub fn add_account(&self, name: &str, account_type: models::account::Type) -> String {
+        let mut state = self.state.borrow_mut();
+        let id = state.next_id("account");
+        state.accounts.push(models::Account {
+            id: id.clone(),
+            name: name.to_string(),
+            _type: account_type,
+            on_budget: true,
+            closed: false,
+            note: None,
+            balance: 0,
+            cleared_balance: 0,
+            uncleared_balance: 0,
+            transfer_payee_id: format!("transfer-payee-{}", id),
+            deleted: false,
+        });
+        id
+    }
+
+    /// Add a transaction as if the user entered it in YNAB.
+    pub fn add_transaction(
+        &self,
+        account_id: &str,
+        date: NaiveDate,
+        amount: i64,
+        memo: Option<&str>,
+    ) -> String {
+        self.create_transaction(account_id, date, amount, None, memo, None, Vec::new())
+    }
+
+    /// Add a split transaction with the given subtransaction amounts.
+    pub fn add_split_transaction(
+        &self,
+        account_id: &str,
+        date: NaiveDate,
+        amounts: &[i64],
+    ) -> String {
+        let subtransactions = amounts
+            .iter()
+            .map(|&amount| models::SubTransaction {
+                id: self.state.borrow_mut().next_id("subtransaction"),
+                transaction_id: String::new(),
+                amount,
+                memo: None,
+                payee_id: None,
+                category_id: None,
+                transfer_account_id: None,
+                deleted: false,
+            })
+            .collect();
+        self.create_transaction(
+            account_id,
+            date,
+            amounts.iter().sum(),
+            None,
+            None,
+            None,
+            subtransactions,
+        )
+    }
+
+    /// Add a transfer of `amount` from one account to another, returning the
+    /// transactions in the from and to accounts.
+    pub fn add_transfer(
+        &self,
+        from_account_id: &str,
+        to_account_id: &str,
+        date: NaiveDate,
+        amount: i64,
+    ) -> (String, String) {
+        let transfer_payee_id = self.account(to_account_id).transfer_payee_id;
+        let from_transaction_id = self.create_transaction(
+            from_account_id,
+            date,
+            -amount,
+            Some(transfer_payee_id),
+            None,
+            None,
+            Vec::new(),
+        );
+        let to_transaction_id = self
+            .transaction(&from_transaction_id)
+            .transfer_transaction_id
+            .expect("Transfer should have a transfer transaction");
+        (from_transaction_id, to_transaction_id)
+    }
+
+    /// Delete a transaction (and the other side of a transfer), as if the user
+    /// deleted it in YNAB.
+    pub fn delete_transaction(&self, id: &str) {
+        let mut state = self.state.borrow_mut();
+        let transfer_transaction_id = state.transaction_mut(id).1.transfer_transaction_id.clone();
+        for id in Some(id.to_string())
+            .iter()
+            .chain(transfer_transaction_id.iter())
+        {
+            let server_knowledge = state.next_server_knowledge();
+            let (changed_server_knowledge, transaction) = state.transaction_mut(id);
+            *changed_server_knowledge = server_knowledge;
+            transaction.deleted = true;
+        }
+    }
+
+    pub fn account(&self, id: &str) -> models::Account {
+        let state = self.state.borrow();
+        let mut account = state
+            .accounts
+            .iter()
+            .find(|account| account.id == id)
+            .expect("Fake budget should have account")
+            .clone();
+        account.balance = state
+            .transactions
+            .iter()
+            .filter(|(_, transaction)| transaction.account_id == id && !transaction.deleted)
+            .map(|(_, transaction)| transaction.amount)
+            .sum();
+        account
+    }
+
+    pub fn transaction(&self, id: &str) -> models::TransactionDetail {
+        self.state.borrow_mut().transaction_mut(id).1.clone()
+    }
+
+    /// The transactions in an account that have not been deleted.
+    pub fn account_transactions(&self, account_id: &str) -> Vec<models::TransactionDetail> {
+        self.state
+            .borrow()
+            .transactions
+            .iter()
+            .filter(|(_, transaction)| transaction.account_id == account_id && !transaction.deleted)
+            .map(|(_, transaction)| transaction.clone())
+            .collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create_transaction(
+        &self,
+        account_id: &str,
+        date: NaiveDate,
+        amount: i64,
+        payee_id: Option<String>,
+        memo: Option<&str>,
+        import_id: Option<String>,
+        mut subtransactions: Vec<models::SubTransaction>,
+    ) -> String {
+        let account = self.account(account_id);
+        let transfer_account = payee_id.as_ref().and_then(|payee_id| {
+            self.state
+                .borrow()
+                .accounts
+                .iter()
+                .find(|account| &account.transfer_payee_id == payee_id)
+                .cloned()
+        });
+        let mut state = self.state.borrow_mut();
+        let id = state.next_id("transaction");
+        for subtransaction in &mut subtransactions {
+            subtransaction.transaction_id = id.clone();
+        }
+        let transfer_transaction_id = transfer_account
+            .as_ref()
+            .map(|_| state.next_id("transaction"));
+        let server_knowledge = state.next_server_knowledge();
+        state.transactions.push((
+            server_knowledge,
+            models::TransactionDetail {
+                id: id.clone(),
+                date: format_iso_date(date),
+                amount,
+                memo: memo.map(str::to_string),
+                cleared: models::transaction_detail::Cleared::Uncleared,
+                approved: false,
+                flag_color: None,
+                account_id: account_id.to_string(),
+                payee_id,
+                category_id: None,
+                transfer_account_id: transfer_account.as_ref().map(|account| account.id.clone()),
+                transfer_transaction_id: transfer_transaction_id.clone(),
+                matched_transaction_id: None,
+                import_id,
+                deleted: false,
+                account_name: account.name.clone(),
+                payee_name: None,
+                category_name: None,
+                subtransactions,
+            },
+        ));
+        if let (Some(transfer_account), Some(transfer_transaction_id)) =
+            (transfer_account, transfer_transaction_id)
+        {
+            state.transactions.push((
+                server_knowledge,
+                models::TransactionDetail {
+                    id: transfer_transaction_id,
+                    date: format_iso_date(date),
+                    amount: -amount,
+                    memo: memo.map(str::to_string),
+                    cleared: models::transaction_detail::Cleared::Uncleared,
+                    approved: false,
+                    flag_color: None,
+                    account_id: transfer_account.id.clone(),
+                    payee_id: Some(account.transfer_payee_id),
+                    category_id: None,
+                    transfer_account_id: Some(account.id),
+                    transfer_transaction_id: Some(id.clone()),
+                    matched_transaction_id: None,
+                    import_id: None,
+                    deleted: false,
+                    account_name: transfer_account.name,
+                    payee_name: None,
+                    category_name: None,
+                    subtransactions: Vec::new(),
+                },
+            ));
+        }
+        id
+    }
+}
+
+impl FakeYnabBudgetState {
+    fn next_id(&mut self, kind: &str) -> String {
+        let id = format!("{}-{}", kind, self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn next_server_knowledge(&mut self) -> i64 {
+        self.server_knowledge += 1;
+        self.server_knowledge
+    }
+
+    fn transaction_mut(&mut self, id: &str) -> &mut (i64, models::TransactionDetail) {
+        self.transactions
+            .iter_mut()
+            .find(|(_, transaction)| transaction.id == id)
+            .expect("Fake budget should have transaction")
+    }
+}
+
+impl YnabBudget for FakeYnabBudget {
+    fn budget_id(&self) -> &str {
+        Self::BUDGET_ID
+    }
+
+    fn get_budget_settings(&self) -> Result<models::BudgetSettings> {
+        Ok(self.settings.clone())
+    }
+
+    fn get_accounts(&self) -> Result<Vec<models::Account>> {
+        let account_ids = self
+            .state
+            .borrow()
+            .accounts
+            .iter()
+            .map(|account| account.id.clone())
+            .collect::<Vec<_>>();
+        Ok(account_ids.iter().map(|id| self.account(id)).collect())
+    }
+
+    fn get_transactions(
+        &self,
+        start_date: Option<NaiveDate>,
+        server_knowledge: Option<i64>,
+    ) -> Result<models::TransactionsResponseData> {
+        let state = self.state.borrow();
+        let start_date = start_date.map(format_iso_date);
+        Ok(models::TransactionsResponseData {
+            transactions: state
+                .transactions
+                .iter()
+                .filter(|(changed_server_knowledge, transaction)| {
+                    start_date
+                        .as_ref()
+                        .map_or(true, |start_date| &transaction.date >= start_date)
+                        && match server_knowledge {
+                            Some(server_knowledge) => *changed_server_knowledge > server_knowledge,
+                            None => !transaction.deleted,
+                        }
+                })
+                .map(|(_, transaction)| transaction.clone())
+                .collect(),
+            server_knowledge: state.server_knowledge,
+        })
+    }
+
+    fn create_transactions(
+        &self,
+        transactions: Vec<models::SaveTransaction>,
+    ) -> Result<Vec<models::TransactionDetail>> {
+        let mut created_transactions = Vec::new();
+        for transaction in transactions {
+            if let Some(import_id) = &transaction.import_id {
+                let is_duplicate = self
+                    .state
+                    .borrow()
+                    .transactions
+                    .iter()
+                    .any(|(_, existing)| existing.import_id.as_ref() == Some(import_id));
+                if is_duplicate {
+                    continue;
+                }
+            }
+            let id = self.create_transaction(
+                &transaction.account_id,
+                parse_iso_date(&transaction.date)?,
+                transaction.amount,
+                transaction.payee_id,
+                transaction.memo.as_ref().map(String::as_str),
+                transaction.import_id,
+                Vec::new(),
+            );
+            let mut state = self.state.borrow_mut();
+            let (_, created_transaction) = state.transaction_mut(&id);
+            created_transaction.payee_name = transaction.payee_name;
+            created_transaction.category_id = transaction.category_id;
+            if let Some(cleared) = transaction.cleared {
+                created_transaction.cleared = convert_enum!(cleared);
+            }
+            created_transaction.approved = transaction.approved.unwrap_or(false);
+            created_transaction.flag_color = transaction
+                .flag_color
+                .map(|flag_color| convert_enum!(flag_color));
+            created_transactions.push(created_transaction.clone());
+        }
+        Ok(created_transactions)
+    }
+
+    fn update_transactions(
+        &self,
+        transactions: Vec<models::UpdateTransaction>,
+    ) -> Result<Vec<models::TransactionDetail>> {
+        let mut state = self.state.borrow_mut();
+        let mut updated_transactions = Vec::new();
+        for transaction in transactions {
+            ensure!(
+                state
+                    .transactions
+                    .iter()
+                    .any(|(_, existing)| existing.id == transaction.id),
+                "Fake budget has no transaction: {}",
+                transaction.id
+            );
+            let server_knowledge = state.next_server_knowledge();
+            let (changed_server_knowledge, updated_transaction) =
+                state.transaction_mut(&transaction.id);
+            ensure!(
+                !updated_transaction.deleted,
+                "Fake budget cannot update deleted transaction: {}",
+                transaction.id
+            );
+            ensure!(
+                updated_transaction.account_id == transaction.account_id,
+                "Fake budget does not support moving transactions: {}",
+                transaction.id
+            );
+            *changed_server_knowledge = server_knowledge;
+            updated_transaction.date = transaction.date;
+            updated_transaction.amount = transaction.amount;
+            updated_transaction.category_id = transaction.category_id;
+            updated_transaction.memo = transaction.memo;
+            if let Some(cleared) = transaction.cleared {
+                updated_transaction.cleared = convert_enum!(cleared);
+            }
+            if let Some(approved) = transaction.approved {
+                updated_transaction.approved = approved;
+            }
+            updated_transaction.flag_color = transaction
+                .flag_color
+                .map(|flag_color| convert_enum!(flag_color));
+            let updated_transaction = updated_transaction.clone();
+            if let Some(transfer_transaction_id) = &updated_transaction.transfer_transaction_id {
+                let (changed_server_knowledge, transfer_transaction) =
+                    state.transaction_mut(transfer_transaction_id);
+                *changed_server_knowledge = server_knowledge;
+                transfer_transaction.date = updated_transaction.date.clone();
+                transfer_transaction.amount = -updated_transaction.amount;
+            }
+            updated_transactions.push(updated_transaction);
+        }
+        Ok(updated_transactions)
+    }
+}
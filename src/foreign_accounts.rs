@@ -4,6 +4,7 @@ use std::collections::{HashMap, HashSet};
 
 use crate::budget_formatter::*;
 use crate::constants::*;
+use crate::currencies::*;
 use crate::errors::*;
 use crate::types::*;
 use crate::utilities::*;
@@ -13,6 +14,8 @@ use crate::ynab_client::*;
 pub struct ForeignAccounts<'a> {
     all_used_foreign_currencies: HashSet<CurrencyCode>,
     account_id_data: HashMap<YnabAccountId<'a>, AccountData>,
+    account_rate_date_policies: HashMap<YnabAccountId<'a>, RateDatePolicy>,
+    account_fees: HashMap<YnabAccountId<'a>, AccountFee>,
     difference_account_ids: HashMap<DifferenceKey, YnabAccountId<'a>>,
 }
 
@@ -23,6 +26,9 @@ pub struct DifferenceBalances {
 pub struct ForeignTotalAndDifferenceBalance {
     pub foreign_accounts_total: Milliunits,
     pub difference_account_balance: Milliunits,
+    /// Total fees included in the difference account balance, which
+    /// adjustments must leave in place.
+    pub fees_total: Milliunits,
 }
 
 #[derive(Debug)]
@@ -34,14 +40,17 @@ pub enum AccountData {
 
 impl<'a> ForeignAccounts<'a> {
     pub fn load(
-        ynab_client: &YnabBudgetClient,
+        ynab_client: &dyn YnabBudget,
         budget_formatter: &BudgetFormatter,
         local_currency: CurrencyCode,
+        currency_registry: &CurrencyRegistry,
     ) -> Result<(ForeignAccounts<'a>, DifferenceBalances)> {
         let mut all_used_foreign_currencies = HashSet::new();
         let mut account_id_data = HashMap::new();
+        let mut account_rate_date_policies = HashMap::new();
+        let mut account_fees = HashMap::new();
         let mut difference_account_ids = HashMap::new();
-        println!("Getting accounts from YNAB...");
+        progress!("Getting accounts from YNAB...");
         let raw_accounts = ynab_client.get_accounts()?;
         debug!("Accounts received from YNAB: {:#?}", &raw_accounts);
         for account in &raw_accounts {
@@ -50,8 +59,26 @@ impl<'a> ForeignAccounts<'a> {
                     force_convert: account_matches_regex(&FORCE_CONVERT_REGEX, &account),
                 };
                 let account_id = YnabAccountId::new(account.id.clone());
-                let opt_foreign_account_key = Self::foreign_account_key(&account)?;
-                let opt_difference_account_key = Self::difference_account_key(&account)?;
+                // Other words in angle brackets (e.g. `<TODO>`) are not
+                // currency tags, but they may also be typos, so mention them.
+                let is_known_currency = |currency_code: &str| {
+                    let is_known = CurrencyCode::from_str(currency_code)
+                        .map(|currency| currency_registry.contains(currency))
+                        .unwrap_or(false);
+                    if !is_known {
+                        progress!(
+                            "  Ignoring unknown currency <{}> for account: {} (use --{} for currencies that are not in ISO 4217)",
+                            currency_code,
+                            account.name,
+                            CUSTOM_CURRENCIES_ARG
+                        );
+                    }
+                    is_known
+                };
+                let opt_foreign_account_key =
+                    Self::foreign_account_key(&account, &is_known_currency)?;
+                let opt_difference_account_key =
+                    Self::difference_account_key(&account, &is_known_currency)?;
                 if opt_foreign_account_key.is_some() && opt_difference_account_key.is_some() {
                     bail!(
                         "One account may not be both foreign currency and difference account: {}",
@@ -63,12 +90,14 @@ impl<'a> ForeignAccounts<'a> {
                         local_account_data()
                     } else {
                         all_used_foreign_currencies.insert(difference_key.currency);
-                        println!(
+                        progress!(
                             "  Found foreign {}: {} ({})",
                             difference_key,
                             account.name,
-                            budget_formatter
-                                .format_milliunits(Milliunits::from_scaled_i64(account.balance))
+                            budget_formatter.format_milliunits_with_code(
+                                difference_key.currency,
+                                Milliunits::from_scaled_i64(account.balance)
+                            )
                         );
                         AccountData::Foreign { difference_key }
                     }
@@ -89,7 +118,7 @@ impl<'a> ForeignAccounts<'a> {
                             difference_key
                         )
                     );
-                    println!(
+                    progress!(
                         "  Found difference {}: {} ({})",
                         difference_key,
                         account.name,
@@ -100,6 +129,12 @@ impl<'a> ForeignAccounts<'a> {
                 } else {
                     local_account_data()
                 };
+                if let Some(rate_date_policy) = Self::account_rate_date_policy(account)? {
+                    account_rate_date_policies.insert(account_id.clone(), rate_date_policy);
+                }
+                if let Some(fee) = Self::account_fee(account)? {
+                    account_fees.insert(account_id.clone(), fee);
+                }
                 ensure!(
                     account_id_data.insert(account_id, account_data).is_none(),
                     format!(
@@ -127,6 +162,8 @@ impl<'a> ForeignAccounts<'a> {
             ForeignAccounts {
                 all_used_foreign_currencies,
                 account_id_data,
+                account_rate_date_policies,
+                account_fees,
                 difference_account_ids,
             },
             difference_balances,
@@ -137,6 +174,16 @@ impl<'a> ForeignAccounts<'a> {
         self.account_id_data.get(account_id)
     }
 
+    /// The rate date policy set by the account's `<RATE DATE: ...>` tag, if any.
+    pub fn get_rate_date_policy(&self, account_id: &YnabAccountId) -> Option<RateDatePolicy> {
+        self.account_rate_date_policies.get(account_id).cloned()
+    }
+
+    /// The fee set by the account's `<FEE ...>` tag, if any.
+    pub fn get_account_fee(&self, account_id: &YnabAccountId) -> Option<AccountFee> {
+        self.account_fees.get(account_id).cloned()
+    }
+
     pub fn get_difference_account_id(
         &self,
         difference_key: DifferenceKey,
@@ -148,35 +195,69 @@ impl<'a> ForeignAccounts<'a> {
         &self.all_used_foreign_currencies
     }
 
-    fn foreign_account_key(account: &ynab_api::models::Account) -> Result<Option<DifferenceKey>> {
-        Self::account_difference_key_from_regex(&ACCOUNT_CURRENCY_REGEX, account).chain_err(|| {
+    fn foreign_account_key(
+        account: &ynab_api::models::Account,
+        is_known_currency: &dyn Fn(&str) -> bool,
+    ) -> Result<Option<DifferenceKey>> {
+        Self::account_difference_key_from_regex(&ACCOUNT_CURRENCY_REGEX, account, is_known_currency)
+            .chain_err(|| {
+                format!(
+                    "Could not determine foreign currency for account: {}",
+                    account.name
+                )
+            })
+    }
+
+    fn difference_account_key(
+        account: &ynab_api::models::Account,
+        is_known_currency: &dyn Fn(&str) -> bool,
+    ) -> Result<Option<DifferenceKey>> {
+        Self::account_difference_key_from_regex(
+            &DIFFERENCE_ACCOUNT_CURRENCY_REGEX,
+            account,
+            is_known_currency,
+        )
+        .chain_err(|| {
             format!(
-                "Could not determine foreign currency for account: {}",
+                "Could not determine foreign currency for difference account: {}",
                 account.name
             )
         })
     }
 
-    fn difference_account_key(
+    fn account_rate_date_policy(
         account: &ynab_api::models::Account,
-    ) -> Result<Option<DifferenceKey>> {
-        Self::account_difference_key_from_regex(&DIFFERENCE_ACCOUNT_CURRENCY_REGEX, account)
+    ) -> Result<Option<RateDatePolicy>> {
+        Self::account_name_and_note_regex_capture(&ACCOUNT_RATE_DATE_REGEX, account, &|_| true)
+            .and_then(|opt_value| {
+                opt_value
+                    .map(|value| RateDatePolicy::from_str(&value.to_lowercase()))
+                    .transpose()
+            })
             .chain_err(|| {
                 format!(
-                    "Could not determine foreign currency for difference account: {}",
+                    "Could not determine rate date for account: {}",
                     account.name
                 )
             })
     }
 
+    fn account_fee(account: &ynab_api::models::Account) -> Result<Option<AccountFee>> {
+        Self::account_name_and_note_regex_capture(&ACCOUNT_FEE_REGEX, account, &|_| true)
+            .and_then(|opt_value| opt_value.map(AccountFee::from_str).transpose())
+            .chain_err(|| format!("Could not determine fee for account: {}", account.name))
+    }
+
     fn account_difference_key_from_regex(
         regex: &Regex,
         account: &ynab_api::models::Account,
+        is_known_currency: &dyn Fn(&str) -> bool,
     ) -> Result<Option<DifferenceKey>> {
-        let currency_code = match Self::account_name_and_note_regex_capture(regex, &account)? {
-            Some(currency_code) => currency_code,
-            None => return Ok(None),
-        };
+        let currency_code =
+            match Self::account_name_and_note_regex_capture(regex, &account, is_known_currency)? {
+                Some(currency_code) => currency_code,
+                None => return Ok(None),
+            };
         let currency = CurrencyCode::from_str(currency_code)
             .expect("Account name and note regex should capture a valid currency code");
         let class = match account._type {
@@ -201,48 +282,38 @@ impl<'a> ForeignAccounts<'a> {
         Ok(Some(DifferenceKey::new(currency, class)))
     }
 
+    /// Get the tag's value from the account's name or note, ignoring values
+    /// that `is_tag` rejects.
     fn account_name_and_note_regex_capture<'b>(
         regex: &Regex,
         account: &'b ynab_api::models::Account,
+        is_tag: &dyn Fn(&str) -> bool,
     ) -> Result<Option<&'b str>> {
-        let mut name_captures_iter = regex.captures_iter(&account.name);
-        if let Some(name_captures) = name_captures_iter.next() {
-            ensure!(
-                name_captures_iter.next().is_none(),
-                "Name may not have multiple tags"
-            );
-            if let Some(note) = &account.note {
-                ensure!(
-                    regex.captures(&note).is_none(),
-                    "Name and note may not both have tags"
-                );
-            }
-            Ok(Some(
-                name_captures
-                    .get(1)
-                    .expect("account_name_and_note_regex_capture regex should have capture group")
-                    .as_str(),
-            ))
-        } else if let Some(note) = &account.note {
-            let mut note_captures_iter = regex.captures_iter(note);
-            if let Some(note_captures) = note_captures_iter.next() {
-                ensure!(
-                    note_captures_iter.next().is_none(),
-                    "Note may not have multiple tags"
-                );
-                Ok(Some(
-                    note_captures
+        let tag_values = |text: &'b str| -> Vec<&'b str> {
+            regex
+                .captures_iter(text)
+                .map(|captures| {
+                    captures
                         .get(1)
                         .expect(
                             "account_name_and_note_regex_capture regex should have capture group",
                         )
-                        .as_str(),
-                ))
-            } else {
-                Ok(None)
-            }
-        } else {
-            Ok(None)
+                        .as_str()
+                })
+                .filter(|value| is_tag(value))
+                .collect()
+        };
+        let name_values = tag_values(&account.name);
+        let note_values = account
+            .note
+            .as_ref()
+            .map_or_else(Vec::new, |note| tag_values(note));
+        match (name_values.as_slice(), note_values.as_slice()) {
+            ([], []) => Ok(None),
+            ([value], []) | ([], [value]) => Ok(Some(value)),
+            ([_], _) => bail!("Name and note may not both have tags"),
+            ([], _) => bail!("Note may not have multiple tags"),
+            (_, _) => bail!("Name may not have multiple tags"),
         }
     }
 }
@@ -302,6 +373,41 @@ impl<'a> DifferenceBalances {
         }
     }
 
+    /// Leave a foreign transaction out of the foreign accounts' total, so
+    /// that adjustments do not include it.
+    pub fn exclude_foreign_amount(&mut self, difference_key: DifferenceKey, amount: Milliunits) {
+        self.balances
+            .get_mut(&difference_key)
+            .unwrap_or_else(|| {
+                panic!(
+                    "DifferenceBalances should have entry for difference_key: {}",
+                    difference_key
+                )
+            })
+            .foreign_accounts_total -= amount;
+    }
+
+    /// Add fees saved with difference transactions in earlier runs.
+    pub fn add_fees_totals(&mut self, fees_totals: HashMap<DifferenceKey, Milliunits>) {
+        for (difference_key, fees_total) in fees_totals {
+            if let Some(balance) = self.balances.get_mut(&difference_key) {
+                balance.fees_total += fees_total;
+            }
+        }
+    }
+
+    pub fn update_fees(&mut self, difference_key: DifferenceKey, delta_amount: Milliunits) {
+        self.balances
+            .get_mut(&difference_key)
+            .unwrap_or_else(|| {
+                panic!(
+                    "DifferenceBalances should have entry for difference_key: {}",
+                    difference_key
+                )
+            })
+            .fees_total += delta_amount;
+    }
+
     pub fn iter(
         &'a self,
     ) -> Box<dyn Iterator<Item = (&'a DifferenceKey, &'a ForeignTotalAndDifferenceBalance)> + 'a>
@@ -315,6 +421,7 @@ impl ForeignTotalAndDifferenceBalance {
         ForeignTotalAndDifferenceBalance {
             foreign_accounts_total: Milliunits::zero(),
             difference_account_balance: Milliunits::zero(),
+            fees_total: Milliunits::zero(),
         }
     }
 }
@@ -1,11 +1,16 @@
 use chrono::{Duration, NaiveDate};
 use log::{debug, warn};
+use rust_decimal::prelude::Zero;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
 
 use crate::budget_formatter::*;
+use crate::clock::*;
 use crate::constants::*;
-use crate::currency_converter_client::*;
+use crate::currencies::*;
 use crate::database::models::*;
 use crate::database::*;
 use crate::errors::*;
@@ -18,33 +23,78 @@ use crate::ynab_client::*;
 
 pub struct ForeignTransactionsProcessor<'a> {
     budget_database: &'a BudgetDatabase<'a>,
-    ynab_client: &'a YnabBudgetClient<'a>,
+    ynab_client: &'a dyn YnabBudget,
     today_date: NaiveDate,
     dry_run: bool,
     auto_approve_transactions: bool,
     auto_approve_adjustments: bool,
+    rate_date_policy: RateDatePolicy,
+    save_plan_file: Option<&'a str>,
+    /// Asks about each planned transaction in `--interactive` mode.
+    prompter: Option<&'a dyn Prompter>,
     budget_settings: &'a ynab_api::models::BudgetSettings,
     budget_formatter: &'a BudgetFormatter<'a>,
     local_currency: CurrencyCode,
-    exchange_rates_cache: ExchangeRatesCache<'a>,
+    currency_registry: &'a CurrencyRegistry,
+    exchange_rates_cache: &'a ExchangeRatesCache<'a>,
     import_id_generator: ImportIdGenerator,
     foreign_accounts: ForeignAccounts<'a>,
     difference_balances: RefCell<DifferenceBalances>,
 }
 
+/// A dry run's changes, saved to a file so that they can be applied later
+/// exactly as reviewed.
+struct SavedPlan {
+    budget_id: String,
+    date: NaiveDate,
+    start_date: NaiveDate,
+    /// The budget's server knowledge in the database when the plan was made.
+    previous_server_knowledge: Option<i64>,
+    /// YNAB's server knowledge that the plan is based on.
+    server_knowledge: i64,
+    transactions_modifications: TransactionsModificationsData<'static>,
+}
+
+/// What a difference account's balance should be at today's exchange rate.
+struct DifferenceAdjustment {
+    rate_info: ExchangeRateInfo,
+    expected_difference_account_balance: Milliunits,
+    /// Zero if the difference is too small to be worth adjusting.
+    amount: Milliunits,
+}
+
 #[derive(Debug)]
 struct TransactionsModificationsData<'a> {
     create_transactions: Vec<ynab_api::models::SaveTransaction>,
     update_transactions: Vec<ynab_api::models::UpdateTransaction>,
     create_import_ids_foreign_ynab_transaction_ids:
         HashMap<YnabImportId<'a>, YnabTransactionId<'a>>,
+    update_ids_foreign_ynab_transaction_ids: HashMap<YnabTransactionId<'a>, YnabTransactionId<'a>>,
+    foreign_ynab_transaction_ids_exchange_rate_dates: HashMap<YnabTransactionId<'a>, NaiveDate>,
+    foreign_ynab_transaction_ids_fee_amounts: HashMap<YnabTransactionId<'a>, Milliunits>,
     delete_difference_transaction_ids: HashSet<YnabTransactionId<'a>>,
+    recomputed_foreign_transaction_ids: HashSet<YnabTransactionId<'a>>,
+    /// Foreign transactions whose difference transaction was skipped in
+    /// `--interactive` mode.
+    skipped_foreign_transaction_ids: HashSet<YnabTransactionId<'a>>,
+    /// Difference accounts with skipped updates.  Their adjustments wait until
+    /// the updates are accepted, since the old foreign amounts they would
+    /// leave out are not known.
+    postponed_adjustment_difference_keys: HashSet<DifferenceKey>,
+    /// Planned transactions, for `--output json`.
+    planned_modifications: Vec<(TransactionModificationKind, serde_json::Value)>,
+    /// Planned transactions skipped in `--interactive` mode, for
+    /// `--output json`.
+    skipped_modifications: Vec<(TransactionModificationKind, serde_json::Value)>,
 }
 
 #[derive(Debug)]
 struct ForeignCommonData {
     difference_key: Option<DifferenceKey>,
     transaction_date: NaiveDate,
+    transaction_posting_date: Option<NaiveDate>,
+    rate_date_policy: RateDatePolicy,
+    fee: Option<AccountFee>,
     transaction_cleared: ynab_api::models::transaction_detail::Cleared,
     transaction_approved: bool,
     transaction_flag_color: Option<ynab_api::models::transaction_detail::FlagColor>,
@@ -68,36 +118,56 @@ struct ForeignTransactionData<'a> {
 
 #[derive(Debug)]
 struct TransactionModificationData<'a> {
-    prefix: &'a str,
+    kind: TransactionModificationKind,
     difference_key: DifferenceKey,
     date: NaiveDate,
     payee_name: Option<&'a str>,
     category_name: Option<&'a str>,
     memo: &'a str,
     amount: Milliunits,
+    foreign_amount: Milliunits,
+    exchange_rate: Option<ExchangeRate>,
+    fee_amount: Milliunits,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum TransactionModificationKind {
+    CreateDifference,
+    UpdateDifference,
+    CreateAdjustment,
 }
 
 #[derive(Debug)]
 struct DifferenceTransactionData<'a> {
     amount: Milliunits,
+    fee_amount: Milliunits,
     memo: String,
     category_id: &'a Option<String>,
     category_name: Option<&'a str>,
+    exchange_rate: Option<ExchangeRate>,
+    exchange_rate_date: Option<NaiveDate>,
 }
 
 impl<'a> ForeignTransactionsProcessor<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn run(
         database: &'a Database,
-        ynab_client: &'a YnabBudgetClient,
-        currency_converter_client: &'a CurrencyConverterClient,
+        ynab_client: &'a dyn YnabBudget,
+        exchange_rates_cache: &'a ExchangeRatesCache<'a>,
+        currency_registry: &'a CurrencyRegistry,
         start_date_arg: Option<NaiveDate>,
         dry_run: bool,
         auto_approve_transactions: bool,
         auto_approve_adjustments: bool,
-    ) -> Result<()> {
-        let today_date = chrono::Local::today().naive_utc();
+        rate_date_policy: RateDatePolicy,
+        foreign_amount_style: ForeignAmountStyle,
+        save_plan_file: Option<&'a str>,
+        prompter: Option<&'a dyn Prompter>,
+        clock: &dyn Clock,
+    ) -> Result<serde_json::Value> {
+        let today_date = clock.today();
         let (initial_budget_state, budget_database) = database.get_or_create_budget(
-            ynab_client.budget_id,
+            ynab_client.budget_id(),
             start_date_arg.unwrap_or(today_date - Duration::days(30)),
         )?;
         ensure!(
@@ -108,8 +178,8 @@ impl<'a> ForeignTransactionsProcessor<'a> {
             )
         );
 
-        println!("Loading latest transactions from YNAB...");
-        let transactions_response_data = ynab_client.get_transactions(
+        progress!("Loading latest transactions from YNAB...");
+        let mut transactions_response_data = ynab_client.get_transactions(
             Some(initial_budget_state.start_date),
             initial_budget_state.ynab_server_knowledge,
         )?;
@@ -117,19 +187,45 @@ impl<'a> ForeignTransactionsProcessor<'a> {
             "Latest transactions received from YNAB: {:#?}",
             &transactions_response_data
         );
+        let recompute_foreign_transaction_ids =
+            budget_database.get_recompute_foreign_transaction_ids()?;
+        if !recompute_foreign_transaction_ids.is_empty() {
+            add_recompute_transactions(
+                ynab_client,
+                initial_budget_state.start_date,
+                &recompute_foreign_transaction_ids,
+                &mut transactions_response_data.transactions,
+            )?;
+        }
 
         if transactions_response_data.transactions.is_empty()
+            && recompute_foreign_transaction_ids.is_empty()
             && Some(today_date) == initial_budget_state.last_run_date
         {
-            println!("No new/updated/deleted transactions; nothing to do!");
-            Ok(())
+            progress!("No new/updated/deleted transactions; nothing to do!");
+            if save_plan_file.is_some() {
+                progress!("No plan saved, since there is nothing to apply.");
+            }
+            Ok(json_plan(
+                ynab_client.budget_id(),
+                today_date,
+                dry_run,
+                transactions_response_data.server_knowledge,
+                &TransactionsModificationsData::new(),
+            ))
         } else {
-            println!("Loading budget settings from YNAB...");
+            progress!("Loading budget settings from YNAB...");
             let budget_settings = ynab_client.get_budget_settings()?;
-            let budget_formatter = BudgetFormatter::new(&budget_settings);
+            let budget_formatter =
+                BudgetFormatter::new(&budget_settings, foreign_amount_style, currency_registry);
             let local_currency = CurrencyCode::from_str(&budget_settings.currency_format.iso_code)?;
-            let (foreign_accounts, difference_balances) =
-                ForeignAccounts::load(ynab_client, &budget_formatter, local_currency)?;
+            let (foreign_accounts, mut difference_balances) = ForeignAccounts::load(
+                ynab_client,
+                &budget_formatter,
+                local_currency,
+                currency_registry,
+            )?;
+            difference_balances.add_fees_totals(budget_database.get_fees_totals()?);
             ForeignTransactionsProcessor {
                 budget_database: &budget_database,
                 ynab_client,
@@ -137,38 +233,296 @@ impl<'a> ForeignTransactionsProcessor<'a> {
                 dry_run,
                 auto_approve_transactions,
                 auto_approve_adjustments,
+                rate_date_policy,
+                save_plan_file,
+                prompter,
                 budget_settings: &budget_settings,
                 budget_formatter: &budget_formatter,
                 local_currency,
-                exchange_rates_cache: ExchangeRatesCache::new(currency_converter_client, database),
-                import_id_generator: ImportIdGenerator::new(),
+                currency_registry,
+                exchange_rates_cache,
+                import_id_generator: ImportIdGenerator::new(clock),
                 foreign_accounts,
                 difference_balances: RefCell::new(difference_balances),
             }
-            .process(transactions_response_data)
+            .process(
+                &initial_budget_state,
+                transactions_response_data,
+                recompute_foreign_transaction_ids,
+            )
         }
     }
 
+    /// Print the budget's saved state and, for each difference account, how
+    /// its balance compares to the foreign accounts' total at today's
+    /// exchange rate.  Nothing is saved to the budget.
+    pub fn status(
+        database: &'a Database,
+        ynab_client: &'a dyn YnabBudget,
+        exchange_rates_cache: &'a ExchangeRatesCache<'a>,
+        currency_registry: &'a CurrencyRegistry,
+        foreign_amount_style: ForeignAmountStyle,
+        clock: &dyn Clock,
+    ) -> Result<()> {
+        let today_date = clock.today();
+        let opt_budget_state = database.get_budget_state(ynab_client.budget_id())?;
+        progress!("Loading budget settings from YNAB...");
+        let budget_settings = ynab_client.get_budget_settings()?;
+        let budget_formatter =
+            BudgetFormatter::new(&budget_settings, foreign_amount_style, currency_registry);
+        let local_currency = CurrencyCode::from_str(&budget_settings.currency_format.iso_code)?;
+        let (foreign_accounts, mut difference_balances) = ForeignAccounts::load(
+            ynab_client,
+            &budget_formatter,
+            local_currency,
+            currency_registry,
+        )?;
+        let (_, budget_database) =
+            database.get_or_create_budget(ynab_client.budget_id(), today_date)?;
+        difference_balances.add_fees_totals(budget_database.get_fees_totals()?);
+        match &opt_budget_state {
+            Some(budget_state) => {
+                println!("Budget state:");
+                println!(
+                    "             Start date: {}",
+                    budget_formatter.format_date(budget_state.start_date)
+                );
+                println!(
+                    "          Last run date: {}",
+                    budget_state
+                        .last_run_date
+                        .map(|date| budget_formatter.format_date(date))
+                        .unwrap_or_else(|| "never".to_string())
+                );
+                println!(
+                    "  YNAB server knowledge: {}",
+                    budget_state
+                        .ynab_server_knowledge
+                        .map(|knowledge| knowledge.to_string())
+                        .unwrap_or_else(|| "none".to_string())
+                );
+            }
+            None => println!("Budget has not been synced yet."),
+        }
+        ForeignTransactionsProcessor {
+            budget_database: &budget_database,
+            ynab_client,
+            today_date,
+            dry_run: true,
+            auto_approve_transactions: false,
+            auto_approve_adjustments: false,
+            // Not used, since no foreign transactions are converted.
+            rate_date_policy: RateDatePolicy::SameDay,
+            save_plan_file: None,
+            prompter: None,
+            budget_settings: &budget_settings,
+            budget_formatter: &budget_formatter,
+            local_currency,
+            currency_registry,
+            exchange_rates_cache,
+            import_id_generator: ImportIdGenerator::new(clock),
+            foreign_accounts,
+            difference_balances: RefCell::new(difference_balances),
+        }
+        .print_status(opt_budget_state)
+    }
+
+    /// Save the changes from a plan file made by a dry run with
+    /// `--save-plan`, refusing if the budget has changed since.
+    pub fn apply(
+        database: &'a Database,
+        ynab_client: &'a dyn YnabBudget,
+        exchange_rates_cache: &'a ExchangeRatesCache<'a>,
+        currency_registry: &'a CurrencyRegistry,
+        foreign_amount_style: ForeignAmountStyle,
+        plan_file: &str,
+        clock: &dyn Clock,
+    ) -> Result<()> {
+        let plan = SavedPlan::read(plan_file)?;
+        ensure!(
+            plan.budget_id == ynab_client.budget_id(),
+            "Plan file is for a different budget: {}",
+            plan.budget_id
+        );
+        let (budget_state, budget_database) =
+            database.get_or_create_budget(ynab_client.budget_id(), plan.start_date)?;
+        ensure!(
+            budget_state.start_date == plan.start_date
+                && budget_state.ynab_server_knowledge == plan.previous_server_knowledge,
+            "The budget has been synced since the plan was saved; save a new plan"
+        );
+        progress!("Checking for changes in YNAB since the plan was saved...");
+        let transactions_response_data =
+            ynab_client.get_transactions(Some(plan.start_date), Some(plan.server_knowledge))?;
+        ensure!(
+            transactions_response_data.server_knowledge == plan.server_knowledge
+                && transactions_response_data.transactions.is_empty(),
+            "The YNAB budget has changed since the plan was saved (server knowledge {} is now {}); save a new plan",
+            plan.server_knowledge,
+            transactions_response_data.server_knowledge
+        );
+        progress!("Loading budget settings from YNAB...");
+        let budget_settings = ynab_client.get_budget_settings()?;
+        let budget_formatter =
+            BudgetFormatter::new(&budget_settings, foreign_amount_style, currency_registry);
+        let local_currency = CurrencyCode::from_str(&budget_settings.currency_format.iso_code)?;
+        let (foreign_accounts, difference_balances) = ForeignAccounts::load(
+            ynab_client,
+            &budget_formatter,
+            local_currency,
+            currency_registry,
+        )?;
+        let processor = ForeignTransactionsProcessor {
+            budget_database: &budget_database,
+            ynab_client,
+            today_date: plan.date,
+            dry_run: false,
+            // Approval is already part of the planned transactions.
+            auto_approve_transactions: false,
+            auto_approve_adjustments: false,
+            // Not used, since no foreign transactions are converted.
+            rate_date_policy: RateDatePolicy::SameDay,
+            save_plan_file: None,
+            prompter: None,
+            budget_settings: &budget_settings,
+            budget_formatter: &budget_formatter,
+            local_currency,
+            currency_registry,
+            exchange_rates_cache,
+            import_id_generator: ImportIdGenerator::new(clock),
+            foreign_accounts,
+            difference_balances: RefCell::new(difference_balances),
+        };
+        progress!(
+            "Applying plan from {}: {} new and {} changed difference transactions",
+            format_iso_date(plan.date),
+            plan.transactions_modifications.create_transactions.len(),
+            plan.transactions_modifications.update_transactions.len()
+        );
+        let update_state_data = processor.save_transactions(plan.transactions_modifications)?;
+        budget_database.update_state(plan.server_knowledge, plan.date, update_state_data)
+    }
+
+    fn print_status(&self, opt_budget_state: Option<BudgetState>) -> Result<()> {
+        let difference_balances = self.difference_balances.borrow();
+        let mut sorted_balances: Vec<_> = difference_balances.iter().collect();
+        sorted_balances.sort_by_key(|&(&difference_key, _)| difference_key);
+        let mut adjustments_count = 0;
+        for (&difference_key, foreign_total_and_difference_balance) in sorted_balances {
+            if self
+                .foreign_accounts
+                .get_difference_account_id(difference_key)
+                .is_none()
+            {
+                continue;
+            }
+            println!("Difference {}:", difference_key);
+            println!(
+                "                  Foreign total: {}",
+                self.budget_formatter.format_milliunits_with_code(
+                    difference_key.currency,
+                    foreign_total_and_difference_balance.foreign_accounts_total
+                )
+            );
+            let opt_adjustment =
+                self.difference_adjustment(difference_key, foreign_total_and_difference_balance)?;
+            if let Some(adjustment) = &opt_adjustment {
+                println!(
+                    "           Today's exchange rate: {}/{}",
+                    self.budget_formatter
+                        .format_exchange_rate(adjustment.rate_info.exchange_rate),
+                    difference_key.currency
+                );
+                println!(
+                    "    Expected difference balance: {}",
+                    self.budget_formatter
+                        .format_milliunits(adjustment.expected_difference_account_balance)
+                );
+            } else {
+                println!("           Today's exchange rate: not available");
+            }
+            println!(
+                "      Actual difference balance: {}",
+                self.budget_formatter.format_milliunits(
+                    foreign_total_and_difference_balance.difference_account_balance
+                )
+            );
+            match opt_adjustment {
+                Some(ref adjustment) if !adjustment.amount.is_zero() => {
+                    adjustments_count += 1;
+                    println!(
+                        "             Pending adjustment: {}",
+                        self.budget_formatter.format_milliunits(adjustment.amount)
+                    );
+                }
+                Some(_) => println!("             Pending adjustment: none"),
+                None => println!("             Pending adjustment: unknown"),
+            }
+        }
+        if let Some(budget_state) = opt_budget_state {
+            let transactions_response_data = self.ynab_client.get_transactions(
+                Some(budget_state.start_date),
+                budget_state.ynab_server_knowledge,
+            )?;
+            println!(
+                "New/updated/deleted transactions since last run: {}",
+                transactions_response_data.transactions.len()
+            );
+            if !transactions_response_data.transactions.is_empty() || adjustments_count > 0 {
+                println!("A sync is needed.");
+            } else {
+                println!("No sync is needed.");
+            }
+        }
+        Ok(())
+    }
+
     fn process(
         &self,
+        initial_budget_state: &BudgetState,
         transactions_response_data: ynab_api::models::transactions_response_data::TransactionsResponseData,
-    ) -> Result<()> {
+        recompute_foreign_transaction_ids: HashSet<YnabTransactionId<'static>>,
+    ) -> Result<serde_json::Value> {
         let mut transactions_modifications =
             self.process_transactions(transactions_response_data.transactions)?;
+        // Skipped transactions still need to be recomputed on the next run.
+        transactions_modifications.recomputed_foreign_transaction_ids =
+            &recompute_foreign_transaction_ids
+                - &transactions_modifications.skipped_foreign_transaction_ids;
         self.create_adjustments(&mut transactions_modifications)?;
+        self.exchange_rates_cache.check_missing_exchange_rates()?;
+        let plan = json_plan(
+            self.ynab_client.budget_id(),
+            self.today_date,
+            self.dry_run,
+            transactions_response_data.server_knowledge,
+            &transactions_modifications,
+        );
+        if let Some(save_plan_file) = self.save_plan_file {
+            SavedPlan::write(
+                save_plan_file,
+                self.ynab_client.budget_id(),
+                self.today_date,
+                initial_budget_state,
+                transactions_response_data.server_knowledge,
+                &transactions_modifications,
+            )?;
+            progress!("Saved plan to {}", save_plan_file);
+        }
         let update_state_data = self.save_transactions(transactions_modifications)?;
         self.budget_database.update_state(
             transactions_response_data.server_knowledge,
             self.today_date,
             update_state_data,
-        )
+        )?;
+        Ok(plan)
     }
 
     fn process_transactions(
         &self,
         latest_transactions: Vec<ynab_api::models::TransactionDetail>,
     ) -> Result<TransactionsModificationsData> {
-        println!("Processing latest transactions...");
+        progress!("Processing latest transactions...");
         let mut transactions_modifications = TransactionsModificationsData::new();
         for parent_transaction in &latest_transactions {
             // If user has deleted a transaction from the difference account,
@@ -205,6 +559,17 @@ impl<'a> ForeignTransactionsProcessor<'a> {
             let common_data = ForeignCommonData {
                 difference_key,
                 transaction_date: parse_iso_date(&parent_transaction.date)?,
+                transaction_posting_date: memo_posting_date(
+                    &parent_transaction.memo,
+                    &parent_transaction_id,
+                ),
+                rate_date_policy: self
+                    .foreign_accounts
+                    .get_rate_date_policy(&parent_transaction_account_id)
+                    .unwrap_or(self.rate_date_policy),
+                fee: self
+                    .foreign_accounts
+                    .get_account_fee(&parent_transaction_account_id),
                 transaction_cleared: parent_transaction.cleared,
                 transaction_approved: parent_transaction.approved,
                 transaction_flag_color: parent_transaction.flag_color,
@@ -312,6 +677,7 @@ impl<'a> ForeignTransactionsProcessor<'a> {
             // we update the difference transaction to a zero amount.
             DifferenceTransactionData {
                 amount: Milliunits::zero(),
+                fee_amount: Milliunits::zero(),
                 memo: format!(
                     "<{}DELETED{}>{}",
                     DIFFERENCE_MEMO_TAG_PREFIX,
@@ -320,33 +686,74 @@ impl<'a> ForeignTransactionsProcessor<'a> {
                 ),
                 category_id: &None,
                 category_name: None,
+                exchange_rate: None,
+                exchange_rate_date: None,
             }
         } else if let Some(difference_key) = common_data.difference_key {
-            let exchange_rate = self.get_transaction_date_exchange_rate(
-                difference_key.currency,
+            // A split's own posting date tag takes precedence over the parent
+            // transaction's.
+            let rate_date = common_data.rate_date_policy.rate_date(
                 common_data.transaction_date,
-            )?;
+                memo_posting_date(foreign_data.memo, foreign_data.ynab_transaction_id)
+                    .or(common_data.transaction_posting_date),
+            );
+            let rate_info = match self
+                .get_transaction_date_exchange_rate(difference_key.currency, rate_date)?
+            {
+                Some(rate_info) => rate_info,
+                // Missing in offline mode; reported once all transactions
+                // have been processed.
+                None => return Ok(()),
+            };
+            // The rate date policy's date, unlike a fallback to an earlier
+            // published rate, is deliberate, so it is shown differently.
+            let opt_policy_rate_date = if rate_date == common_data.transaction_date {
+                None
+            } else {
+                Some(rate_date)
+            };
+            let converted_amount = self
+                .round_to_currency_minor_units(difference_key.currency, foreign_data.amount)
+                .convert_currency(rate_info.exchange_rate);
+            let fee_amount = match common_data.fee {
+                Some(fee) => self.round_to_budget_decimal_digits(fee.fee_amount(converted_amount)),
+                None => Milliunits::zero(),
+            };
             DifferenceTransactionData {
-                amount: self.round_to_budget_decimal_digits(
-                    foreign_data.amount.convert_currency(exchange_rate) - foreign_data.amount,
-                ),
+                // The foreign account holds the amount as recorded, so together
+                // they add up to the rounded converted amount.
+                amount: self.round_to_budget_decimal_digits(converted_amount) - foreign_data.amount
+                    + fee_amount,
+                fee_amount,
                 memo: format!(
-                    "<{}{}{}>{}",
+                    "<{}{}{}{}>{}",
                     DIFFERENCE_MEMO_TAG_PREFIX,
                     self.format_exchange(
                         difference_key.currency,
                         foreign_data.amount,
-                        exchange_rate
+                        rate_info,
+                        opt_policy_rate_date
                     ),
+                    if fee_amount.is_zero() {
+                        "".to_string()
+                    } else {
+                        format!(
+                            ", fee {}",
+                            self.budget_formatter.format_milliunits(-fee_amount)
+                        )
+                    },
                     foreign_data.difference_memo_tag_suffix,
                     difference_memo_suffix
                 ),
                 category_id: foreign_data.category_id,
                 category_name: foreign_data.category_name,
+                exchange_rate: Some(rate_info.exchange_rate),
+                exchange_rate_date: Some(rate_date),
             }
         } else {
             DifferenceTransactionData {
                 amount: Milliunits::zero(),
+                fee_amount: Milliunits::zero(),
                 memo: format!(
                     "<{}MOVED TO LOCAL CURRENCY ACCOUNT{}>{}",
                     DIFFERENCE_MEMO_TAG_PREFIX,
@@ -355,6 +762,8 @@ impl<'a> ForeignTransactionsProcessor<'a> {
                 ),
                 category_id: &None,
                 category_name: None,
+                exchange_rate: None,
+                exchange_rate_date: None,
             }
         };
         let opt_existing_difference_transaction = self
@@ -370,14 +779,6 @@ impl<'a> ForeignTransactionsProcessor<'a> {
                     Some(existing_difference_transaction)
                 }
             });
-        let mut difference_balances = self.difference_balances.borrow_mut();
-        if let Some(old_difference_transaction) = &opt_existing_difference_transaction {
-            difference_balances.update(
-                old_difference_transaction.difference_key,
-                old_difference_transaction.transfer_key,
-                -old_difference_transaction.amount,
-            );
-        }
         let opt_difference_key = match (
             common_data.difference_key,
             &opt_existing_difference_transaction,
@@ -393,87 +794,170 @@ impl<'a> ForeignTransactionsProcessor<'a> {
                 .foreign_accounts
                 .get_difference_account_id(difference_key)
                 .expect("Difference account should exist");
-            if let Some(difference_transaction) = &opt_existing_difference_transaction {
-                self.print_transaction_modification(&TransactionModificationData {
-                    prefix: "Update difference",
-                    difference_key,
-                    date: common_data.transaction_date,
-                    payee_name: foreign_data.payee_name,
-                    category_name: difference_data.category_name,
-                    memo: &difference_data.memo,
-                    amount: difference_data.amount,
-                });
-                transactions_modifications.update_transactions.push(
-                    ynab_api::models::UpdateTransaction {
-                        id: difference_transaction.difference_transaction_id.to_string(),
-                        account_id: difference_account_id.to_string(),
-                        date: format_iso_date(common_data.transaction_date),
-                        amount: difference_data.amount.to_scaled_i64(),
-                        payee_id: foreign_data.payee_id.cloned(),
-                        payee_name: None,
-                        category_id: difference_data.category_id.clone(),
-                        memo: Some(difference_data.memo),
-                        cleared: Some(transaction_detail_cleared_to_update_transaction(
-                            common_data.transaction_cleared,
-                        )),
-                        approved: None,
-                        flag_color: common_data
-                            .transaction_flag_color
-                            .map(transaction_detail_flag_color_to_update_transaction),
-                        import_id: None,
-                    },
-                );
-            } else if !difference_data.amount.is_zero() {
-                let difference_import_id = self.import_id_generator.next_import_id();
+            let foreign_ynab_transaction_id =
+                YnabTransactionId::new(foreign_data.ynab_transaction_id.raw.clone().into_owned());
+            if let Some(exchange_rate_date) = difference_data.exchange_rate_date {
                 transactions_modifications
-                    .create_import_ids_foreign_ynab_transaction_ids
+                    .foreign_ynab_transaction_ids_exchange_rate_dates
+                    .insert(foreign_ynab_transaction_id.clone(), exchange_rate_date);
+            }
+            if !difference_data.fee_amount.is_zero() {
+                transactions_modifications
+                    .foreign_ynab_transaction_ids_fee_amounts
                     .insert(
-                        YnabImportId::new(difference_import_id.raw.clone().into_owned()),
-                        YnabTransactionId::new(
-                            foreign_data.ynab_transaction_id.raw.clone().into_owned(),
-                        ),
+                        foreign_ynab_transaction_id.clone(),
+                        difference_data.fee_amount,
                     );
-                self.print_transaction_modification(&TransactionModificationData {
-                    prefix: "Create difference",
-                    difference_key,
-                    date: common_data.transaction_date,
-                    payee_name: foreign_data.payee_name,
-                    category_name: difference_data.category_name,
-                    memo: &difference_data.memo,
-                    amount: difference_data.amount,
-                });
-                transactions_modifications.create_transactions.push(
-                    ynab_api::models::SaveTransaction {
-                        account_id: difference_account_id.to_string(),
-                        date: format_iso_date(common_data.transaction_date),
-                        amount: difference_data.amount.to_scaled_i64(),
-                        payee_id: foreign_data.payee_id.cloned(),
-                        payee_name: None,
-                        category_id: difference_data.category_id.clone(),
-                        memo: Some(difference_data.memo),
-                        cleared: Some(transaction_detail_cleared_to_save_transaction(
-                            common_data.transaction_cleared,
-                        )),
-                        approved: Some(self.auto_approve_transactions),
-                        flag_color: common_data
-                            .transaction_flag_color
-                            .map(transaction_detail_flag_color_to_save_transaction),
-                        import_id: Some(difference_import_id.raw.into()),
-                    },
-                );
             }
-            let transfer_difference_key =
-                foreign_data.transfer_account_id.as_ref().and_then(|id| {
-                    match self.foreign_accounts.get_account_data(id) {
-                        Some(AccountData::Foreign { difference_key }) => Some(*difference_key),
-                        _ => None,
+            let opt_difference_amount =
+                if let Some(difference_transaction) = &opt_existing_difference_transaction {
+                    let opt_amount_and_memo = self.add_transaction_modification(
+                        transactions_modifications,
+                        &TransactionModificationData {
+                            kind: TransactionModificationKind::UpdateDifference,
+                            difference_key,
+                            date: common_data.transaction_date,
+                            payee_name: foreign_data.payee_name,
+                            category_name: difference_data.category_name,
+                            memo: &difference_data.memo,
+                            amount: difference_data.amount,
+                            foreign_amount: foreign_data.amount,
+                            exchange_rate: difference_data.exchange_rate,
+                            fee_amount: difference_data.fee_amount,
+                        },
+                    )?;
+                    if let Some((amount, memo)) = opt_amount_and_memo {
+                        transactions_modifications
+                            .update_ids_foreign_ynab_transaction_ids
+                            .insert(
+                                YnabTransactionId::new(
+                                    difference_transaction
+                                        .difference_transaction_id
+                                        .raw
+                                        .clone()
+                                        .into_owned(),
+                                ),
+                                foreign_ynab_transaction_id.clone(),
+                            );
+                        transactions_modifications.update_transactions.push(
+                            ynab_api::models::UpdateTransaction {
+                                id: difference_transaction.difference_transaction_id.to_string(),
+                                account_id: difference_account_id.to_string(),
+                                date: format_iso_date(common_data.transaction_date),
+                                amount: amount.to_scaled_i64(),
+                                payee_id: foreign_data.payee_id.cloned(),
+                                payee_name: None,
+                                category_id: difference_data.category_id.clone(),
+                                memo: Some(memo),
+                                cleared: Some(transaction_detail_cleared_to_update_transaction(
+                                    common_data.transaction_cleared,
+                                )),
+                                approved: None,
+                                flag_color: common_data
+                                    .transaction_flag_color
+                                    .map(transaction_detail_flag_color_to_update_transaction),
+                                import_id: None,
+                            },
+                        );
+                        Some(amount)
+                    } else {
+                        None
                     }
-                });
-            difference_balances.update(
-                difference_key,
-                transfer_difference_key,
-                difference_data.amount,
-            );
+                } else if !difference_data.amount.is_zero() {
+                    let opt_amount_and_memo = self.add_transaction_modification(
+                        transactions_modifications,
+                        &TransactionModificationData {
+                            kind: TransactionModificationKind::CreateDifference,
+                            difference_key,
+                            date: common_data.transaction_date,
+                            payee_name: foreign_data.payee_name,
+                            category_name: difference_data.category_name,
+                            memo: &difference_data.memo,
+                            amount: difference_data.amount,
+                            foreign_amount: foreign_data.amount,
+                            exchange_rate: difference_data.exchange_rate,
+                            fee_amount: difference_data.fee_amount,
+                        },
+                    )?;
+                    if let Some((amount, memo)) = opt_amount_and_memo {
+                        let difference_import_id = self.import_id_generator.next_import_id();
+                        transactions_modifications
+                            .create_import_ids_foreign_ynab_transaction_ids
+                            .insert(
+                                YnabImportId::new(difference_import_id.raw.clone().into_owned()),
+                                foreign_ynab_transaction_id.clone(),
+                            );
+                        transactions_modifications.create_transactions.push(
+                            ynab_api::models::SaveTransaction {
+                                account_id: difference_account_id.to_string(),
+                                date: format_iso_date(common_data.transaction_date),
+                                amount: amount.to_scaled_i64(),
+                                payee_id: foreign_data.payee_id.cloned(),
+                                payee_name: None,
+                                category_id: difference_data.category_id.clone(),
+                                memo: Some(memo),
+                                cleared: Some(transaction_detail_cleared_to_save_transaction(
+                                    common_data.transaction_cleared,
+                                )),
+                                approved: Some(self.auto_approve_transactions),
+                                flag_color: common_data
+                                    .transaction_flag_color
+                                    .map(transaction_detail_flag_color_to_save_transaction),
+                                import_id: Some(difference_import_id.raw.into()),
+                            },
+                        );
+                        Some(amount)
+                    } else {
+                        None
+                    }
+                } else {
+                    Some(difference_data.amount)
+                };
+            match opt_difference_amount {
+                Some(difference_amount) => {
+                    let mut difference_balances = self.difference_balances.borrow_mut();
+                    if let Some(old_difference_transaction) = &opt_existing_difference_transaction {
+                        difference_balances.update(
+                            old_difference_transaction.difference_key,
+                            old_difference_transaction.transfer_key,
+                            -old_difference_transaction.amount,
+                        );
+                        difference_balances.update_fees(
+                            old_difference_transaction.difference_key,
+                            -old_difference_transaction.fee_amount,
+                        );
+                    }
+                    let transfer_difference_key = foreign_data
+                        .transfer_account_id
+                        .as_ref()
+                        .and_then(|id| match self.foreign_accounts.get_account_data(id) {
+                            Some(AccountData::Foreign { difference_key }) => Some(*difference_key),
+                            _ => None,
+                        });
+                    difference_balances.update(
+                        difference_key,
+                        transfer_difference_key,
+                        difference_amount,
+                    );
+                    difference_balances.update_fees(difference_key, difference_data.fee_amount);
+                }
+                None => {
+                    // Skipped conversions are left out of this run's
+                    // adjustments, so that they are not booked there instead.
+                    if opt_existing_difference_transaction.is_some() {
+                        transactions_modifications
+                            .postponed_adjustment_difference_keys
+                            .insert(difference_key);
+                    } else {
+                        self.difference_balances
+                            .borrow_mut()
+                            .exclude_foreign_amount(difference_key, foreign_data.amount);
+                    }
+                    transactions_modifications
+                        .skipped_foreign_transaction_ids
+                        .insert(foreign_ynab_transaction_id);
+                }
+            }
         } else {
             assert!(
                 difference_data.amount.is_zero(),
@@ -488,34 +972,30 @@ impl<'a> ForeignTransactionsProcessor<'a> {
         &self,
         transactions_modifications: &mut TransactionsModificationsData,
     ) -> Result<()> {
-        println!("Checking for adjustments...");
+        progress!("Checking for adjustments...");
         let difference_balances = self.difference_balances.borrow();
         for (&difference_key, foreign_total_and_difference_balance) in difference_balances.iter() {
             if let Some(difference_account_id) = self
                 .foreign_accounts
                 .get_difference_account_id(difference_key)
             {
-                let exchange_rate = self
-                    .get_transaction_date_exchange_rate(difference_key.currency, self.today_date)?;
-                let expected_difference_account_balance = self.round_to_budget_decimal_digits(
-                    foreign_total_and_difference_balance
-                        .foreign_accounts_total
-                        .convert_currency(exchange_rate)
-                        - foreign_total_and_difference_balance.foreign_accounts_total,
-                );
-                let difference_adjustment_amount = self.round_to_budget_decimal_digits(
-                    expected_difference_account_balance
-                        - foreign_total_and_difference_balance.difference_account_balance,
-                );
-                // We don't create adjustments for less than two of the
-                // smallest currency unit (e.g. two cents), to avoid risk of
-                // "cycles" when YNAB API rounds sub-currency-unit (e.g.
-                // sub-cent) account balances in unpredictable ways.
-                if difference_adjustment_amount.abs()
-                    > Milliunits::smallest_unit(
-                        self.budget_settings.currency_format.decimal_digits as u32,
-                    )
+                if transactions_modifications
+                    .postponed_adjustment_difference_keys
+                    .contains(&difference_key)
+                {
+                    progress!(
+                        "  Not adjusting {} until its skipped updates are accepted.",
+                        difference_key
+                    );
+                    continue;
+                }
+                let (rate_info, difference_adjustment_amount) = match self
+                    .difference_adjustment(difference_key, foreign_total_and_difference_balance)?
                 {
+                    Some(adjustment) => (adjustment.rate_info, adjustment.amount),
+                    None => continue,
+                };
+                if !difference_adjustment_amount.is_zero() {
                     let adjustment_payee_name = format_adjustment_payee_name(difference_key);
                     let adjustment_memo = format!(
                         "{}{}",
@@ -523,27 +1003,39 @@ impl<'a> ForeignTransactionsProcessor<'a> {
                         self.format_exchange(
                             difference_key.currency,
                             foreign_total_and_difference_balance.foreign_accounts_total,
-                            exchange_rate
+                            rate_info,
+                            None
                         )
                     );
-                    self.print_transaction_modification(&TransactionModificationData {
-                        prefix: "Create adjustment",
-                        difference_key,
-                        date: self.today_date,
-                        payee_name: Some(&adjustment_payee_name),
-                        category_name: None,
-                        memo: &adjustment_memo,
-                        amount: difference_adjustment_amount,
-                    });
+                    let opt_amount_and_memo = self.add_transaction_modification(
+                        transactions_modifications,
+                        &TransactionModificationData {
+                            kind: TransactionModificationKind::CreateAdjustment,
+                            difference_key,
+                            date: self.today_date,
+                            payee_name: Some(&adjustment_payee_name),
+                            category_name: None,
+                            memo: &adjustment_memo,
+                            amount: difference_adjustment_amount,
+                            foreign_amount: foreign_total_and_difference_balance
+                                .foreign_accounts_total,
+                            exchange_rate: Some(rate_info.exchange_rate),
+                            fee_amount: Milliunits::zero(),
+                        },
+                    )?;
+                    let (amount, memo) = match opt_amount_and_memo {
+                        Some(amount_and_memo) => amount_and_memo,
+                        None => continue,
+                    };
                     transactions_modifications.create_transactions.push(
                         ynab_api::models::SaveTransaction {
                             account_id: difference_account_id.to_string(),
                             date: format_iso_date(self.today_date),
-                            amount: difference_adjustment_amount.to_scaled_i64(),
+                            amount: amount.to_scaled_i64(),
                             payee_id: None,
                             payee_name: Some(adjustment_payee_name),
                             category_id: None,
-                            memo: Some(adjustment_memo),
+                            memo: Some(memo),
                             cleared: None,
                             approved: Some(self.auto_approve_adjustments),
                             flag_color: None,
@@ -556,6 +1048,53 @@ impl<'a> ForeignTransactionsProcessor<'a> {
         Ok(())
     }
 
+    fn difference_adjustment(
+        &self,
+        difference_key: DifferenceKey,
+        foreign_total_and_difference_balance: &ForeignTotalAndDifferenceBalance,
+    ) -> Result<Option<DifferenceAdjustment>> {
+        let rate_info = match self
+            .get_transaction_date_exchange_rate(difference_key.currency, self.today_date)?
+        {
+            Some(rate_info) => rate_info,
+            None => return Ok(None),
+        };
+        // Fees were really charged, so they stay in the difference account
+        // regardless of how the exchange rate changes.
+        let foreign_accounts_total = foreign_total_and_difference_balance.foreign_accounts_total;
+        let expected_difference_account_balance = self.round_to_budget_decimal_digits(
+            self.round_to_currency_minor_units(difference_key.currency, foreign_accounts_total)
+                .convert_currency(rate_info.exchange_rate),
+        ) - foreign_accounts_total
+            + foreign_total_and_difference_balance.fees_total;
+        let difference_adjustment_amount = self.round_to_budget_decimal_digits(
+            expected_difference_account_balance
+                - foreign_total_and_difference_balance.difference_account_balance,
+        );
+        // We don't create adjustments for less than two of the smallest
+        // currency unit (e.g. two cents), to avoid risk of "cycles" when YNAB
+        // API rounds sub-currency-unit (e.g. sub-cent) account balances in
+        // unpredictable ways.  When the foreign currency's smallest unit is
+        // worth more (e.g. one Euro cent in a Japanese Yen budget), that is
+        // used instead.
+        let adjustment_threshold = std::cmp::max(
+            Milliunits::smallest_unit(self.budget_settings.currency_format.decimal_digits as u32),
+            self.round_to_budget_decimal_digits(
+                Milliunits::smallest_unit(self.currency_minor_units(difference_key.currency))
+                    .convert_currency(rate_info.exchange_rate),
+            ),
+        );
+        Ok(Some(DifferenceAdjustment {
+            rate_info,
+            expected_difference_account_balance,
+            amount: if difference_adjustment_amount.abs() > adjustment_threshold {
+                difference_adjustment_amount
+            } else {
+                Milliunits::zero()
+            },
+        }))
+    }
+
     fn save_transactions<'b>(
         &self,
         transactions_modifications: TransactionsModificationsData<'b>,
@@ -563,7 +1102,7 @@ impl<'a> ForeignTransactionsProcessor<'a> {
         let mut database_create_difference_transactions = Vec::new();
         let mut database_update_difference_transactions = Vec::new();
         let had_changes = if transactions_modifications.has_changes() {
-            println!("No new/changed difference transactions; nothing to do!");
+            progress!("No new/changed difference transactions; nothing to do!");
             false
         } else {
             debug!(
@@ -571,7 +1110,7 @@ impl<'a> ForeignTransactionsProcessor<'a> {
                 transactions_modifications.update_transactions
             );
             if !transactions_modifications.update_transactions.is_empty() && !self.dry_run {
-                println!("Saving changed transactions to YNAB...");
+                progress!("Saving changed transactions to YNAB...");
                 for update_transactions_chunk in transactions_modifications
                     .update_transactions
                     .chunks(SAVE_TRANSACTIONS_CHUNK_SIZE)
@@ -585,6 +1124,24 @@ impl<'a> ForeignTransactionsProcessor<'a> {
                     );
                     for updated_transaction in updated_transactions {
                         let updated_transaction_id = YnabTransactionId::new(updated_transaction.id);
+                        let opt_foreign_ynab_transaction_id = transactions_modifications
+                            .update_ids_foreign_ynab_transaction_ids
+                            .get(&updated_transaction_id);
+                        let exchange_rate_date = opt_foreign_ynab_transaction_id
+                            .and_then(|foreign_ynab_transaction_id| {
+                                transactions_modifications
+                                    .foreign_ynab_transaction_ids_exchange_rate_dates
+                                    .get(foreign_ynab_transaction_id)
+                            })
+                            .cloned();
+                        let fee_amount = opt_foreign_ynab_transaction_id
+                            .and_then(|foreign_ynab_transaction_id| {
+                                transactions_modifications
+                                    .foreign_ynab_transaction_ids_fee_amounts
+                                    .get(foreign_ynab_transaction_id)
+                            })
+                            .cloned()
+                            .unwrap_or_else(Milliunits::zero);
                         database_update_difference_transactions.push(DifferenceTransaction {
                             difference_transaction_id: updated_transaction_id,
                             amount: Milliunits::from_scaled_i64(updated_transaction.amount),
@@ -594,6 +1151,8 @@ impl<'a> ForeignTransactionsProcessor<'a> {
                             transfer_key: updated_transaction.transfer_account_id.and_then(|a| {
                                 self.transfer_account_key_for_save(&YnabAccountId::new(a))
                             }),
+                            exchange_rate_date,
+                            fee_amount,
                         });
                     }
                 }
@@ -603,7 +1162,7 @@ impl<'a> ForeignTransactionsProcessor<'a> {
                 transactions_modifications.create_transactions
             );
             if !transactions_modifications.create_transactions.is_empty() && !self.dry_run {
-                println!("Saving new transactions to YNAB...");
+                progress!("Saving new transactions to YNAB...");
                 for create_transactions_chunk in transactions_modifications
                     .create_transactions
                     .chunks(SAVE_TRANSACTIONS_CHUNK_SIZE)
@@ -641,6 +1200,15 @@ impl<'a> ForeignTransactionsProcessor<'a> {
                                                         &YnabAccountId::new(a),
                                                     )
                                                 }),
+                                            exchange_rate_date: transactions_modifications
+                                                .foreign_ynab_transaction_ids_exchange_rate_dates
+                                                .get(foreign_ynab_transaction_id)
+                                                .cloned(),
+                                            fee_amount: transactions_modifications
+                                                .foreign_ynab_transaction_ids_fee_amounts
+                                                .get(foreign_ynab_transaction_id)
+                                                .cloned()
+                                                .unwrap_or_else(Milliunits::zero),
                                         },
                                     },
                                 );
@@ -650,19 +1218,24 @@ impl<'a> ForeignTransactionsProcessor<'a> {
                 }
             }
             if self.dry_run {
-                println!("\nNOTE: No transactions were actually saved.");
-                println!("Re-run with '--yes' to save the changes to YNAB.");
+                progress!("\nNOTE: No transactions were actually saved.");
+                progress!("Re-run with '--yes' to save the changes to YNAB.");
             } else {
-                println!("Done!");
+                progress!("Done!");
             }
             true
         };
         Ok(UpdateBudgetState {
             had_changes,
+            currency: self.local_currency,
             create_difference_transactions: database_create_difference_transactions,
             update_difference_transactions: database_update_difference_transactions,
             delete_difference_transaction_ids: transactions_modifications
                 .delete_difference_transaction_ids,
+            recomputed_foreign_transaction_ids: transactions_modifications
+                .recomputed_foreign_transaction_ids,
+            skipped_foreign_transaction_ids: transactions_modifications
+                .skipped_foreign_transaction_ids,
         })
     }
 
@@ -684,7 +1257,7 @@ impl<'a> ForeignTransactionsProcessor<'a> {
         &self,
         from_currency: CurrencyCode,
         date: NaiveDate,
-    ) -> Result<ExchangeRate> {
+    ) -> Result<Option<ExchangeRateInfo>> {
         self.exchange_rates_cache.get_exchange_rate(
             self.foreign_accounts.get_all_used_foreign_currencies(),
             from_currency,
@@ -693,46 +1266,178 @@ impl<'a> ForeignTransactionsProcessor<'a> {
         )
     }
 
+    /// Print a planned transaction, and remember it for `--output json`.
+    /// Returns the amount and memo to save.  In `--interactive` mode, the user
+    /// may also skip it (returning `None`) or change its amount.
+    fn add_transaction_modification(
+        &self,
+        transactions_modifications: &mut TransactionsModificationsData,
+        data: &TransactionModificationData,
+    ) -> Result<Option<(Milliunits, String)>> {
+        self.print_transaction_modification(data);
+        let opt_amount = match self.prompter {
+            Some(prompter) => self.prompt_transaction_modification(prompter, data.amount)?,
+            None => Some(data.amount),
+        };
+        // The memo describes the computed amount, so note when the user
+        // changed it.
+        let memo = match opt_amount {
+            Some(amount) if amount != data.amount => edited_memo(
+                data.memo,
+                &format!(
+                    "edited to {}",
+                    self.budget_formatter.format_milliunits(amount)
+                ),
+            ),
+            _ => data.memo.to_string(),
+        };
+        let planned = serde_json::json!({
+            "difference_key": {
+                "currency": data.difference_key.currency.to_str(),
+                "account_class": data.difference_key.account_class.to_string(),
+            },
+            "date": format_iso_date(data.date),
+            "payee_name": data.payee_name,
+            "category_name": data.category_name,
+            "memo": memo,
+            "amount_milliunits": opt_amount.unwrap_or(data.amount).to_scaled_i64(),
+            "foreign_amount_milliunits": data.foreign_amount.to_scaled_i64(),
+            "exchange_rate": data.exchange_rate.map(|rate| rate.to_string()),
+            "fee_amount_milliunits": data.fee_amount.to_scaled_i64(),
+        });
+        if opt_amount.is_some() {
+            transactions_modifications
+                .planned_modifications
+                .push((data.kind, planned));
+        } else {
+            transactions_modifications
+                .skipped_modifications
+                .push((data.kind, planned));
+        }
+        Ok(opt_amount.map(|amount| (amount, memo)))
+    }
+
+    /// Ask whether to accept, skip or change the amount of a planned
+    /// transaction.  Quitting stops without saving anything.
+    fn prompt_transaction_modification(
+        &self,
+        prompter: &dyn Prompter,
+        amount: Milliunits,
+    ) -> Result<Option<Milliunits>> {
+        loop {
+            match prompter
+                .prompt("  Accept, skip, edit amount or quit? [a/s/e/q]")?
+                .to_lowercase()
+                .as_str()
+            {
+                "a" | "accept" => return Ok(Some(amount)),
+                "s" | "skip" => return Ok(None),
+                "e" | "edit" => {
+                    let answer = prompter.prompt(&format!(
+                        "  New amount (was {}):",
+                        self.budget_formatter.format_milliunits(amount)
+                    ))?;
+                    match answer.parse::<Decimal>() {
+                        Ok(new_amount) if !new_amount.is_zero() => {
+                            let new_amount = self.round_to_budget_decimal_digits(
+                                Milliunits::from_decimal(new_amount),
+                            );
+                            progress!(
+                                "  Amount changed to {}",
+                                self.budget_formatter.format_milliunits(new_amount)
+                            );
+                            return Ok(Some(new_amount));
+                        }
+                        _ => progress!("  Invalid amount (use e.g. -12.34): {}", answer),
+                    }
+                }
+                "q" | "quit" => bail!("Quit; no changes were saved to the YNAB budget"),
+                _ => {}
+            }
+        }
+    }
+
     fn print_transaction_modification(&self, data: &TransactionModificationData) {
-        println!("  {} transaction:", data.prefix,);
-        println!("     Account: Difference {}", data.difference_key);
-        println!(
+        progress!(
+            "  {} transaction:",
+            match data.kind {
+                TransactionModificationKind::CreateDifference => "Create difference",
+                TransactionModificationKind::UpdateDifference => "Update difference",
+                TransactionModificationKind::CreateAdjustment => "Create adjustment",
+            }
+        );
+        progress!("     Account: Difference {}", data.difference_key);
+        progress!(
             "        Date: {}",
             self.budget_formatter.format_date(data.date)
         );
         if let Some(payee_name) = data.payee_name {
-            println!("       Payee: {}", payee_name);
+            progress!("       Payee: {}", payee_name);
         }
         if let Some(category) = data.category_name {
-            println!("    Category: {}", category);
+            progress!("    Category: {}", category);
         }
-        println!("        Memo: {}", data.memo);
-        println!(
+        progress!("        Memo: {}", data.memo);
+        progress!(
             "      Amount: {}",
             self.budget_formatter.format_milliunits(data.amount)
         )
     }
 
+    /// Describe a conversion for a memo.  `opt_rate_date` is the date whose
+    /// rate was used, if it is not the transaction's date.
     fn format_exchange(
         &self,
         currency: CurrencyCode,
         amount: Milliunits,
-        exchange_rate: ExchangeRate,
+        rate_info: ExchangeRateInfo,
+        opt_rate_date: Option<NaiveDate>,
     ) -> String {
         format!(
-            "{} @{}/{} = {}",
+            "{} @{}/{} = {}{}",
             self.budget_formatter
                 .format_milliunits_with_code(currency, amount),
-            self.budget_formatter.format_exchange_rate(exchange_rate),
+            self.budget_formatter
+                .format_exchange_rate(rate_info.exchange_rate),
             currency,
             self.budget_formatter
-                .format_milliunits(amount.convert_currency(exchange_rate)),
+                .format_milliunits(amount.convert_currency(rate_info.exchange_rate)),
+            match (opt_rate_date, rate_info.fallback_date) {
+                (Some(rate_date), Some(fallback_date)) => format!(
+                    " (rate for {} from {})",
+                    self.budget_formatter.format_date(rate_date),
+                    self.budget_formatter.format_date(fallback_date)
+                ),
+                (Some(rate_date), None) => format!(
+                    " (rate for {})",
+                    self.budget_formatter.format_date(rate_date)
+                ),
+                (None, Some(fallback_date)) => format!(
+                    " (rate from {})",
+                    self.budget_formatter.format_date(fallback_date)
+                ),
+                (None, None) => "".to_string(),
+            }
         )
     }
 
     fn round_to_budget_decimal_digits(&self, amount: Milliunits) -> Milliunits {
         amount.round_bankers(self.budget_settings.currency_format.decimal_digits as u32)
     }
+
+    fn currency_minor_units(&self, currency: CurrencyCode) -> u32 {
+        self.currency_registry
+            .minor_units(currency)
+            .unwrap_or(MAX_MINOR_UNITS)
+    }
+
+    fn round_to_currency_minor_units(
+        &self,
+        currency: CurrencyCode,
+        amount: Milliunits,
+    ) -> Milliunits {
+        amount.round_bankers(self.currency_minor_units(currency))
+    }
 }
 
 impl<'a> TransactionsModificationsData<'a> {
@@ -741,7 +1446,15 @@ impl<'a> TransactionsModificationsData<'a> {
             create_transactions: Vec::new(),
             update_transactions: Vec::new(),
             create_import_ids_foreign_ynab_transaction_ids: HashMap::new(),
+            update_ids_foreign_ynab_transaction_ids: HashMap::new(),
+            foreign_ynab_transaction_ids_exchange_rate_dates: HashMap::new(),
+            foreign_ynab_transaction_ids_fee_amounts: HashMap::new(),
             delete_difference_transaction_ids: HashSet::new(),
+            recomputed_foreign_transaction_ids: HashSet::new(),
+            skipped_foreign_transaction_ids: HashSet::new(),
+            postponed_adjustment_difference_keys: HashSet::new(),
+            planned_modifications: Vec::new(),
+            skipped_modifications: Vec::new(),
         }
     }
 
@@ -749,3 +1462,894 @@ impl<'a> TransactionsModificationsData<'a> {
         self.create_transactions.is_empty() && self.update_transactions.is_empty()
     }
 }
+
+/// The contents of a saved plan file.  Maps and sets are sorted, so that
+/// the same plan is always written the same way.
+#[derive(Deserialize, Serialize)]
+struct SavedPlanFile {
+    version: i64,
+    budget_id: String,
+    date: String,
+    start_date: String,
+    previous_server_knowledge: Option<i64>,
+    server_knowledge: i64,
+    create_transactions: Vec<ynab_api::models::SaveTransaction>,
+    update_transactions: Vec<ynab_api::models::UpdateTransaction>,
+    create_import_ids_foreign_ynab_transaction_ids: BTreeMap<String, String>,
+    update_ids_foreign_ynab_transaction_ids: BTreeMap<String, String>,
+    foreign_ynab_transaction_ids_exchange_rate_dates: BTreeMap<String, String>,
+    foreign_ynab_transaction_ids_fee_amounts: BTreeMap<String, i64>,
+    delete_difference_transaction_ids: BTreeSet<String>,
+    recomputed_foreign_transaction_ids: BTreeSet<String>,
+}
+
+impl SavedPlan {
+    const VERSION: i64 = 1;
+
+    fn write(
+        path: &str,
+        budget_id: &str,
+        date: NaiveDate,
+        budget_state: &BudgetState,
+        server_knowledge: i64,
+        transactions_modifications: &TransactionsModificationsData,
+    ) -> Result<()> {
+        let contents = Self::to_json(
+            budget_id,
+            date,
+            budget_state,
+            server_knowledge,
+            transactions_modifications,
+        );
+        std::fs::write(path, contents).chain_err(|| format!("Failed to write plan file: {}", path))
+    }
+
+    fn read(path: &str) -> Result<SavedPlan> {
+        let contents = std::fs::read_to_string(path)
+            .chain_err(|| format!("Failed to read plan file: {}", path))?;
+        Self::from_str(&contents).chain_err(|| format!("Invalid plan file: {}", path))
+    }
+
+    fn to_json(
+        budget_id: &str,
+        date: NaiveDate,
+        budget_state: &BudgetState,
+        server_knowledge: i64,
+        transactions_modifications: &TransactionsModificationsData,
+    ) -> String {
+        let plan_file = SavedPlanFile {
+            version: Self::VERSION,
+            budget_id: budget_id.to_string(),
+            date: format_iso_date(date),
+            start_date: format_iso_date(budget_state.start_date),
+            previous_server_knowledge: budget_state.ynab_server_knowledge,
+            server_knowledge,
+            create_transactions: transactions_modifications.create_transactions.clone(),
+            update_transactions: transactions_modifications.update_transactions.clone(),
+            create_import_ids_foreign_ynab_transaction_ids: map_to_strings(
+                &transactions_modifications.create_import_ids_foreign_ynab_transaction_ids,
+                ToString::to_string,
+            ),
+            update_ids_foreign_ynab_transaction_ids: map_to_strings(
+                &transactions_modifications.update_ids_foreign_ynab_transaction_ids,
+                ToString::to_string,
+            ),
+            foreign_ynab_transaction_ids_exchange_rate_dates: map_to_strings(
+                &transactions_modifications.foreign_ynab_transaction_ids_exchange_rate_dates,
+                |&date| format_iso_date(date),
+            ),
+            foreign_ynab_transaction_ids_fee_amounts: map_to_strings(
+                &transactions_modifications.foreign_ynab_transaction_ids_fee_amounts,
+                |amount| amount.to_scaled_i64(),
+            ),
+            delete_difference_transaction_ids: transactions_modifications
+                .delete_difference_transaction_ids
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            recomputed_foreign_transaction_ids: transactions_modifications
+                .recomputed_foreign_transaction_ids
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        };
+        serde_json::to_string_pretty(&plan_file).expect("Plan should serialize")
+    }
+
+    fn from_str(contents: &str) -> Result<SavedPlan> {
+        let plan_value = serde_json::from_str::<serde_json::Value>(contents)
+            .chain_err(|| "Plan is not valid JSON")?;
+        // Check the version first, so that a plan from another version gets a
+        // clear error rather than a missing field.
+        let version = plan_value
+            .get("version")
+            .and_then(serde_json::Value::as_i64)
+            .chain_err(|| "Plan has no version")?;
+        ensure!(
+            version == Self::VERSION,
+            "Unsupported plan version: {}",
+            version
+        );
+        let plan_file = serde_json::from_value::<SavedPlanFile>(plan_value)
+            .chain_err(|| "Plan has missing or invalid fields")?;
+        let foreign_ynab_transaction_ids_exchange_rate_dates = plan_file
+            .foreign_ynab_transaction_ids_exchange_rate_dates
+            .into_iter()
+            .map(|(key, value)| Ok((YnabTransactionId::new(key), parse_iso_date(&value)?)))
+            .collect::<Result<_>>()?;
+        Ok(SavedPlan {
+            budget_id: plan_file.budget_id,
+            date: parse_iso_date(&plan_file.date)?,
+            start_date: parse_iso_date(&plan_file.start_date)?,
+            previous_server_knowledge: plan_file.previous_server_knowledge,
+            server_knowledge: plan_file.server_knowledge,
+            transactions_modifications: TransactionsModificationsData {
+                create_transactions: plan_file.create_transactions,
+                update_transactions: plan_file.update_transactions,
+                create_import_ids_foreign_ynab_transaction_ids: plan_file
+                    .create_import_ids_foreign_ynab_transaction_ids
+                    .into_iter()
+                    .map(|(key, value)| (YnabImportId::new(key), YnabTransactionId::new(value)))
+                    .collect(),
+                update_ids_foreign_ynab_transaction_ids: plan_file
+                    .update_ids_foreign_ynab_transaction_ids
+                    .into_iter()
+                    .map(|(key, value)| {
+                        (YnabTransactionId::new(key), YnabTransactionId::new(value))
+                    })
+                    .collect(),
+                foreign_ynab_transaction_ids_exchange_rate_dates,
+                foreign_ynab_transaction_ids_fee_amounts: plan_file
+                    .foreign_ynab_transaction_ids_fee_amounts
+                    .into_iter()
+                    .map(|(key, value)| {
+                        (
+                            YnabTransactionId::new(key),
+                            Milliunits::from_scaled_i64(value),
+                        )
+                    })
+                    .collect(),
+                delete_difference_transaction_ids: plan_file
+                    .delete_difference_transaction_ids
+                    .into_iter()
+                    .map(YnabTransactionId::new)
+                    .collect(),
+                recomputed_foreign_transaction_ids: plan_file
+                    .recomputed_foreign_transaction_ids
+                    .into_iter()
+                    .map(YnabTransactionId::new)
+                    .collect(),
+                skipped_foreign_transaction_ids: HashSet::new(),
+                postponed_adjustment_difference_keys: HashSet::new(),
+                planned_modifications: Vec::new(),
+                skipped_modifications: Vec::new(),
+            },
+        })
+    }
+}
+
+/// Convert a map with ID keys to one with string keys, for a saved plan.
+fn map_to_strings<K: fmt::Display, V, T>(
+    map: &HashMap<K, V>,
+    value_fn: impl Fn(&V) -> T,
+) -> BTreeMap<String, T> {
+    map.iter()
+        .map(|(key, value)| (key.to_string(), value_fn(value)))
+        .collect()
+}
+
+/// The planned transactions as a JSON document, for `--output json`.
+fn json_plan(
+    budget_id: &str,
+    today_date: NaiveDate,
+    dry_run: bool,
+    server_knowledge: i64,
+    transactions_modifications: &TransactionsModificationsData,
+) -> serde_json::Value {
+    let planned_modifications = &transactions_modifications.planned_modifications;
+    let of_kind = |kind| {
+        planned_modifications
+            .iter()
+            .filter(|(planned_kind, _)| *planned_kind == kind)
+            .map(|(_, planned)| planned)
+            .collect::<Vec<_>>()
+    };
+    let creates = of_kind(TransactionModificationKind::CreateDifference);
+    let updates = of_kind(TransactionModificationKind::UpdateDifference);
+    let adjustments = of_kind(TransactionModificationKind::CreateAdjustment);
+    let skipped = transactions_modifications
+        .skipped_modifications
+        .iter()
+        .map(|(kind, planned)| {
+            let mut skipped = planned.clone();
+            skipped["kind"] = serde_json::Value::from(match kind {
+                TransactionModificationKind::CreateDifference => "create",
+                TransactionModificationKind::UpdateDifference => "update",
+                TransactionModificationKind::CreateAdjustment => "adjustment",
+            });
+            skipped
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "budget_id": budget_id,
+        "date": format_iso_date(today_date),
+        "dry_run": dry_run,
+        "server_knowledge": server_knowledge,
+        "creates": creates,
+        "updates": updates,
+        "adjustments": adjustments,
+        "skipped": skipped,
+        "summary": {
+            "creates": creates.len(),
+            "updates": updates.len(),
+            "adjustments": adjustments.len(),
+            "skipped": skipped.len(),
+            "saved": !dry_run && !planned_modifications.is_empty(),
+        },
+    })
+}
+
+/// Add a note to a memo: inside a difference transaction memo's tag, or at
+/// the end of an adjustment memo.
+fn edited_memo(memo: &str, note: &str) -> String {
+    match memo.find('>') {
+        Some(tag_end) if memo.starts_with('<') => {
+            format!("{}, {}{}", &memo[..tag_end], note, &memo[tag_end..])
+        }
+        _ => format!("{} ({})", memo, note),
+    }
+}
+
+/// Get the date from a memo's `<POSTED YYYY-MM-DD>` tag, if it has one.
+fn memo_posting_date(
+    memo: &Option<String>,
+    ynab_transaction_id: &YnabTransactionId,
+) -> Option<NaiveDate> {
+    let captures = memo
+        .as_ref()
+        .and_then(|memo| POSTING_DATE_REGEX.captures(memo))?;
+    let posting_date = captures
+        .get(1)
+        .expect("POSTING_DATE_REGEX should have capture group")
+        .as_str();
+    match parse_iso_date(posting_date) {
+        Ok(date) => Some(date),
+        Err(err) => {
+            // Rather than failing the whole sync, fall back to the rate date
+            // policy for this transaction.
+            warn!(
+                "Ignoring invalid posting date {} for transaction {}: {}",
+                posting_date, ynab_transaction_id, err
+            );
+            None
+        }
+    }
+}
+
+/// Add transactions whose difference transactions were flagged for
+/// recomputation (e.g. because an exchange rate override changed), unless
+/// they are already among the latest transactions.
+fn add_recompute_transactions(
+    ynab_client: &dyn YnabBudget,
+    start_date: NaiveDate,
+    recompute_foreign_transaction_ids: &HashSet<YnabTransactionId>,
+    transactions: &mut Vec<ynab_api::models::TransactionDetail>,
+) -> Result<()> {
+    let is_recompute_transaction = |transaction: &ynab_api::models::TransactionDetail| {
+        recompute_foreign_transaction_ids.contains(&YnabTransactionId::new(transaction.id.clone()))
+            || transaction.subtransactions.iter().any(|subtransaction| {
+                recompute_foreign_transaction_ids
+                    .contains(&YnabTransactionId::new(subtransaction.id.clone()))
+            })
+    };
+    let latest_transaction_ids: HashSet<String> = transactions
+        .iter()
+        .map(|transaction| transaction.id.clone())
+        .collect();
+    progress!("Loading transactions to recompute from YNAB...");
+    let all_transactions = ynab_client.get_transactions(Some(start_date), None)?;
+    transactions.extend(
+        all_transactions
+            .transactions
+            .into_iter()
+            .filter(|transaction| {
+                !latest_transaction_ids.contains(&transaction.id)
+                    && is_recompute_transaction(transaction)
+            }),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_ynab_budget::*;
+    use crate::mock_ynab_server::*;
+    use crate::test_support::*;
+    use chrono::{TimeZone, Utc};
+    use std::collections::VecDeque;
+    use ynab_api::models::account::Type;
+
+    /// The date the tests' syncs run as of.
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd(2020, 3, 10)
+    }
+
+    fn yesterday() -> NaiveDate {
+        today() - Duration::days(1)
+    }
+
+    fn sync(database: &Database, budget: &FakeYnabBudget) {
+        sync_from(database, budget, None);
+    }
+
+    /// Sync the budget, converting euros to U.S. dollars at a fixed 1.1.
+    fn sync_from(database: &Database, budget: &dyn YnabBudget, start_date: Option<NaiveDate>) {
+        run_sync(database, budget, start_date, None, None);
+    }
+
+    /// Sync the budget in `--interactive` mode, giving the answers in order,
+    /// and return the JSON plan.
+    fn sync_interactively(
+        database: &Database,
+        budget: &FakeYnabBudget,
+        answers: &[&'static str],
+    ) -> serde_json::Value {
+        let prompter = ScriptedPrompter {
+            answers: RefCell::new(answers.iter().cloned().collect()),
+        };
+        let plan = run_sync(database, budget, None, None, Some(&prompter));
+        assert!(prompter.answers.borrow().is_empty(), "Unused answers");
+        plan
+    }
+
+    /// Sync the budget, or only save a plan to `save_plan_file` if given.
+    fn run_sync(
+        database: &Database,
+        budget: &dyn YnabBudget,
+        start_date: Option<NaiveDate>,
+        save_plan_file: Option<&str>,
+        prompter: Option<&dyn Prompter>,
+    ) -> serde_json::Value {
+        let currency_registry = CurrencyRegistry::new(&[]);
+        let exchange_rates_cache = ExchangeRatesCache::new(
+            ExchangeRateSource::Offline(MissingRatePolicy::Fail),
+            database,
+            parse_fixed_exchange_rates(vec!["EUR_USD=1.1", "JPY_USD=0.01"]).unwrap(),
+            None,
+            0,
+            None,
+        );
+        ForeignTransactionsProcessor::run(
+            database,
+            budget,
+            &exchange_rates_cache,
+            &currency_registry,
+            start_date,
+            save_plan_file.is_some(),
+            false,
+            false,
+            RateDatePolicy::SameDay,
+            ForeignAmountStyle::Local,
+            save_plan_file,
+            prompter,
+            &FixedClock::new(today(), Utc.ymd(2020, 3, 10).and_hms(12, 0, 0)),
+        )
+        .unwrap()
+    }
+
+    struct ScriptedPrompter {
+        answers: RefCell<VecDeque<&'static str>>,
+    }
+
+    impl Prompter for ScriptedPrompter {
+        fn prompt(&self, question: &str) -> Result<String> {
+            let answer = self
+                .answers
+                .borrow_mut()
+                .pop_front()
+                .unwrap_or_else(|| panic!("No answer for prompt: {}", question));
+            Ok(answer.to_string())
+        }
+    }
+
+    fn new_euro_budget() -> (FakeYnabBudget, String, String) {
+        let budget = FakeYnabBudget::new("USD");
+        let euro_account_id = budget.add_account("Euro <EUR>", Type::Checking);
+        let difference_account_id = budget.add_account("<EUR DIFFERENCE>", Type::Checking);
+        (budget, euro_account_id, difference_account_id)
+    }
+
+    fn amounts_and_memos(transactions: &[ynab_api::models::TransactionDetail]) -> Vec<(i64, &str)> {
+        let mut amounts_and_memos = transactions
+            .iter()
+            .map(|transaction| {
+                (
+                    transaction.amount,
+                    transaction.memo.as_ref().map_or("", String::as_str),
+                )
+            })
+            .collect::<Vec<_>>();
+        amounts_and_memos.sort();
+        amounts_and_memos
+    }
+
+    #[test]
+    fn test_sync_splits() {
+        with_test_database("splits", |database| {
+            let (budget, euro_account_id, difference_account_id) = new_euro_budget();
+            budget.add_split_transaction(&euro_account_id, yesterday(), &[-10_000, -20_000]);
+            sync(database, &budget);
+            let difference_transactions = budget.account_transactions(&difference_account_id);
+            assert_eq!(
+                amounts_and_memos(&difference_transactions),
+                vec![
+                    (
+                        -2_000,
+                        "<CONVERT: EUR -20.00 @$1.1/EUR = -$22.00 (split 2/2)>"
+                    ),
+                    (
+                        -1_000,
+                        "<CONVERT: EUR -10.00 @$1.1/EUR = -$11.00 (split 1/2)>"
+                    ),
+                ]
+            );
+            // Nothing changed, so the second run leaves the budget alone.
+            sync(database, &budget);
+            assert_eq!(budget.account_transactions(&difference_account_id).len(), 2);
+        });
+    }
+
+    #[test]
+    fn test_sync_sub_unit_amounts() {
+        with_test_database("sub-unit", |database| {
+            let budget = FakeYnabBudget::new("USD");
+            let yen_account_id = budget.add_account("Yen <JPY>", Type::Checking);
+            let difference_account_id = budget.add_account("<JPY DIFFERENCE>", Type::Checking);
+            // Yen have no minor units, but YNAB records milliunits.
+            budget.add_transaction(&yen_account_id, yesterday(), -1_000_400, Some("Ramen"));
+            sync(database, &budget);
+            let difference_transactions = budget.account_transactions(&difference_account_id);
+            assert_eq!(difference_transactions.len(), 1);
+            assert_eq!(difference_transactions[0].amount, 990_400);
+            // Together, the accounts hold the converted amount of the rounded
+            // yen, so no adjustment is needed.
+            assert_eq!(
+                budget.account(&yen_account_id).balance
+                    + budget.account(&difference_account_id).balance,
+                -10_000
+            );
+            sync(database, &budget);
+            assert_eq!(budget.account_transactions(&difference_account_id).len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_sync_read_only_database() {
+        let file = TempFile::new("read-only.sqlite3");
+        let database = Database::establish_connection(file.path(), false).unwrap();
+        let (budget, _, _) = new_euro_budget();
+        let checking_account_id = budget.add_account("Checking", Type::Checking);
+        sync(&database, &budget);
+        let budget_state = || {
+            let state = database
+                .get_budget_state(budget.budget_id())
+                .unwrap()
+                .unwrap();
+            (state.ynab_server_knowledge, state.last_run_date)
+        };
+        let initial_state = budget_state();
+        budget.add_transaction(&checking_account_id, yesterday(), -5000, Some("Coffee"));
+        let read_only_database = Database::establish_connection(file.path(), false)
+            .unwrap()
+            .read_only();
+        sync(&read_only_database, &budget);
+        assert_eq!(budget_state(), initial_state);
+        sync(&database, &budget);
+        assert_ne!(budget_state(), initial_state);
+    }
+
+    #[test]
+    fn test_sync_transfers() {
+        with_test_database("transfers", |database| {
+            let (budget, euro_account_id, difference_account_id) = new_euro_budget();
+            let local_account_id = budget.add_account("Checking <CONVERT>", Type::Checking);
+            budget.add_transfer(&local_account_id, &euro_account_id, yesterday(), 10_000);
+            sync(database, &budget);
+            let difference_transactions = budget.account_transactions(&difference_account_id);
+            assert_eq!(difference_transactions.len(), 1);
+            assert_eq!(difference_transactions[0].amount, 1_000);
+            assert_eq!(
+                difference_transactions[0].transfer_account_id,
+                Some(local_account_id.clone())
+            );
+            // The difference is transferred from the local account, which
+            // paid the converted amount.
+            assert_eq!(budget.account(&local_account_id).balance, -11_000);
+            sync(database, &budget);
+            assert_eq!(budget.account_transactions(&difference_account_id).len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_sync_lowercase_tags() {
+        with_test_database("lowercase-tags", |database| {
+            let budget = FakeYnabBudget::new("USD");
+            let euro_account_id = budget.add_account("Euro <eur>", Type::Checking);
+            let difference_account_id = budget.add_account("<eur difference>", Type::Checking);
+            // Not a known currency, so not a currency tag.
+            let card_account_id = budget.add_account("Checking <card>", Type::Checking);
+            budget.add_transaction(&euro_account_id, yesterday(), -10_000, Some("Lunch"));
+            budget.add_transaction(&card_account_id, yesterday(), -5_000, None);
+            sync(database, &budget);
+            assert_eq!(
+                amounts_and_memos(&budget.account_transactions(&difference_account_id)),
+                vec![(-1_000, "<CONVERT: EUR -10.00 @$1.1/EUR = -$11.00> Lunch")]
+            );
+        });
+    }
+
+    #[test]
+    fn test_sync_ignores_unknown_currency_tags() {
+        with_test_database("unknown-currency", |database| {
+            let budget = FakeYnabBudget::new("USD");
+            let euro_account_id = budget.add_account("Euro <EUR> <TODO>", Type::Checking);
+            let difference_account_id = budget.add_account("<EUR DIFFERENCE>", Type::Checking);
+            let other_account_id = budget.add_account("Savings <TODO>", Type::Savings);
+            budget.add_transaction(&euro_account_id, yesterday(), -10_000, Some("Lunch"));
+            budget.add_transaction(&other_account_id, yesterday(), -5_000, None);
+            sync(database, &budget);
+            assert_eq!(
+                amounts_and_memos(&budget.account_transactions(&difference_account_id)),
+                vec![(-1_000, "<CONVERT: EUR -10.00 @$1.1/EUR = -$11.00> Lunch")]
+            );
+        });
+    }
+
+    #[test]
+    fn test_sync_deletions() {
+        with_test_database("deletions", |database| {
+            let (budget, euro_account_id, difference_account_id) = new_euro_budget();
+            let transaction_id =
+                budget.add_transaction(&euro_account_id, yesterday(), -10_000, Some("Lunch"));
+            sync(database, &budget);
+            assert_eq!(
+                amounts_and_memos(&budget.account_transactions(&difference_account_id)),
+                vec![(-1_000, "<CONVERT: EUR -10.00 @$1.1/EUR = -$11.00> Lunch")]
+            );
+            // YNAB cannot delete transactions through the API, so the
+            // difference transaction is zeroed instead.
+            budget.delete_transaction(&transaction_id);
+            sync(database, &budget);
+            assert_eq!(
+                amounts_and_memos(&budget.account_transactions(&difference_account_id)),
+                vec![(0, "<CONVERT: DELETED> Lunch")]
+            );
+            assert_eq!(budget.account(&difference_account_id).balance, 0);
+        });
+    }
+
+    #[test]
+    fn test_sync_exchange_rate_override() {
+        with_test_database("override", |database| {
+            let (budget, euro_account_id, difference_account_id) = new_euro_budget();
+            budget.add_transaction(&euro_account_id, yesterday(), -10_000, Some("Lunch"));
+            sync(database, &budget);
+            let euro_override = |to_currency| DatedExchangeRate {
+                date: yesterday(),
+                from_currency: code("EUR"),
+                to_currency: code(to_currency),
+                exchange_rate: rate("1.2"),
+            };
+            // Only the difference transactions of budgets in the override's
+            // to currency used the rate.
+            assert_eq!(
+                database
+                    .set_exchange_rate_override(&euro_override("GBP"))
+                    .unwrap(),
+                0
+            );
+            assert_eq!(
+                database
+                    .set_exchange_rate_override(&euro_override("USD"))
+                    .unwrap(),
+                1
+            );
+            sync(database, &budget);
+            assert_eq!(
+                amounts_and_memos(&budget.account_transactions(&difference_account_id)),
+                vec![(-2_000, "<CONVERT: EUR -10.00 @$1.2/EUR = -$12.00> Lunch")]
+            );
+            assert_eq!(
+                database
+                    .remove_exchange_rate_override(code("EUR"), code("USD"), yesterday())
+                    .unwrap(),
+                Some(1)
+            );
+            sync(database, &budget);
+            assert_eq!(
+                amounts_and_memos(&budget.account_transactions(&difference_account_id)),
+                vec![(-1_000, "<CONVERT: EUR -10.00 @$1.1/EUR = -$11.00> Lunch")]
+            );
+        });
+    }
+
+    #[test]
+    fn test_import_overwritten_exchange_rates() {
+        with_test_database("import", |database| {
+            let (budget, euro_account_id, _) = new_euro_budget();
+            budget.add_transaction(&euro_account_id, yesterday(), -10_000, Some("Lunch"));
+            sync(database, &budget);
+            database
+                .create_exchange_rate(
+                    code("EUR"),
+                    code("USD"),
+                    yesterday(),
+                    ExchangeRateInfo {
+                        fallback_date: Some(yesterday() - Duration::days(1)),
+                        ..ExchangeRateInfo::new(rate("1.1"))
+                    },
+                )
+                .unwrap();
+            let import = |exchange_rate| {
+                database
+                    .import_exchange_rates(
+                        &[DatedExchangeRate {
+                            date: yesterday(),
+                            from_currency: code("EUR"),
+                            to_currency: code("USD"),
+                            exchange_rate: rate(exchange_rate),
+                        }],
+                        ExchangeRateConflict::Overwrite,
+                    )
+                    .unwrap()
+            };
+            // Replacing the fallback rate with the published rate changes the
+            // memo even though the rate is the same.
+            assert_eq!(
+                import("1.1"),
+                ImportedExchangeRates {
+                    imported_count: 1,
+                    flagged_count: 1
+                }
+            );
+            assert_eq!(
+                database
+                    .get_exchange_rate(code("EUR"), code("USD"), yesterday())
+                    .unwrap(),
+                Some(ExchangeRateInfo::new(rate("1.1")))
+            );
+            sync(database, &budget);
+            assert_eq!(import("1.1").flagged_count, 0);
+            assert_eq!(import("1.2").flagged_count, 1);
+        });
+    }
+
+    #[test]
+    fn test_sync_rate_date_policy() {
+        with_test_database("rate-date-policy", |database| {
+            let budget = FakeYnabBudget::new("USD");
+            let euro_account_id = budget.add_account(
+                "Euro <EUR> <RATE DATE: previous-business-day>",
+                Type::Checking,
+            );
+            let difference_account_id = budget.add_account("<EUR DIFFERENCE>", Type::Checking);
+            // Monday's rate date is the previous Friday.
+            budget.add_transaction(&euro_account_id, yesterday(), -10_000, Some("Lunch"));
+            sync(database, &budget);
+            assert_eq!(
+                amounts_and_memos(&budget.account_transactions(&difference_account_id)),
+                vec![(
+                    -1_000,
+                    "<CONVERT: EUR -10.00 @$1.1/EUR = -$11.00 (rate for 2020-03-06)> Lunch"
+                )]
+            );
+        });
+    }
+
+    #[test]
+    fn test_sync_invalid_posting_date() {
+        with_test_database("posting-date", |database| {
+            let (budget, euro_account_id, difference_account_id) = new_euro_budget();
+            budget.add_transaction(
+                &euro_account_id,
+                yesterday(),
+                -10_000,
+                Some("<POSTED 2020-13-45> Lunch"),
+            );
+            sync(database, &budget);
+            assert_eq!(
+                amounts_and_memos(&budget.account_transactions(&difference_account_id)),
+                vec![(
+                    -1_000,
+                    "<CONVERT: EUR -10.00 @$1.1/EUR = -$11.00> <POSTED 2020-13-45> Lunch"
+                )]
+            );
+        });
+    }
+
+    #[test]
+    fn test_sync_json_plan() {
+        with_test_database("json-plan", |database| {
+            let (budget, euro_account_id, difference_account_id) = new_euro_budget();
+            budget.add_transaction(&euro_account_id, yesterday(), -10_000, Some("Lunch"));
+            budget.add_transaction(&euro_account_id, yesterday(), -20_000, Some("Dinner"));
+            // Entered by hand, so the difference account needs adjusting.
+            budget.add_transaction(&difference_account_id, yesterday(), -500, None);
+            let server_knowledge = budget.server_knowledge();
+            // Accept lunch, skip dinner and accept the adjustment (the
+            // transactions are processed in the order they were added).  The
+            // adjustment leaves out the skipped dinner.
+            let plan = sync_interactively(database, &budget, &["a", "s", "a"]);
+            let planned = |amount: i64, foreign_amount: i64, memo: &str| {
+                serde_json::json!({
+                    "difference_key": {"currency": "EUR", "account_class": "debit"},
+                    "date": "2020-03-09",
+                    "payee_name": null,
+                    "category_name": null,
+                    "memo": memo,
+                    "amount_milliunits": amount,
+                    "foreign_amount_milliunits": foreign_amount,
+                    "exchange_rate": "1.1",
+                    "fee_amount_milliunits": 0,
+                })
+            };
+            let mut skipped = planned(
+                -2_000,
+                -20_000,
+                "<CONVERT: EUR -20.00 @$1.1/EUR = -$22.00> Dinner",
+            );
+            skipped["kind"] = serde_json::Value::from("create");
+            assert_eq!(
+                plan,
+                serde_json::json!({
+                    "budget_id": FakeYnabBudget::BUDGET_ID,
+                    "date": "2020-03-10",
+                    "dry_run": false,
+                    "server_knowledge": server_knowledge,
+                    "creates": [planned(
+                        -1_000,
+                        -10_000,
+                        "<CONVERT: EUR -10.00 @$1.1/EUR = -$11.00> Lunch",
+                    )],
+                    "updates": [],
+                    "adjustments": [{
+                        "difference_key": {"currency": "EUR", "account_class": "debit"},
+                        "date": "2020-03-10",
+                        "payee_name": "Exchange Rate Adjustment <EUR>",
+                        "category_name": null,
+                        "memo": "Exchange rate adjustment: EUR -10.00 @$1.1/EUR = -$11.00",
+                        "amount_milliunits": 500,
+                        "foreign_amount_milliunits": -10_000,
+                        "exchange_rate": "1.1",
+                        "fee_amount_milliunits": 0,
+                    }],
+                    "skipped": [skipped],
+                    "summary": {
+                        "creates": 1,
+                        "updates": 0,
+                        "adjustments": 1,
+                        "skipped": 1,
+                        "saved": true,
+                    },
+                })
+            );
+        });
+    }
+
+    #[test]
+    fn test_sync_interactive_skip() {
+        with_test_database("interactive-skip", |database| {
+            let (budget, euro_account_id, difference_account_id) = new_euro_budget();
+            budget.add_transaction(&euro_account_id, yesterday(), -10_000, Some("Lunch"));
+            // Skipping leaves the euros out of the adjustment, so there is
+            // nothing else to ask.
+            sync_interactively(database, &budget, &["s"]);
+            assert!(budget
+                .account_transactions(&difference_account_id)
+                .is_empty());
+            // YNAB does not return the transaction again, but the skip was
+            // saved, so the next run still converts it.
+            sync(database, &budget);
+            assert_eq!(
+                amounts_and_memos(&budget.account_transactions(&difference_account_id)),
+                vec![(-1_000, "<CONVERT: EUR -10.00 @$1.1/EUR = -$11.00> Lunch")]
+            );
+            sync(database, &budget);
+            assert_eq!(budget.account_transactions(&difference_account_id).len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_sync_interactive_edit() {
+        with_test_database("interactive-edit", |database| {
+            let (budget, euro_account_id, difference_account_id) = new_euro_budget();
+            budget.add_transaction(&euro_account_id, yesterday(), -10_000, Some("Lunch"));
+            // Edit the amount, then skip the adjustment that would undo it.
+            sync_interactively(database, &budget, &["e", "-1.50", "s"]);
+            assert_eq!(
+                amounts_and_memos(&budget.account_transactions(&difference_account_id)),
+                vec![(
+                    -1_500,
+                    "<CONVERT: EUR -10.00 @$1.1/EUR = -$11.00, edited to -$1.50> Lunch"
+                )]
+            );
+        });
+    }
+
+    #[test]
+    fn test_saved_plan_round_trip() {
+        with_test_database("saved-plan", |database| {
+            let (budget, euro_account_id, _) = new_euro_budget();
+            let transaction_id =
+                budget.add_transaction(&euro_account_id, yesterday(), -10_000, Some("Lunch"));
+            sync(database, &budget);
+            budget.delete_transaction(&transaction_id);
+            budget.add_split_transaction(&euro_account_id, yesterday(), &[-10_000, -20_000]);
+            let mut path = std::env::temp_dir();
+            path.push(format!("fca4ynab-test-plan-{}.json", std::process::id()));
+            let path = path.to_str().unwrap();
+            run_sync(database, &budget, None, Some(path), None);
+            let contents = std::fs::read_to_string(path).unwrap();
+            std::fs::remove_file(path).unwrap();
+            let plan = SavedPlan::from_str(&contents).unwrap();
+            let transactions_modifications = &plan.transactions_modifications;
+            assert_eq!(transactions_modifications.create_transactions.len(), 2);
+            assert_eq!(
+                transactions_modifications
+                    .create_import_ids_foreign_ynab_transaction_ids
+                    .len(),
+                2
+            );
+            assert_eq!(transactions_modifications.update_transactions.len(), 1);
+            assert!(transactions_modifications
+                .delete_difference_transaction_ids
+                .contains(&YnabTransactionId::new(transaction_id)));
+            let budget_state = BudgetState {
+                start_date: plan.start_date,
+                ynab_server_knowledge: plan.previous_server_knowledge,
+                last_run_date: None,
+            };
+            assert_eq!(
+                SavedPlan::to_json(
+                    &plan.budget_id,
+                    plan.date,
+                    &budget_state,
+                    plan.server_knowledge,
+                    transactions_modifications,
+                ),
+                contents
+            );
+        });
+    }
+
+    #[test]
+    fn test_sync_with_ynab_budget_client() {
+        with_test_database("client", |database| {
+            let server =
+                MockYnabServer::start(include_str!("../tests/fixtures/ynab_budget.json")).unwrap();
+            let client = YnabBudgetClient::new(
+                MOCK_ACCESS_TOKEN.to_string(),
+                &server.base_url,
+                FakeYnabBudget::BUDGET_ID,
+            );
+            sync_from(database, &client, Some(NaiveDate::from_ymd(2020, 3, 1)));
+            let difference_transactions = client
+                .get_transactions(None, Some(100))
+                .unwrap()
+                .transactions;
+            assert_eq!(
+                amounts_and_memos(&difference_transactions),
+                vec![(-1_000, "<CONVERT: EUR -10.00 @$1.1/EUR = -$11.00> Lunch")]
+            );
+            assert_eq!(
+                difference_transactions[0].account_id,
+                "euro-difference-account"
+            );
+            assert_eq!(
+                difference_transactions[0].import_id,
+                Some("FCAY:20200310:120000000:0".to_string())
+            );
+        });
+    }
+}
@@ -1,5 +1,6 @@
 use std::cell::RefCell;
 
+use crate::clock::*;
 use crate::constants::*;
 use crate::types::*;
 
@@ -10,12 +11,12 @@ pub struct ImportIdGenerator {
 }
 
 impl ImportIdGenerator {
-    pub fn new() -> ImportIdGenerator {
+    pub fn new(clock: &dyn Clock) -> ImportIdGenerator {
         ImportIdGenerator {
             prefix: format!(
                 "{}:{}",
                 IMPORT_ID_PREFIX,
-                chrono::Utc::now().format("%Y%m%d:%H%M%S%3f")
+                clock.now().format("%Y%m%d:%H%M%S%3f")
             ),
             next_number: RefCell::new(0),
         }
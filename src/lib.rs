@@ -7,22 +7,68 @@ extern crate diesel_migrations;
 #[macro_use]
 extern crate error_chain;
 
+/// Print a progress message, which goes to standard error instead when
+/// standard output is used for machine-readable output.
+macro_rules! progress {
+    ($($arg:tt)*) => {
+        if crate::utilities::progress_to_stderr() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
 mod budget_formatter;
 mod cli;
+mod clock;
 mod constants;
+mod currencies;
 mod currency_converter_client;
 mod database;
+mod ecb_client;
+mod exchange_rate_provider;
 mod exchange_rates;
+#[cfg(any(test, feature = "mock-ynab-server"))]
+#[cfg_attr(not(test), allow(dead_code))]
+mod fake_ynab_budget;
 mod foreign_accounts;
 mod foreign_transactions_processor;
 mod import_id_generator;
+#[cfg(any(test, feature = "mock-ynab-server"))]
+mod mock_ynab_server;
 mod schema;
+#[cfg(test)]
+mod test_support;
 mod types;
 mod utilities;
 mod ynab_client;
 
 mod errors {
-    error_chain! {}
+    error_chain! {
+        errors {
+            QuotaExhausted(provider: String, message: String) {
+                description("exchange rate provider quota exhausted")
+                display("{} quota exhausted: {}", provider, message)
+            }
+        }
+    }
+
+    /// Whether the error, or any error that caused it, is because an exchange
+    /// rate provider's quota ran out.
+    pub fn is_quota_exhausted(err: &Error) -> bool {
+        // Like `err.iter()`, but `source()` keeps the `'static` bound that
+        // `downcast_ref` needs.
+        let err: &(dyn std::error::Error + 'static) = err;
+        std::iter::successors(Some(err), |err| err.source()).any(|err| {
+            match err.downcast_ref::<Error>().map(Error::kind) {
+                Some(ErrorKind::QuotaExhausted(..)) => true,
+                _ => false,
+            }
+        })
+    }
 }
 
 pub use cli::run;
+#[cfg(feature = "mock-ynab-server")]
+pub use mock_ynab_server::run_mock_ynab_server;
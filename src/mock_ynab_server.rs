@@ -0,0 +1,441 @@
+use log::warn;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use ynab_api::models;
+
+use crate::errors::*;
+use crate::fake_ynab_budget::*;
+use crate::utilities::*;
+use crate::ynab_client::*;
+
+pub const MOCK_ACCESS_TOKEN: &str = "mock-access-token";
+
+/// A local HTTP server for the parts of the YNAB API that `YnabBudgetClient`
+/// uses, backed by a `FakeYnabBudget` loaded from a JSON fixture, so that the
+/// real client can be tested end to end.
+pub struct MockYnabServer {
+    pub base_url: String,
+    address: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+struct MockRequest {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    authorization: Option<String>,
+    body: String,
+}
+
+impl MockYnabServer {
+    /// Start serving the fixture's budget as `FakeYnabBudget::BUDGET_ID`.
+    /// The server stops when it is dropped.
+    pub fn start(fixture: &str) -> Result<MockYnabServer> {
+        let budget = FakeYnabBudget::from_fixture(fixture)?;
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .chain_err(|| "Failed to listen for mock YNAB server connections")?;
+        let address = listener
+            .local_addr()
+            .chain_err(|| "Failed to get mock YNAB server address")?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // A bad request only fails its own connection.
+                    if let Err(err) = stream
+                        .chain_err(|| "Failed to accept connection")
+                        .and_then(|stream| serve_connection(&budget, stream))
+                    {
+                        warn!("Mock YNAB server: {}", err);
+                    }
+                }
+            })
+        };
+        Ok(MockYnabServer {
+            base_url: format!("http://{}/v1", address),
+            address,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    /// Serve requests until the process is stopped.
+    #[cfg(feature = "mock-ynab-server")]
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            thread
+                .join()
+                .expect("Mock YNAB server thread should not panic");
+        }
+    }
+}
+
+impl Drop for MockYnabServer {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.shutdown.store(true, Ordering::SeqCst);
+            // Wake the server thread, which is waiting for a connection, so
+            // that it sees the shutdown.
+            TcpStream::connect(self.address).ok();
+            thread.join().ok();
+        }
+    }
+}
+
+/// Serve the budget from `fixture_file` until the process is stopped, for
+/// running `fca4ynab` against with `--ynab-base-url`.
+#[cfg(feature = "mock-ynab-server")]
+pub fn run_mock_ynab_server() -> Result<()> {
+    env_logger::init();
+    let fixture_file = std::env::args()
+        .nth(1)
+        .chain_err(|| "Usage: fca4ynab-mock-ynab-server FIXTURE-FILE")?;
+    let fixture = std::fs::read_to_string(&fixture_file)
+        .chain_err(|| format!("Failed to read fixture file: {}", fixture_file))?;
+    let server = MockYnabServer::start(&fixture)
+        .chain_err(|| format!("Invalid fixture file: {}", fixture_file))?;
+    // The URL goes first on its own line, so that scripts can read it.
+    println!("{}", server.base_url);
+    eprintln!(
+        "Serving budget {} with access token {}",
+        FakeYnabBudget::BUDGET_ID,
+        MOCK_ACCESS_TOKEN
+    );
+    server.wait();
+    Ok(())
+}
+
+fn serve_connection(budget: &FakeYnabBudget, mut stream: TcpStream) -> Result<()> {
+    let request = read_request(&stream)?;
+    let (status, body) = respond(budget, &request);
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .chain_err(|| "Failed to write response")
+}
+
+fn read_request(stream: &TcpStream) -> Result<MockRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader
+        .read_line(&mut request_line)
+        .chain_err(|| "Failed to read request line")?;
+    let mut request_line_parts = request_line.split_whitespace();
+    let method = request_line_parts.next().unwrap_or_default().to_string();
+    let target = request_line_parts.next().unwrap_or_default();
+    let mut path_and_query = target.splitn(2, '?');
+    let path = path_and_query.next().unwrap_or_default().to_string();
+    let query = path_and_query
+        .next()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let mut name_and_value = param.splitn(2, '=');
+            (
+                name_and_value.next().unwrap_or_default().to_string(),
+                name_and_value.next().unwrap_or_default().to_string(),
+            )
+        })
+        .collect();
+    let mut authorization = None;
+    let mut content_length = 0;
+    loop {
+        let mut header_line = String::new();
+        reader
+            .read_line(&mut header_line)
+            .chain_err(|| "Failed to read request header")?;
+        if header_line.trim().is_empty() {
+            break;
+        }
+        let mut name_and_value = header_line.splitn(2, ':');
+        let name = name_and_value
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+        let value = name_and_value.next().unwrap_or_default().trim().to_string();
+        match name.as_str() {
+            "authorization" => authorization = Some(value),
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .chain_err(|| format!("Invalid Content-Length: {}", value))?
+            }
+            _ => {}
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader
+        .read_exact(&mut body)
+        .chain_err(|| "Failed to read request body")?;
+    Ok(MockRequest {
+        method,
+        path,
+        query,
+        authorization,
+        body: String::from_utf8(body).chain_err(|| "Request body is not UTF-8")?,
+    })
+}
+
+fn error_response(
+    status: &'static str,
+    id: &str,
+    name: &str,
+    detail: &str,
+) -> (&'static str, String) {
+    (
+        status,
+        serde_json::json!({"error": {"id": id, "name": name, "detail": detail}}).to_string(),
+    )
+}
+
+/// Handle a request the way the YNAB API would, returning the response status
+/// and JSON body.
+fn respond(budget: &FakeYnabBudget, request: &MockRequest) -> (&'static str, String) {
+    if request.authorization != Some(format!("Bearer {}", MOCK_ACCESS_TOKEN)) {
+        return error_response("401 Unauthorized", "401", "unauthorized", "Unauthorized");
+    }
+    let budget_path = format!("/v1/budgets/{}/", budget.budget_id());
+    let resource = if request.path.starts_with(&budget_path) {
+        &request.path[budget_path.len()..]
+    } else {
+        ""
+    };
+    let result = match (request.method.as_str(), resource) {
+        ("GET", "settings") => budget.get_budget_settings().map(|settings| {
+            (
+                "200 OK",
+                serde_json::json!({ "data": { "settings": settings } }),
+            )
+        }),
+        ("GET", "accounts") => budget.get_accounts().map(|accounts| {
+            (
+                "200 OK",
+                serde_json::json!({
+                    "data": { "accounts": accounts, "server_knowledge": budget.server_knowledge() }
+                }),
+            )
+        }),
+        ("GET", "transactions") => respond_get_transactions(budget, request),
+        ("POST", "transactions") => respond_create_transactions(budget, request),
+        ("PATCH", "transactions") => respond_update_transactions(budget, request),
+        _ => {
+            return error_response(
+                "404 Not Found",
+                "404.2",
+                "resource_not_found",
+                "Resource not found",
+            )
+        }
+    };
+    match result {
+        Ok((status, body)) => (status, body.to_string()),
+        Err(err) => error_response("400 Bad Request", "400", "bad_request", &err.to_string()),
+    }
+}
+
+fn respond_get_transactions(
+    budget: &FakeYnabBudget,
+    request: &MockRequest,
+) -> Result<(&'static str, serde_json::Value)> {
+    let start_date = request
+        .query
+        .get("since_date")
+        .map(|date| parse_iso_date(date))
+        .transpose()?;
+    let server_knowledge = request
+        .query
+        .get("last_knowledge_of_server")
+        .map(|knowledge| {
+            knowledge
+                .parse()
+                .chain_err(|| format!("Invalid last_knowledge_of_server: {}", knowledge))
+        })
+        .transpose()?;
+    let data = budget.get_transactions(start_date, server_knowledge)?;
+    Ok(("200 OK", serde_json::json!({ "data": data })))
+}
+
+fn respond_create_transactions(
+    budget: &FakeYnabBudget,
+    request: &MockRequest,
+) -> Result<(&'static str, serde_json::Value)> {
+    let wrapper = serde_json::from_str::<models::SaveTransactionsWrapper>(&request.body)
+        .chain_err(|| "Invalid transactions to create")?;
+    let transactions = wrapper
+        .transaction
+        .into_iter()
+        .chain(wrapper.transactions.unwrap_or_default())
+        .collect::<Vec<_>>();
+    let import_ids = transactions
+        .iter()
+        .filter_map(|transaction| transaction.import_id.clone())
+        .collect::<Vec<_>>();
+    let created_transactions = budget.create_transactions(transactions)?;
+    let duplicate_import_ids = import_ids
+        .into_iter()
+        .filter(|import_id| {
+            !created_transactions
+                .iter()
+                .any(|transaction| transaction.import_id.as_ref() == Some(import_id))
+        })
+        .collect::<Vec<_>>();
+    Ok((
+        "201 Created",
+        saved_transactions_data(budget, created_transactions, duplicate_import_ids),
+    ))
+}
+
+fn respond_update_transactions(
+    budget: &FakeYnabBudget,
+    request: &MockRequest,
+) -> Result<(&'static str, serde_json::Value)> {
+    let wrapper = serde_json::from_str::<models::UpdateTransactionsWrapper>(&request.body)
+        .chain_err(|| "Invalid transactions to update")?;
+    let updated_transactions = budget.update_transactions(wrapper.transactions)?;
+    Ok((
+        "209 Saved",
+        saved_transactions_data(budget, updated_transactions, Vec::new()),
+    ))
+}
+
+fn saved_transactions_data(
+    budget: &FakeYnabBudget,
+    transactions: Vec<models::TransactionDetail>,
+    duplicate_import_ids: Vec<String>,
+) -> serde_json::Value {
+    serde_json::json!({
+        "data": {
+            "transaction_ids": transactions
+                .iter()
+                .map(|transaction| transaction.id.clone())
+                .collect::<Vec<_>>(),
+            "transaction": null,
+            "transactions": transactions,
+            "duplicate_import_ids": duplicate_import_ids,
+            "server_knowledge": budget.server_knowledge(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/ynab_budget.json");
+
+    fn save_transaction(import_id: &str) -> models::SaveTransaction {
+        models::SaveTransaction {
+            account_id: "checking-account".to_string(),
+            date: "2020-03-04".to_string(),
+            amount: -1_000,
+            payee_id: None,
+            payee_name: Some("Coffee".to_string()),
+            category_id: None,
+            memo: None,
+            cleared: None,
+            approved: None,
+            flag_color: None,
+            import_id: Some(import_id.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_ynab_budget_client() {
+        let server = MockYnabServer::start(FIXTURE).unwrap();
+        let client = YnabBudgetClient::new(
+            MOCK_ACCESS_TOKEN.to_string(),
+            &server.base_url,
+            FakeYnabBudget::BUDGET_ID,
+        );
+        assert_eq!(
+            client
+                .get_budget_settings()
+                .unwrap()
+                .currency_format
+                .iso_code,
+            "USD"
+        );
+        let accounts = client.get_accounts().unwrap();
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(accounts[1].balance, -10_000);
+        let transactions = client.get_transactions(None, None).unwrap();
+        assert_eq!(transactions.transactions.len(), 2);
+        assert_eq!(transactions.server_knowledge, 100);
+        assert!(client
+            .get_transactions(Some(parse_iso_date("2020-03-03").unwrap()), Some(100))
+            .unwrap()
+            .transactions
+            .is_empty());
+
+        let created = client
+            .create_transactions(vec![save_transaction("import-1")])
+            .unwrap();
+        assert_eq!(created.len(), 1);
+        assert!(client
+            .create_transactions(vec![save_transaction("import-1")])
+            .unwrap()
+            .is_empty());
+        let mut update_transaction = models::UpdateTransaction {
+            id: created[0].id.clone(),
+            account_id: created[0].account_id.clone(),
+            date: created[0].date.clone(),
+            amount: -2_000,
+            payee_id: None,
+            payee_name: None,
+            category_id: None,
+            memo: Some("Large coffee".to_string()),
+            cleared: None,
+            approved: None,
+            flag_color: None,
+            import_id: None,
+        };
+        let updated = client
+            .update_transactions(vec![update_transaction.clone()])
+            .unwrap();
+        assert_eq!(updated[0].amount, -2_000);
+        let changed = client.get_transactions(None, Some(100)).unwrap();
+        assert_eq!(changed.transactions.len(), 1);
+        assert_eq!(
+            changed.transactions[0].memo,
+            Some("Large coffee".to_string())
+        );
+        assert_eq!(changed.server_knowledge, 102);
+
+        update_transaction.id = "no-such-transaction".to_string();
+        assert!(client
+            .update_transactions(vec![update_transaction])
+            .is_err());
+    }
+
+    #[test]
+    fn test_ynab_budget_client_errors() {
+        let server = MockYnabServer::start(FIXTURE).unwrap();
+        let unauthorized_client = YnabBudgetClient::new(
+            "wrong-access-token".to_string(),
+            &server.base_url,
+            FakeYnabBudget::BUDGET_ID,
+        );
+        assert!(unauthorized_client.get_budget_settings().is_err());
+        let wrong_budget_client = YnabBudgetClient::new(
+            MOCK_ACCESS_TOKEN.to_string(),
+            &server.base_url,
+            "wrong-budget",
+        );
+        assert!(wrong_budget_client.get_accounts().is_err());
+    }
+}
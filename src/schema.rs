@@ -5,6 +5,7 @@ table! {
         start_date -> Integer,
         ynab_server_knowledge -> Nullable<BigInt>,
         last_run_date -> Nullable<Integer>,
+        currency_code -> Nullable<Text>,
     }
 }
 
@@ -19,6 +20,9 @@ table! {
         difference_account_class -> Text,
         transfer_currency_code -> Nullable<Text>,
         transfer_account_class -> Nullable<Text>,
+        exchange_rate_date -> Nullable<Integer>,
+        needs_recompute -> Bool,
+        fee_amount_milliunits -> BigInt,
     }
 }
 
@@ -28,10 +32,37 @@ table! {
         date -> Integer,
         from_currency_code -> Text,
         to_currency_code -> Text,
-        exchange_rate -> BigInt,
+        exchange_rate -> Text,
+        pivot_currency_code -> Nullable<Text>,
+        fallback_date -> Nullable<Integer>,
+    }
+}
+
+table! {
+    exchange_rate_overrides (id) {
+        id -> Integer,
+        date -> Integer,
+        from_currency_code -> Text,
+        to_currency_code -> Text,
+        exchange_rate -> Text,
+    }
+}
+
+table! {
+    skipped_foreign_transactions (id) {
+        id -> Integer,
+        budget_id -> Integer,
+        foreign_ynab_transaction_id -> Text,
     }
 }
 
 joinable!(difference_transactions -> budgets (budget_id));
+joinable!(skipped_foreign_transactions -> budgets (budget_id));
 
-allow_tables_to_appear_in_same_query!(budgets, difference_transactions, exchange_rates,);
+allow_tables_to_appear_in_same_query!(
+    budgets,
+    difference_transactions,
+    exchange_rate_overrides,
+    exchange_rates,
+    skipped_foreign_transactions,
+);
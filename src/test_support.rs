@@ -0,0 +1,143 @@
+//! Fixtures shared by the unit tests.
+
+use chrono::NaiveDate;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+
+use crate::database::*;
+use crate::errors::*;
+use crate::exchange_rate_provider::*;
+use crate::types::*;
+
+/// Run `f` with a new database in a temporary file, which is removed
+/// afterwards.  Tests run in parallel, so each must use its own `name`.
+pub fn with_test_database<F: FnOnce(&Database)>(name: &str, f: F) {
+    let file = TempFile::new(&format!("{}.sqlite3", name));
+    let database = Database::establish_connection(file.path(), false).unwrap();
+    f(&database);
+}
+
+pub fn code(code: &str) -> CurrencyCode {
+    CurrencyCode::from_str(code).unwrap()
+}
+
+pub fn rate(value: &str) -> ExchangeRate {
+    ExchangeRate::from_str(value).unwrap()
+}
+
+/// Provider that quotes the given rates on the given dates only.
+pub struct TestExchangeRateProvider {
+    pub dates: Vec<NaiveDate>,
+    pub rates: HashMap<(CurrencyCode, CurrencyCode), ExchangeRate>,
+}
+
+impl ExchangeRateProvider for TestExchangeRateProvider {
+    fn name(&self) -> &str {
+        "Test"
+    }
+
+    fn get_date_exchange_rates(
+        &self,
+        date: NaiveDate,
+        from_to_currency_pairs: &HashSet<(CurrencyCode, CurrencyCode)>,
+    ) -> Result<HashMap<(CurrencyCode, CurrencyCode), ExchangeRate>> {
+        if !self.dates.contains(&date) {
+            return Ok(HashMap::new());
+        }
+        Ok(from_to_currency_pairs
+            .iter()
+            .filter_map(|pair| self.rates.get(pair).map(|&rate| (*pair, rate)))
+            .collect())
+    }
+}
+
+/// Provider that always fails.
+pub struct FailingExchangeRateProvider;
+
+impl FailingExchangeRateProvider {
+    pub const ERROR: &'static str = "Exchange rate provider is unavailable";
+}
+
+impl ExchangeRateProvider for FailingExchangeRateProvider {
+    fn name(&self) -> &str {
+        "Failing"
+    }
+
+    fn get_date_exchange_rates(
+        &self,
+        _date: NaiveDate,
+        _from_to_currency_pairs: &HashSet<(CurrencyCode, CurrencyCode)>,
+    ) -> Result<HashMap<(CurrencyCode, CurrencyCode), ExchangeRate>> {
+        bail!(FailingExchangeRateProvider::ERROR)
+    }
+}
+
+/// A file in the temporary directory that is removed when dropped, even if
+/// the test fails.  Tests run in parallel, so each must use its own `name`.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(name: &str) -> TempFile {
+        let mut path = std::env::temp_dir();
+        path.push(format!("fca4ynab-test-{}-{}", std::process::id(), name));
+        TempFile { path }
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+pub fn http_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Start a local HTTP server that serves the responses in order, one per
+/// request, and then stops.  Returns its base URL, and a handle that yields
+/// the request paths once all the responses have been served.
+pub fn serve_http_responses(responses: Vec<String>) -> (String, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = thread::spawn(move || {
+        responses
+            .into_iter()
+            .map(|response| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut header_line = String::new();
+                    reader.read_line(&mut header_line).unwrap();
+                    if header_line.trim().is_empty() {
+                        break;
+                    }
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+                request_line
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect()
+    });
+    (base_url, handle)
+}
@@ -1,14 +1,23 @@
+use chrono::{Datelike, NaiveDate, Weekday};
 use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::{Decimal, RoundingStrategy};
 use std::borrow::Cow;
+use std::str::FromStr;
 use std::{fmt, ops};
 
+use crate::constants::*;
 use crate::errors::*;
 
 pub use rust_decimal::prelude::Zero;
 
+/// Currency code such as `EUR`, or a longer ticker such as `USDT` for assets
+/// without an ISO 4217 code.  Stored uppercase and padded, so that it can be
+/// `Copy` and orders the same as the string.
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub struct CurrencyCode([u8; 3]);
+pub struct CurrencyCode {
+    bytes: [u8; CurrencyCode::MAX_LEN],
+    len: u8,
+}
 
 #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Milliunits(Decimal);
@@ -44,18 +53,68 @@ pub enum AccountClass {
     Tracking,
 }
 
+/// How the sync command reports what it does.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// Progress and planned transactions as human-readable text.
+    Text,
+    /// A JSON document with the planned transactions on standard output, and
+    /// progress on standard error.
+    Json,
+}
+
+/// Which date's exchange rate to use for a foreign currency transaction.
+/// Banks settle card transactions on different days, so this can be set for
+/// the whole budget and for individual accounts.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RateDatePolicy {
+    SameDay,
+    /// The closest weekday before the transaction's date.
+    PreviousBusinessDay,
+    /// The date from a `<POSTED YYYY-MM-DD>` memo tag, or the transaction's
+    /// date if the memo has no such tag.
+    PostingDate,
+}
+
+/// Bank margin and/or fee charged on a foreign account's transactions, set
+/// with the account's `<FEE ...>` tag (e.g. `<FEE 1.5% + 0.50>`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccountFee {
+    /// Percentage of the converted amount.
+    pub percent: Decimal,
+    /// Fixed amount in the budget's currency, charged for each outflow.
+    pub fixed: Milliunits,
+}
+
 impl CurrencyCode {
+    const MIN_LEN: usize = 3;
+    const MAX_LEN: usize = 6;
+
+    /// Parse a code of three to six ASCII letters and digits, starting with a
+    /// letter.  Lowercase letters are converted to uppercase.
     pub fn from_str(code: &str) -> Result<CurrencyCode> {
-        match code.as_bytes() {
-            [a, b, c] => Ok(CurrencyCode([*a, *b, *c])),
-            _ => bail!("Invalid currency code: {}", code),
+        let code_bytes = code.as_bytes();
+        ensure!(
+            code_bytes.len() >= Self::MIN_LEN
+                && code_bytes.len() <= Self::MAX_LEN
+                && code_bytes[0].is_ascii_alphabetic()
+                && code_bytes.iter().all(u8::is_ascii_alphanumeric),
+            "Invalid currency code: {}",
+            code
+        );
+        let mut bytes = [0; Self::MAX_LEN];
+        for (byte, code_byte) in bytes.iter_mut().zip(code_bytes) {
+            *byte = code_byte.to_ascii_uppercase();
         }
+        Ok(CurrencyCode {
+            bytes,
+            len: code_bytes.len() as u8,
+        })
     }
 
     pub fn to_str(&self) -> Cow<str> {
-        // Safe to use 'from_utf8_lossy', since we know our bytes
-        // originally came from a 'String'.
-        String::from_utf8_lossy(&self.0)
+        // Safe to use 'from_utf8_lossy', since we know our bytes are ASCII.
+        String::from_utf8_lossy(&self.bytes[..self.len as usize])
     }
 }
 
@@ -211,37 +270,101 @@ impl Zero for Milliunits {
 }
 
 impl ExchangeRate {
-    const SCALE: u32 = 6;
-
-    pub fn from_scaled_i64(value: i64) -> ExchangeRate {
-        ExchangeRate(Decimal::new(value, Self::SCALE))
-    }
+    /// Maximum number of decimal places kept, which is enough for rates
+    /// between currencies of very different magnitudes (e.g. VND to EUR).
+    const MAX_SCALE: u32 = 12;
 
-    pub fn to_scaled_i64(self) -> i64 {
-        assert!(
-            self.0.scale() == Self::SCALE,
-            "ExchangeRate Decimal scale should be {}, but is {}",
-            Self::SCALE,
-            self.0.scale()
-        );
-        let mut result = self.0;
-        result
-            .set_scale(0)
-            .expect("Milliunits Decimal scale should be settable to 0");
-        result
-            .to_i64()
-            .expect("Milliunits Decimal should be convertible to i64")
+    pub fn from_str(rate: &str) -> Result<ExchangeRate> {
+        Ok(ExchangeRate::from_decimal(parse_decimal(rate).chain_err(
+            || format!("Invalid exchange rate: {}", rate),
+        )?))
     }
 
     pub fn to_decimal(self) -> Decimal {
         self.0
     }
 
-    pub fn from_f64(rate: f64) -> ExchangeRate {
-        ExchangeRate(Decimal::new(
-            (rate * 10.0f64.powi(Self::SCALE as i32)).round() as i64,
-            Self::SCALE,
-        ))
+    pub fn from_decimal(rate: Decimal) -> ExchangeRate {
+        ExchangeRate(
+            rate.round_dp_with_strategy(Self::MAX_SCALE, RoundingStrategy::BankersRounding),
+        )
+    }
+}
+
+impl fmt::Display for ExchangeRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Parse a decimal number, which may be in scientific notation (e.g.
+/// `4.1e-5`) as some APIs return for very small exchange rates.
+fn parse_decimal(value: &str) -> Result<Decimal> {
+    let mut parts = value.trim().splitn(2, |c| c == 'e' || c == 'E');
+    let mantissa = parts.next().unwrap_or_default();
+    let mut result =
+        Decimal::from_str(mantissa).chain_err(|| format!("Invalid decimal: {}", mantissa))?;
+    if let Some(exponent) = parts.next() {
+        let exponent: i32 = exponent
+            .trim_start_matches('+')
+            .parse()
+            .chain_err(|| format!("Invalid exponent: {}", exponent))?;
+        if exponent < 0 {
+            let scale = result.scale() + exponent.abs() as u32;
+            result
+                .set_scale(scale)
+                .chain_err(|| format!("Exponent is too small: {}", exponent))?;
+        } else {
+            ensure!(exponent <= 18, "Exponent is too large: {}", exponent);
+            result = result * Decimal::new(10i64.pow(exponent as u32), 0);
+        }
+    }
+    Ok(result)
+}
+
+impl AccountFee {
+    pub fn from_str(value: &str) -> Result<AccountFee> {
+        let mut percent = None;
+        let mut fixed = None;
+        for part in value.split('+').map(str::trim) {
+            let (target, number) = if part.ends_with('%') {
+                (&mut percent, &part[..part.len() - 1])
+            } else {
+                (&mut fixed, part)
+            };
+            ensure!(target.is_none(), "Fee may not have more than one {}", part);
+            let number =
+                Decimal::from_str(number.trim()).chain_err(|| format!("Invalid fee: {}", value))?;
+            ensure!(
+                !number.is_sign_negative(),
+                "Fee may not be negative: {}",
+                value
+            );
+            *target = Some(number);
+        }
+        Ok(AccountFee {
+            percent: percent.unwrap_or_else(Decimal::zero),
+            fixed: Milliunits::from_decimal(
+                fixed
+                    .unwrap_or_else(Decimal::zero)
+                    .round_dp_with_strategy(Milliunits::SCALE, RoundingStrategy::BankersRounding),
+            ),
+        })
+    }
+
+    /// The fee for a transaction with the given amount converted to the
+    /// budget's currency, as a negative (or zero) amount since it always costs
+    /// money.
+    pub fn fee_amount(self, converted_amount: Milliunits) -> Milliunits {
+        let margin = Milliunits::from_decimal(
+            (converted_amount.abs().0 * self.percent / Decimal::new(100, 0))
+                .round_dp_with_strategy(Milliunits::SCALE, RoundingStrategy::BankersRounding),
+        );
+        if converted_amount.0.is_sign_negative() && !converted_amount.is_zero() {
+            -(margin + self.fixed)
+        } else {
+            -margin
+        }
     }
 }
 
@@ -310,10 +433,56 @@ impl fmt::Display for AccountClass {
     }
 }
 
+impl RateDatePolicy {
+    pub fn from_str(value: &str) -> Result<RateDatePolicy> {
+        match value {
+            RATE_DATE_SAME_DAY => Ok(RateDatePolicy::SameDay),
+            RATE_DATE_PREVIOUS_BUSINESS_DAY => Ok(RateDatePolicy::PreviousBusinessDay),
+            RATE_DATE_POSTING_DATE => Ok(RateDatePolicy::PostingDate),
+            _ => bail!("Invalid rate date: {}", value),
+        }
+    }
+
+    pub fn rate_date(
+        self,
+        transaction_date: NaiveDate,
+        posting_date: Option<NaiveDate>,
+    ) -> NaiveDate {
+        match self {
+            RateDatePolicy::SameDay => transaction_date,
+            RateDatePolicy::PreviousBusinessDay => {
+                let mut date = transaction_date.pred();
+                while date.weekday() == Weekday::Sat || date.weekday() == Weekday::Sun {
+                    date = date.pred();
+                }
+                date
+            }
+            RateDatePolicy::PostingDate => posting_date.unwrap_or(transaction_date),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_currency_code_from_str() {
+        assert_eq!(CurrencyCode::from_str("EUR").unwrap().to_str(), "EUR");
+        assert_eq!(CurrencyCode::from_str("usdt").unwrap().to_str(), "USDT");
+        assert_eq!(
+            CurrencyCode::from_str("usdt").unwrap(),
+            CurrencyCode::from_str("USDT").unwrap()
+        );
+        assert_eq!(CurrencyCode::from_str("XAU999").unwrap().to_str(), "XAU999");
+        assert!(CurrencyCode::from_str("EUR").unwrap() < CurrencyCode::from_str("EURO").unwrap());
+        assert!(CurrencyCode::from_str("EU").is_err());
+        assert!(CurrencyCode::from_str("CONVERT").is_err());
+        assert!(CurrencyCode::from_str("1INCH").is_err());
+        assert!(CurrencyCode::from_str("US-D").is_err());
+        assert!(CurrencyCode::from_str("ÉUR").is_err());
+    }
+
     #[test]
     fn test_milliunits_from_to_scaled_i64() {
         assert_eq!(Milliunits::from_scaled_i64(12_345).to_scaled_i64(), 12_345);
@@ -323,29 +492,101 @@ mod tests {
     fn test_milliunits_convert_currency() {
         assert_eq!(
             Milliunits::from_scaled_i64(12_345)
-                .convert_currency(ExchangeRate::from_scaled_i64(1_234_567)),
+                .convert_currency(ExchangeRate::from_str("1.234567").unwrap()),
             Milliunits::from_scaled_i64(15_241)
-        )
+        );
+        assert_eq!(
+            Milliunits::from_scaled_i64(250_000_000_000)
+                .convert_currency(ExchangeRate::from_str("0.00003887").unwrap()),
+            Milliunits::from_scaled_i64(9_717_500)
+        );
+    }
+
+    #[test]
+    fn test_exchange_rate_from_str() {
+        assert_eq!(
+            ExchangeRate::from_str("12.345678").unwrap().to_decimal(),
+            Decimal::new(12_345_678, 6)
+        );
+        assert_eq!(
+            ExchangeRate::from_str("0.0000412345678")
+                .unwrap()
+                .to_decimal(),
+            Decimal::new(41_234_568, 12)
+        );
+        assert_eq!(
+            ExchangeRate::from_str("4.12345e-5").unwrap().to_decimal(),
+            Decimal::new(412_345, 10)
+        );
+        assert_eq!(
+            ExchangeRate::from_str("2.5E+3").unwrap().to_decimal(),
+            Decimal::new(2_500, 0)
+        );
+        assert!(ExchangeRate::from_str("abc").is_err());
+        assert!(ExchangeRate::from_str("1e").is_err());
+    }
+
+    #[test]
+    fn test_exchange_rate_from_decimal() {
+        assert_eq!(
+            ExchangeRate::from_decimal(Decimal::new(11, 1)).to_decimal(),
+            Decimal::new(11, 1)
+        );
+        assert_eq!(
+            ExchangeRate::from_decimal(Decimal::new(123_456_789_012_345, 14)).to_decimal(),
+            Decimal::new(1_234_567_890_123, 12)
+        );
     }
 
     #[test]
-    fn test_exchange_rate_from_to_scaled_i64() {
+    fn test_rate_date_policy_rate_date() {
+        let friday = NaiveDate::from_ymd(2020, 3, 6);
+        let monday = NaiveDate::from_ymd(2020, 3, 9);
+        let tuesday = NaiveDate::from_ymd(2020, 3, 10);
+        assert_eq!(
+            RateDatePolicy::SameDay.rate_date(monday, Some(friday)),
+            monday
+        );
         assert_eq!(
-            ExchangeRate::from_scaled_i64(12_345_678).to_scaled_i64(),
-            12_345_678
+            RateDatePolicy::PreviousBusinessDay.rate_date(monday, None),
+            friday
+        );
+        assert_eq!(
+            RateDatePolicy::PreviousBusinessDay.rate_date(tuesday, None),
+            monday
+        );
+        assert_eq!(
+            RateDatePolicy::PostingDate.rate_date(tuesday, Some(friday)),
+            friday
+        );
+        assert_eq!(
+            RateDatePolicy::PostingDate.rate_date(tuesday, None),
+            tuesday
         );
     }
 
     #[test]
-    fn test_exchange_rate_from_f64() {
+    fn test_account_fee() {
+        let fee = AccountFee::from_str("1.5% + 0.50").unwrap();
+        assert_eq!(fee.percent, Decimal::new(15, 1));
+        assert_eq!(fee.fixed, Milliunits::from_scaled_i64(500));
+        assert_eq!(
+            fee.fee_amount(Milliunits::from_scaled_i64(-110_000)),
+            Milliunits::from_scaled_i64(-2_150)
+        );
         assert_eq!(
-            ExchangeRate::from_f64(12.345_678),
-            ExchangeRate::from_scaled_i64(12_345_678)
+            fee.fee_amount(Milliunits::from_scaled_i64(110_000)),
+            Milliunits::from_scaled_i64(-1_650)
         );
         assert_eq!(
-            ExchangeRate::from_f64(12.345_678_5),
-            ExchangeRate::from_scaled_i64(12_345_679)
+            AccountFee::from_str("2")
+                .unwrap()
+                .fee_amount(Milliunits::from_scaled_i64(-1_000)),
+            Milliunits::from_scaled_i64(-2_000)
         );
+        assert!(AccountFee::from_str("1% + 2%").is_err());
+        assert!(AccountFee::from_str("-1%").is_err());
+        assert!(AccountFee::from_str("abc").is_err());
     }
 
     #[test]
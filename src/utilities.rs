@@ -1,10 +1,24 @@
 use chrono::NaiveDate;
 use regex::Regex;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::errors::*;
 
 const ISO_DATE_FORMAT: &str = "%Y-%m-%d";
 
+static PROGRESS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Send progress messages (see `progress!`) to standard error, so that
+/// standard output only has machine-readable output.
+pub fn set_progress_to_stderr(value: bool) {
+    PROGRESS_TO_STDERR.store(value, Ordering::Relaxed);
+}
+
+pub fn progress_to_stderr() -> bool {
+    PROGRESS_TO_STDERR.load(Ordering::Relaxed)
+}
+
 pub fn format_iso_date(date: NaiveDate) -> String {
     date.format(ISO_DATE_FORMAT).to_string()
 }
@@ -14,6 +28,45 @@ pub fn parse_iso_date(iso_date: &str) -> Result<NaiveDate> {
         .chain_err(|| format!("Invalid ISO date string (YYYY-MM-DD): {}", iso_date))
 }
 
+/// Ask the user a yes or no question on the terminal, defaulting to no.
+pub fn prompt_yes_no(question: &str) -> Result<bool> {
+    let answer = prompt(&format!("{} [y/N]", question))?.to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+/// Ask the user a question on the terminal, returning the trimmed answer.
+pub fn prompt(question: &str) -> Result<String> {
+    if progress_to_stderr() {
+        eprint!("{} ", question);
+    } else {
+        print!("{} ", question);
+        io::stdout()
+            .flush()
+            .chain_err(|| "Failed to write to standard output")?;
+    }
+    let mut answer = String::new();
+    let read_len = io::stdin()
+        .read_line(&mut answer)
+        .chain_err(|| "Failed to read answer from standard input")?;
+    ensure!(read_len > 0, "No answer, since standard input was closed");
+    Ok(answer.trim().to_string())
+}
+
+/// Asks the user questions, so that `--interactive` answers can be scripted
+/// in tests.
+pub trait Prompter {
+    fn prompt(&self, question: &str) -> Result<String>;
+}
+
+/// Asks questions on the terminal.
+pub struct TerminalPrompter;
+
+impl Prompter for TerminalPrompter {
+    fn prompt(&self, question: &str) -> Result<String> {
+        prompt(question)
+    }
+}
+
 pub fn account_matches_regex<'a>(regex: &Regex, account: &'a ynab_api::models::Account) -> bool {
     if regex.is_match(&account.name) {
         true
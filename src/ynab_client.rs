@@ -7,6 +7,36 @@ use ynab_api::models;
 use crate::errors::*;
 use crate::utilities::*;
 
+/// The parts of the YNAB API for a single budget that this tool uses, so that
+/// the processor can also be run against an in-memory budget in tests.
+pub trait YnabBudget {
+    fn budget_id(&self) -> &str;
+
+    fn get_budget_settings(&self) -> Result<models::BudgetSettings>;
+
+    fn get_accounts(&self) -> Result<Vec<models::Account>>;
+
+    /// Get transactions from `start_date`, or only those changed since
+    /// `server_knowledge` (including deleted ones) if it is given.
+    fn get_transactions(
+        &self,
+        start_date: Option<NaiveDate>,
+        server_knowledge: Option<i64>,
+    ) -> Result<models::TransactionsResponseData>;
+
+    /// Create transactions, returning the ones that were created.  Those with
+    /// an import ID that already exists in the budget are skipped.
+    fn create_transactions(
+        &self,
+        transactions: Vec<models::SaveTransaction>,
+    ) -> Result<Vec<models::TransactionDetail>>;
+
+    fn update_transactions(
+        &self,
+        transactions: Vec<models::UpdateTransaction>,
+    ) -> Result<Vec<models::TransactionDetail>>;
+}
+
 pub struct YnabBudgetClient<'a> {
     client: APIClient,
     pub budget_id: &'a str,
@@ -18,8 +48,9 @@ pub struct YnabBudgetClient<'a> {
 struct YnabApiError(ynab_api::apis::Error);
 
 impl<'a> YnabBudgetClient<'a> {
-    pub fn new(api_key: String, budget_id: &'a str) -> YnabBudgetClient {
+    pub fn new(api_key: String, base_url: &str, budget_id: &'a str) -> YnabBudgetClient<'a> {
         let mut configuration = Configuration::new();
+        configuration.base_path = base_url.to_string();
         configuration.api_key = Some(ApiKey {
             prefix: Some("Bearer".to_string()),
             key: api_key,
@@ -29,8 +60,14 @@ impl<'a> YnabBudgetClient<'a> {
             budget_id,
         }
     }
+}
+
+impl<'a> YnabBudget for YnabBudgetClient<'a> {
+    fn budget_id(&self) -> &str {
+        self.budget_id
+    }
 
-    pub fn get_budget_settings(&self) -> Result<models::BudgetSettings> {
+    fn get_budget_settings(&self) -> Result<models::BudgetSettings> {
         Ok(self
             .client
             .budgets_api()
@@ -41,7 +78,7 @@ impl<'a> YnabBudgetClient<'a> {
             .settings)
     }
 
-    pub fn get_accounts(&self) -> Result<Vec<models::Account>> {
+    fn get_accounts(&self) -> Result<Vec<models::Account>> {
         self.client
             .accounts_api()
             .get_accounts(self.budget_id, None)
@@ -50,7 +87,7 @@ impl<'a> YnabBudgetClient<'a> {
             .map(|result| result.data.accounts)
     }
 
-    pub fn get_transactions(
+    fn get_transactions(
         &self,
         start_date: Option<NaiveDate>,
         server_knowledge: Option<i64>,
@@ -69,7 +106,7 @@ impl<'a> YnabBudgetClient<'a> {
             .data)
     }
 
-    pub fn create_transactions(
+    fn create_transactions(
         &self,
         transactions: Vec<models::SaveTransaction>,
     ) -> Result<Vec<models::TransactionDetail>> {
@@ -88,7 +125,7 @@ impl<'a> YnabBudgetClient<'a> {
             .unwrap_or_else(|| vec![]))
     }
 
-    pub fn update_transactions(
+    fn update_transactions(
         &self,
         transactions: Vec<models::UpdateTransaction>,
     ) -> Result<Vec<models::TransactionDetail>> {
@@ -0,0 +1,92 @@
+//! Runs `fca4ynab` against `fca4ynab-mock-ynab-server` serving
+//! `tests/fixtures/ynab_budget.json`.
+
+use serde_json::Value;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+
+const FIXTURE_FILE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/ynab_budget.json"
+);
+
+/// Kills the mock server when the test finishes, even if it fails.
+struct MockServerProcess(Child);
+
+impl Drop for MockServerProcess {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+fn start_mock_server() -> (MockServerProcess, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fca4ynab-mock-ynab-server"))
+        .arg(FIXTURE_FILE)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut base_url = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut base_url)
+        .unwrap();
+    (MockServerProcess(child), base_url.trim().to_string())
+}
+
+fn run_sync(base_url: &str, work_dir: &PathBuf) -> Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_fca4ynab"))
+        // Keep any `.env` file in the source tree out of the test.
+        .current_dir(work_dir)
+        .args(&[
+            "--ynab-base-url",
+            base_url,
+            "--ynab-access-token",
+            "mock-access-token",
+            "--budget-id",
+            "fake-budget",
+            "--database-file",
+            "fca4ynab.sqlite3",
+            "--start-date",
+            "2020-03-01",
+            "--fixed-exchange-rates",
+            "EUR_USD=1.1",
+            "--offline",
+            "--output",
+            "json",
+            "--yes",
+        ])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "fca4ynab failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn test_sync_against_mock_server() {
+    let work_dir =
+        std::env::temp_dir().join(format!("fca4ynab-integration-test-{}", std::process::id()));
+    std::fs::create_dir_all(&work_dir).unwrap();
+    let (_server, base_url) = start_mock_server();
+
+    let plan = run_sync(&base_url, &work_dir);
+    let creates = plan["creates"].as_array().unwrap();
+    assert_eq!(creates.len(), 1);
+    assert_eq!(creates[0]["amount_milliunits"], -1_000);
+    assert_eq!(
+        creates[0]["memo"],
+        "<CONVERT: EUR -10.00 @$1.1/EUR = -$11.00> Lunch"
+    );
+
+    // The created difference transaction is saved to the mock server and the
+    // database, so there is nothing left to do.
+    let plan = run_sync(&base_url, &work_dir);
+    assert_eq!(plan["creates"].as_array().unwrap().len(), 0);
+    assert_eq!(plan["updates"].as_array().unwrap().len(), 0);
+
+    std::fs::remove_dir_all(&work_dir).ok();
+}